@@ -0,0 +1,683 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    Json, Router,
+    extract::{
+        ConnectInfo, FromRequestParts, Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade, rejection::WebSocketUpgradeRejection},
+    },
+    http::{
+        HeaderValue, StatusCode,
+        header::{AUTHORIZATION, RETRY_AFTER},
+        request::Parts,
+    },
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{delete, get, post},
+};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc::Sender};
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    audit::{self, AuditQuery, AuditRecord},
+    auth::{Principal, authorize},
+    events,
+    lock::{
+        InstructionId, InstructionSource, InstructionStatus, LockInUse, LockInstruction,
+        LockInstructor, LockState, QueuedInstruction, current_state,
+    },
+    throttle::{self, GuardedVerifyError, Throttled},
+    tokens::{self, Scope, TokenSummary},
+};
+
+const PASSCODE_HEADER: &str = "x-passcode";
+
+pub fn router() -> Router<Arc<Sender<QueuedInstruction>>> {
+    Router::new()
+        .route("/state", get(state))
+        .route("/lock", post(lock))
+        .route("/unlock", post(unlock))
+        .route("/toggle", post(toggle))
+        .route("/mark-locked", post(mark_locked))
+        .route("/mark-unlocked", post(mark_unlocked))
+        .route("/home", post(home))
+        .route("/events", get(events))
+        .route("/history", get(history))
+        .route("/tokens", get(list_tokens).post(create_token))
+        .route("/tokens/{name}", delete(revoke_token))
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    Unauthorized,
+    Forbidden,
+    BadRequest(String),
+    NotFound(String),
+    Throttled(Throttled),
+    LockInUse(LockInUse),
+    Internal(anyhow::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Throttled(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::LockInUse(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized => "invalid_credentials",
+            ApiError::Forbidden => "not_allowed",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Throttled(_) => "locked_out",
+            ApiError::LockInUse(_) => "lock_in_use",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::Unauthorized => "Missing or invalid credentials".to_string(),
+            ApiError::Forbidden => "These credentials can't do that".to_string(),
+            ApiError::BadRequest(message) | ApiError::NotFound(message) => message.clone(),
+            ApiError::Throttled(throttled) => throttled.to_string(),
+            ApiError::LockInUse(e) => e.to_string(),
+            // details stay in our logs
+            ApiError::Internal(_) => "Internal service issue".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(e) = &self {
+            error!("API internal error {:?}", e);
+        }
+        let body = Json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code(),
+                message: self.message(),
+            },
+        });
+        let mut response = (self.status(), body).into_response();
+        if let ApiError::Throttled(throttled) = &self {
+            let retry_secs = (throttled.until - Utc::now()).num_seconds().max(1);
+            if let Ok(value) = HeaderValue::from_str(&retry_secs.to_string()) {
+                response.headers_mut().insert(RETRY_AFTER, value);
+            }
+        }
+        response
+    }
+}
+
+impl From<LockInUse> for ApiError {
+    fn from(e: LockInUse) -> Self {
+        ApiError::LockInUse(e)
+    }
+}
+
+// whoever the request authenticated as. rejects the request before the handler runs otherwise
+pub struct ApiCaller(pub Principal);
+
+impl<S> FromRequestParts<S> for ApiCaller
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::Internal(anyhow::anyhow!("no client address. {e}")))?;
+        let ip = addr.ip();
+
+        // machines send a bearer token, humans (and their phone shortcuts) a passcode
+        let bearer = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let passcode = parts
+            .headers
+            .get(PASSCODE_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let verified = match (bearer, passcode) {
+            (Some(token), _) => throttle::verify_bearer(ip, token.trim()).await,
+            (None, Some(passcode)) => throttle::verify(ip, passcode).await,
+            (None, None) => return Err(ApiError::Unauthorized),
+        };
+
+        match verified {
+            Ok(Some(principal)) => Ok(ApiCaller(principal)),
+            Ok(None) => {
+                info!(%ip, "Bad API credentials");
+                Err(ApiError::Unauthorized)
+            }
+            Err(GuardedVerifyError::Throttled(throttled)) => Err(ApiError::Throttled(throttled)),
+            Err(GuardedVerifyError::Internal(e)) => Err(ApiError::Internal(e)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StateResponse {
+    pub state: LockState,
+}
+
+#[derive(Deserialize)]
+pub struct InstructionParams {
+    #[serde(default)]
+    pub wait: bool, // hold the response until the instruction has run
+    #[serde(default)]
+    pub hold_open: bool, // unlock only, skips the autolock
+}
+
+#[derive(Serialize)]
+pub struct InstructionResponse {
+    pub accepted: bool,
+    pub id: InstructionId,
+    pub instruction: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<InstructionStatus>,
+}
+
+pub async fn state(ApiCaller(_): ApiCaller) -> Json<StateResponse> {
+    Json(StateResponse {
+        state: current_state(),
+    })
+}
+
+async fn instruct(
+    lock_tx: &Arc<Sender<QueuedInstruction>>,
+    principal: Principal,
+    client_ip: IpAddr,
+    instruction: fn(InstructionSource) -> LockInstruction,
+    params: InstructionParams,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    let instruction = instruction(InstructionSource::Api {
+        principal: principal.clone(),
+        client_ip,
+    });
+    if !authorize(&principal, &instruction)
+        .await
+        .map_err(ApiError::Internal)?
+    {
+        return Err(ApiError::Forbidden);
+    }
+    let kind = instruction.kind();
+    let ticket = lock_tx.send_instruction(instruction).await?;
+    let id = ticket.id;
+    debug!(id, position = ticket.position, "Instruction queued");
+    if !params.wait {
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(InstructionResponse {
+                accepted: true,
+                id,
+                instruction: kind,
+                result: None,
+            }),
+        ));
+    }
+
+    let outcome = ticket.completion().await;
+    let status = match outcome {
+        InstructionStatus::Failed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        InstructionStatus::Refused { .. } => StatusCode::CONFLICT,
+        _ => StatusCode::OK,
+    };
+    Ok((
+        status,
+        Json(InstructionResponse {
+            accepted: true,
+            id,
+            instruction: kind,
+            result: Some(outcome),
+        }),
+    ))
+}
+
+pub async fn lock(
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ApiCaller(principal): ApiCaller,
+    Query(params): Query<InstructionParams>,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    instruct(
+        &lock_tx,
+        principal,
+        addr.ip(),
+        LockInstruction::EnsureLocked,
+        params,
+    )
+    .await
+}
+
+pub async fn unlock(
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ApiCaller(principal): ApiCaller,
+    Query(params): Query<InstructionParams>,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    let instruction = match params.hold_open {
+        true => LockInstruction::HoldOpen,
+        false => LockInstruction::EnsureUnlocked,
+    };
+    instruct(&lock_tx, principal, addr.ip(), instruction, params).await
+}
+
+pub async fn toggle(
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ApiCaller(principal): ApiCaller,
+    Query(params): Query<InstructionParams>,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    instruct(
+        &lock_tx,
+        principal,
+        addr.ip(),
+        LockInstruction::Reverse,
+        params,
+    )
+    .await
+}
+
+// admins only, once they've checked the bolt. clears a jammed or unknown lock
+pub async fn mark_locked(
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ApiCaller(principal): ApiCaller,
+    Query(params): Query<InstructionParams>,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    instruct(
+        &lock_tx,
+        principal,
+        addr.ip(),
+        LockInstruction::MarkLocked,
+        params,
+    )
+    .await
+}
+
+pub async fn mark_unlocked(
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ApiCaller(principal): ApiCaller,
+    Query(params): Query<InstructionParams>,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    instruct(
+        &lock_tx,
+        principal,
+        addr.ip(),
+        LockInstruction::MarkUnlocked,
+        params,
+    )
+    .await
+}
+
+// admins only. needs gpio.locked_stop_pin, refused without one
+pub async fn home(
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ApiCaller(principal): ApiCaller,
+    Query(params): Query<InstructionParams>,
+) -> Result<(StatusCode, Json<InstructionResponse>), ApiError> {
+    instruct(
+        &lock_tx,
+        principal,
+        addr.ip(),
+        LockInstruction::Home,
+        params,
+    )
+    .await
+}
+
+// tokens can't mint tokens, only a human admin can
+fn require_admin(principal: &Principal) -> Result<(), ApiError> {
+    match principal {
+        Principal::User { .. } if principal.is_admin() => Ok(()),
+        _ => Err(ApiError::Forbidden),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub scope: Scope,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CreateTokenResponse {
+    pub name: String,
+    pub scope: Scope,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub token: String, // shown once, only the hash is kept
+}
+
+pub async fn list_tokens(
+    ApiCaller(principal): ApiCaller,
+) -> Result<Json<Vec<TokenSummary>>, ApiError> {
+    require_admin(&principal)?;
+    Ok(Json(tokens::list_tokens().await))
+}
+
+pub async fn create_token(
+    ApiCaller(principal): ApiCaller,
+    Json(request): Json<CreateTokenRequest>,
+) -> Result<(StatusCode, Json<CreateTokenResponse>), ApiError> {
+    require_admin(&principal)?;
+    let token = tokens::create_token(request.name.trim(), request.scope, request.expires_at)
+        .await
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    info!("{} created API token '{}'", principal, request.name.trim());
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateTokenResponse {
+            name: request.name.trim().to_string(),
+            scope: request.scope,
+            expires_at: request.expires_at,
+            token,
+        }),
+    ))
+}
+
+pub async fn revoke_token(
+    ApiCaller(principal): ApiCaller,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    require_admin(&principal)?;
+    tokens::revoke_token(&name)
+        .await
+        .map_err(|e| ApiError::NotFound(e.to_string()))?;
+    info!("{} revoked API token '{}'", principal, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// who did what. admins only, it's everyone's comings and goings
+pub async fn history(
+    ApiCaller(principal): ApiCaller,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditRecord>>, ApiError> {
+    require_admin(&principal)?;
+    Ok(Json(audit::query(query).await.map_err(ApiError::Internal)?))
+}
+
+// one endpoint, two transports. a websocket upgrade gets a socket, anything else gets SSE
+pub async fn events(
+    ApiCaller(principal): ApiCaller,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    info!("{} subscribed to events", principal);
+    match ws {
+        Ok(ws) => ws.on_upgrade(stream_events_ws),
+        Err(_) => Sse::new(sse_events())
+            .keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+            .into_response(),
+    }
+}
+
+fn sse_events() -> impl Stream<Item = Result<Event, Infallible>> {
+    BroadcastStream::new(events::subscribe()).filter_map(|event| async move {
+        // a lagged subscriber just misses some, the next state_changed catches it up
+        let event = event.ok()?;
+        Event::default()
+            .event(event.kind.name())
+            .json_data(&event)
+            .ok()
+            .map(Ok)
+    })
+}
+
+async fn stream_events_ws(mut socket: WebSocket) {
+    let mut rx = events::subscribe();
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    let Ok(json) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(json.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Websocket subscriber lagged, skipped {skipped} events");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // we don't take commands over the socket, just notice when the client leaves
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{Client, RequestBuilder};
+    use serde_json::{Value, json};
+    use tokio::{net::TcpListener, sync::mpsc::channel};
+
+    use super::*;
+    use crate::{
+        auth::{Role, add_user},
+        testing,
+    };
+
+    // the api on a port of its own. there's no lock handler behind it, so anything that gets as
+    // far as the lock is turned away with LockInUse
+    async fn serve() -> String {
+        testing::config();
+        let (lock_tx, _) = channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/v1", listener.local_addr().unwrap());
+        let app = Router::new()
+            .nest("/api/v1", router())
+            .with_state(Arc::new(lock_tx));
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        url
+    }
+
+    // the throttle counts per client address, so each test calls from one of its own
+    fn client(n: u8) -> Client {
+        Client::builder()
+            .local_address(IpAddr::from([127, 0, 0, n]))
+            .build()
+            .unwrap()
+    }
+
+    async fn status(request: RequestBuilder) -> StatusCode {
+        request.send().await.unwrap().status()
+    }
+
+    async fn create_token(client: &Client, url: &str, passcode: &str, body: Value) -> String {
+        let response = client
+            .post(format!("{url}/tokens"))
+            .header(PASSCODE_HEADER, passcode)
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let created: Value = response.json().await.unwrap();
+        created["token"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn missing_or_wrong_credentials_are_unauthorized() {
+        let url = serve().await;
+        let client = client(11);
+        let response = client.get(format!("{url}/state")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "invalid_credentials");
+
+        let wrong = client
+            .get(format!("{url}/state"))
+            .header(PASSCODE_HEADER, "not-anyones");
+        assert_eq!(status(wrong).await, StatusCode::UNAUTHORIZED);
+        let malformed = client.get(format!("{url}/state")).bearer_auth("dk_nope");
+        assert_eq!(status(malformed).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_are_throttled() {
+        let url = serve().await;
+        let client = client(12);
+        let wrong = || {
+            client
+                .get(format!("{url}/state"))
+                .header(PASSCODE_HEADER, "still-not-anyones")
+        };
+        // a few free tries, then the one that starts the backoff
+        for _ in 0..4 {
+            assert_eq!(status(wrong()).await, StatusCode::UNAUTHORIZED);
+        }
+
+        let response = wrong().send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "locked_out");
+        throttle::clear(Some(IpAddr::from([127, 0, 0, 12])));
+    }
+
+    #[tokio::test]
+    async fn a_passcode_gets_as_far_as_the_lock() {
+        let url = serve().await;
+        add_user("api-resident", "121212", Role::Resident)
+            .await
+            .unwrap();
+        let client = client(13);
+
+        let state = client
+            .get(format!("{url}/state"))
+            .header(PASSCODE_HEADER, "121212");
+        assert_eq!(status(state).await, StatusCode::OK);
+        let response = client
+            .post(format!("{url}/lock"))
+            .header(PASSCODE_HEADER, "121212")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "lock_in_use");
+        // only admins mark the bolt or see tokens
+        let mark = client
+            .post(format!("{url}/mark-locked"))
+            .header(PASSCODE_HEADER, "121212");
+        assert_eq!(status(mark).await, StatusCode::FORBIDDEN);
+        let tokens = client
+            .get(format!("{url}/tokens"))
+            .header(PASSCODE_HEADER, "121212");
+        assert_eq!(status(tokens).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn a_token_can_do_what_its_scope_allows() {
+        let url = serve().await;
+        add_user("api-admin", "131313", Role::Admin).await.unwrap();
+        let client = client(14);
+        let read = create_token(
+            &client,
+            &url,
+            "131313",
+            json!({"name": "api-read", "scope": "read_state"}),
+        )
+        .await;
+        let lock_only = create_token(
+            &client,
+            &url,
+            "131313",
+            json!({"name": "api-lock", "scope": "lock_only"}),
+        )
+        .await;
+
+        let state = client.get(format!("{url}/state")).bearer_auth(&read);
+        assert_eq!(status(state).await, StatusCode::OK);
+        let lock = client.post(format!("{url}/lock")).bearer_auth(&read);
+        assert_eq!(status(lock).await, StatusCode::FORBIDDEN);
+
+        let lock = client.post(format!("{url}/lock")).bearer_auth(&lock_only);
+        assert_eq!(status(lock).await, StatusCode::CONFLICT);
+        let unlock = client.post(format!("{url}/unlock")).bearer_auth(&lock_only);
+        assert_eq!(status(unlock).await, StatusCode::FORBIDDEN);
+        // tokens can't mint tokens, whatever their scope
+        let mint = client
+            .post(format!("{url}/tokens"))
+            .bearer_auth(&lock_only)
+            .json(&json!({"name": "api-minted", "scope": "read_state"}));
+        assert_eq!(status(mint).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn a_revoked_token_stops_working() {
+        let url = serve().await;
+        add_user("api-revoker", "141414", Role::Admin)
+            .await
+            .unwrap();
+        let client = client(15);
+        let token = create_token(
+            &client,
+            &url,
+            "141414",
+            json!({"name": "api-revoked", "scope": "lock_unlock"}),
+        )
+        .await;
+        let state = client.get(format!("{url}/state")).bearer_auth(&token);
+        assert_eq!(status(state).await, StatusCode::OK);
+
+        let revoke = client
+            .delete(format!("{url}/tokens/api-revoked"))
+            .header(PASSCODE_HEADER, "141414");
+        assert_eq!(status(revoke).await, StatusCode::NO_CONTENT);
+        let state = client.get(format!("{url}/state")).bearer_auth(&token);
+        assert_eq!(status(state).await, StatusCode::UNAUTHORIZED);
+        let again = client
+            .delete(format!("{url}/tokens/api-revoked"))
+            .header(PASSCODE_HEADER, "141414");
+        assert_eq!(status(again).await, StatusCode::NOT_FOUND);
+    }
+}
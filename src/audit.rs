@@ -0,0 +1,255 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{
+    config::config,
+    lock::{InstructionId, InstructionStatus, LockInstruction},
+};
+
+// appends, fsyncs and rotations happen one at a time on their own thread, in the order records
+// were made. callers are the lock actor and async handlers, which shouldn't wait on the sd card
+static WRITER: Lazy<Sender<AuditRecord>> = Lazy::new(|| {
+    let (records_tx, records) = mpsc::channel::<AuditRecord>();
+    thread::Builder::new()
+        .name("audit".to_string())
+        .spawn(move || {
+            for record in records {
+                if let Err(e) = append(&record) {
+                    error!("Could not write audit record {:?}. {e}", record);
+                }
+            }
+        })
+        .expect("could not start the audit writer");
+    records_tx
+});
+
+// one line of audit.jsonl. append only, nothing is ever rewritten
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEntry {
+    // once it's final, whatever that turned out to be
+    Instruction {
+        id: InstructionId,
+        instruction: String,
+        source: String,
+        user: Option<String>,
+        client_ip: Option<IpAddr>,
+        outcome: InstructionStatus,
+        duration_ms: u64,
+    },
+    // credentials were fine, they just can't do that
+    Denied {
+        instruction: String,
+        source: String,
+        user: Option<String>,
+        client_ip: Option<IpAddr>,
+    },
+    AuthFailed {
+        client_ip: IpAddr,
+        credential: String, // passcode or token, never what was tried
+    },
+    Throttled {
+        client_ip: IpAddr,
+        until: DateTime<Utc>,
+        locked_out: bool,
+    },
+}
+
+impl AuditEntry {
+    // only the http side takes credentials, so failed logins are always "api"
+    pub fn source(&self) -> &str {
+        match self {
+            AuditEntry::Instruction { source, .. } | AuditEntry::Denied { source, .. } => source,
+            AuditEntry::AuthFailed { .. } | AuditEntry::Throttled { .. } => "api",
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            AuditEntry::Instruction { user, .. } | AuditEntry::Denied { user, .. } => {
+                user.as_deref()
+            }
+            AuditEntry::AuthFailed { .. } | AuditEntry::Throttled { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub source: Option<String>,
+    pub user: Option<String>,
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+// the limit comes straight from the caller, this keeps an absurd one from taking the daemon's memory
+const MAX_QUERY_LIMIT: usize = 1000;
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.since.is_none_or(|since| record.at >= since)
+            && self.until.is_none_or(|until| record.at <= until)
+            && self
+                .source
+                .as_deref()
+                .is_none_or(|source| record.entry.source() == source)
+            && self
+                .user
+                .as_deref()
+                .is_none_or(|user| record.entry.user() == Some(user))
+    }
+}
+
+pub fn audit_log_path() -> PathBuf {
+    config().files.audit_log.clone()
+}
+
+// audit.jsonl.1 is the newest rotated file, audit.jsonl.<keep> the oldest
+fn rotated_path(n: usize) -> PathBuf {
+    let mut name = audit_log_path().into_os_string();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+fn rotate_if_full() -> Result<(), anyhow::Error> {
+    let path = audit_log_path();
+    let full = fs::metadata(&path).is_ok_and(|meta| meta.len() >= config().audit.max_bytes);
+    if !full {
+        return Ok(());
+    }
+    for n in (1..config().audit.keep).rev() {
+        let from = rotated_path(n);
+        if from.exists() {
+            fs::rename(&from, rotated_path(n + 1))?;
+        }
+    }
+    fs::rename(&path, rotated_path(1))?;
+    Ok(())
+}
+
+fn append(record: &AuditRecord) -> Result<(), anyhow::Error> {
+    rotate_if_full()?;
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log_path())?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+// losing an audit line must never stop the door from working, so this only complains
+pub fn record(entry: AuditEntry) {
+    let record = AuditRecord {
+        at: Utc::now(),
+        entry,
+    };
+    if let Err(e) = WRITER.send(record) {
+        error!("Audit writer is gone, dropping {:?}", e.0);
+    }
+}
+
+pub fn record_instruction(
+    id: InstructionId,
+    instruction: &LockInstruction,
+    outcome: &InstructionStatus,
+    took: Duration,
+) {
+    let source = instruction.source();
+    record(AuditEntry::Instruction {
+        id,
+        instruction: instruction.kind().to_string(),
+        source: source.kind().to_string(),
+        user: source.user(),
+        client_ip: source.client_ip(),
+        outcome: outcome.clone(),
+        duration_ms: took.as_millis() as u64,
+    });
+}
+
+pub fn record_denied(instruction: &LockInstruction) {
+    let source = instruction.source();
+    record(AuditEntry::Denied {
+        instruction: instruction.kind().to_string(),
+        source: source.kind().to_string(),
+        user: source.user(),
+        client_ip: source.client_ip(),
+    });
+}
+
+// oldest first, at most limit of the newest matches. it reads every rotated file, so on the blocking pool
+pub async fn query(query: AuditQuery) -> Result<Vec<AuditRecord>, anyhow::Error> {
+    tokio::task::spawn_blocking(move || read_matches(&query)).await?
+}
+
+fn read_matches(query: &AuditQuery) -> Result<Vec<AuditRecord>, anyhow::Error> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .min(MAX_QUERY_LIMIT);
+    let mut files: Vec<PathBuf> = (1..=config().audit.keep).rev().map(rotated_path).collect();
+    files.push(audit_log_path());
+
+    let mut matches = VecDeque::new();
+    for path in files.iter().filter(|path| path.exists()) {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let Ok(record) = serde_json::from_str::<AuditRecord>(&line) else {
+                // a torn last line from a power cut shouldn't hide everything else
+                warn!("Skipping unreadable audit line in {}", path.display());
+                continue;
+            };
+            if !query.matches(&record) {
+                continue;
+            }
+            if matches.len() == limit {
+                matches.pop_front();
+            }
+            if limit > 0 {
+                matches.push_back(record);
+            }
+        }
+    }
+    Ok(matches.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn a_huge_limit_is_capped() {
+        testing::config();
+        let query = AuditQuery {
+            limit: Some(usize::MAX),
+            ..Default::default()
+        };
+        let records = super::query(query).await.unwrap();
+        assert!(records.len() <= MAX_QUERY_LIMIT);
+    }
+}
@@ -1,45 +1,172 @@
 use std::{
-    fs,
-    io::{Write, stdin, stdout},
+    fmt, fs,
+    io::{IsTerminal, Write, stdin, stdout},
+    path::{Path, PathBuf},
 };
 
+use anyhow::anyhow;
 use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
 };
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use tracing::{error, info};
 
-static PASSWORD_HASH: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(String::new()));
+use crate::{
+    audit, config::config, guest, lock::LockInstruction, state_file::write_atomic, tokens::Scope,
+};
+
+// the single shared hash from before there were users (files.legacy_password_hash)
+// is imported once as this admin account
+const LEGACY_ADMIN_NAME: &str = "admin";
+
+static USERS: Lazy<RwLock<Vec<User>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Resident,
+    Guest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub password_hash: String,
+    pub enabled: bool,
+    pub role: Role,
+}
+
+// user info that is safe to hand out, i.e. no hash
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSummary {
+    pub name: String,
+    pub enabled: bool,
+    pub role: Role,
+}
+
+// whoever a passcode turned out to belong to. travels with the lock instruction
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Principal {
+    User { name: String, role: Role },
+    Guest { name: String, unlock_only: bool },
+    Token { name: String, scope: Scope },
+}
 
-fn hash_password(password: &str) -> String {
+impl Principal {
+    pub fn name(&self) -> &str {
+        match self {
+            Principal::User { name, .. }
+            | Principal::Guest { name, .. }
+            | Principal::Token { name, .. } => name,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Principal::User {
+                role: Role::Admin,
+                ..
+            }
+        )
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::User { name, role } => write!(f, "user '{name}' ({role:?})"),
+            Principal::Guest { name, .. } => write!(f, "guest code '{name}'"),
+            Principal::Token { name, scope } => write!(f, "API token '{name}' ({scope})"),
+        }
+    }
+}
+
+pub fn users_file_path() -> PathBuf {
+    config().files.users.clone()
+}
+
+pub(crate) fn hash_password(password: &str) -> String {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
-    let password_hash = argon2
+    argon2
         .hash_password(password.as_bytes(), &salt)
         .unwrap()
-        .to_string();
-    password_hash
+        .to_string()
 }
 
-async fn cache_hash(password_hash: String) {
-    let mut cacheword = PASSWORD_HASH.write().await;
-    *cacheword = password_hash;
+pub(crate) fn password_matches(password: &str, hash: &str) -> Result<bool, anyhow::Error> {
+    let parsed_hash = PasswordHash::new(hash)?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
 }
 
-async fn save_password(password: &str) {
-    let hash = hash_password(password);
+// argon2 is slow on purpose, so it runs on the blocking pool where a burst of guesses can't
+// stall the async workers
+pub(crate) async fn password_matches_async(
+    password: &str,
+    hash: &str,
+) -> Result<bool, anyhow::Error> {
+    let (password, hash) = (password.to_string(), hash.to_string());
+    tokio::task::spawn_blocking(move || password_matches(&password, &hash)).await?
+}
 
-    fs::write("password_hash.txt", hash.clone())
-        .expect("password hash needs to be saved correctly");
+fn read_users_file(path: &Path) -> Result<Vec<User>, anyhow::Error> {
+    if path.exists() {
+        return Ok(serde_json::from_str(&fs::read_to_string(path)?)?);
+    }
+    let legacy = &config().files.legacy_password_hash;
+    match fs::read_to_string(legacy) {
+        Ok(hash) => {
+            info!(
+                "Importing {} as user '{LEGACY_ADMIN_NAME}'",
+                legacy.display()
+            );
+            Ok(vec![User {
+                name: LEGACY_ADMIN_NAME.to_string(),
+                password_hash: hash.trim().to_string(),
+                enabled: true,
+                role: Role::Admin,
+            }])
+        }
+        Err(_) => Ok(Vec::new()),
+    }
+}
 
-    cache_hash(hash).await;
+fn persist(users: &[User]) -> Result<(), anyhow::Error> {
+    write_atomic(
+        &users_file_path(),
+        serde_json::to_string_pretty(users)?.as_bytes(),
+    )
+}
 
-    println!("Password hash saved successfully.");
+pub async fn load_users() -> Result<(), anyhow::Error> {
+    let loaded = read_users_file(&users_file_path())?;
+    persist(&loaded)?;
+    info!("Loaded {} user(s)", loaded.len());
+    *USERS.write().await = loaded;
+    Ok(())
 }
 
+// only asks when there's nobody who could open the door
 pub async fn setup_password() {
-    println!("Please set the doorlock password");
+    if let Err(e) = load_users().await {
+        panic!("user store at {:?} is broken. {e}", users_file_path())
+    }
+    if !USERS.read().await.is_empty() {
+        return;
+    }
+    if !stdin().is_terminal() {
+        panic!("No users yet and nobody to ask. Run `doorknob set-password` first")
+    }
+
+    println!("No users yet. Please set the doorlock password for '{LEGACY_ADMIN_NAME}'");
     let _ = stdout().flush();
     let mut s: String = String::new();
     stdin().read_line(&mut s).unwrap();
@@ -49,27 +176,265 @@ pub async fn setup_password() {
         panic!("No password set")
     }
 
-    save_password(s.as_str()).await;
+    add_user(LEGACY_ADMIN_NAME, s.as_str(), Role::Admin)
+        .await
+        .expect("admin user needs to be saved correctly");
+}
+
+// passcodes double as usernames at the door, so two users can't share one. nor can a user and a
+// guest code, users are tried first so the guest would come in as the user
+fn ensure_passcode_unused(
+    users: &[User],
+    guest_codes: &[String],
+    password: &str,
+    except: &str,
+) -> Result<(), anyhow::Error> {
+    for user in users.iter().filter(|u| u.name != except) {
+        if password_matches(password, &user.password_hash)? {
+            return Err(anyhow!("passcode is already in use by another user"));
+        }
+    }
+    for hash in guest_codes {
+        if password_matches(password, hash)? {
+            return Err(anyhow!("passcode is already in use by a guest code"));
+        }
+    }
+    Ok(())
+}
+
+pub async fn add_user(name: &str, password: &str, role: Role) -> Result<(), anyhow::Error> {
+    if name.trim().is_empty() || password.is_empty() {
+        return Err(anyhow!("name and passcode can't be empty"));
+    }
+    let guest_codes = guest::code_hashes().await;
+    let mut users = USERS.write().await;
+    if users.iter().any(|u| u.name == name) {
+        return Err(anyhow!("user '{name}' already exists"));
+    }
+    ensure_passcode_unused(&users, &guest_codes, password, name)?;
+
+    let mut updated = users.clone();
+    updated.push(User {
+        name: name.to_string(),
+        password_hash: hash_password(password),
+        enabled: true,
+        role,
+    });
+    persist(&updated)?;
+    *users = updated;
+    info!("Added user '{name}'");
+    Ok(())
+}
+
+pub async fn remove_user(name: &str) -> Result<(), anyhow::Error> {
+    update_users(|users| {
+        let before = users.len();
+        users.retain(|u| u.name != name);
+        if users.len() == before {
+            return Err(anyhow!("no user '{name}'"));
+        }
+        Ok(())
+    })
+    .await?;
+    info!("Removed user '{name}'");
+    Ok(())
+}
+
+pub async fn set_user_enabled(name: &str, enabled: bool) -> Result<(), anyhow::Error> {
+    update_users(|users| {
+        find_mut(users, name)?.enabled = enabled;
+        Ok(())
+    })
+    .await?;
+    info!("User '{name}' enabled: {enabled}");
+    Ok(())
+}
+
+pub async fn set_user_password(name: &str, password: &str) -> Result<(), anyhow::Error> {
+    if password.is_empty() {
+        return Err(anyhow!("passcode can't be empty"));
+    }
+    let guest_codes = guest::code_hashes().await;
+    update_users(|users| {
+        ensure_passcode_unused(users, &guest_codes, password, name)?;
+        find_mut(users, name)?.password_hash = hash_password(password);
+        Ok(())
+    })
+    .await?;
+    info!("Passcode changed for user '{name}'");
+    Ok(())
+}
+
+// what `doorknob set-password` does. with no users at all, this is how the first admin gets made
+pub async fn set_password(name: &str, password: &str) -> Result<(), anyhow::Error> {
+    if USERS.read().await.is_empty() {
+        return add_user(name, password, Role::Admin).await;
+    }
+    set_user_password(name, password).await
+}
+
+pub async fn list_users() -> Vec<UserSummary> {
+    USERS
+        .read()
+        .await
+        .iter()
+        .map(|u| UserSummary {
+            name: u.name.clone(),
+            enabled: u.enabled,
+            role: u.role.clone(),
+        })
+        .collect()
 }
 
-pub async fn verify_password(checkpass: &str) -> Result<bool, anyhow::Error> {
-    let hash = {
-        let cached_hash = PASSWORD_HASH.read().await.clone();
-        if cached_hash.is_empty() {
-            println!("password cache is empty. Justin you fucked up the control flow");
-            let hash_sot = fs::read_to_string("password_hash.txt")?;
-            cache_hash(hash_sot.clone()).await;
-            hash_sot
-        } else {
-            cached_hash
+fn find_mut<'a>(users: &'a mut [User], name: &str) -> Result<&'a mut User, anyhow::Error> {
+    users
+        .iter_mut()
+        .find(|u| u.name == name)
+        .ok_or_else(|| anyhow!("no user '{name}'"))
+}
+
+// edits a copy and only swaps it in once it's on disk, so memory and file never disagree
+async fn update_users<F>(f: F) -> Result<(), anyhow::Error>
+where
+    F: FnOnce(&mut Vec<User>) -> Result<(), anyhow::Error>,
+{
+    let mut users = USERS.write().await;
+    let mut updated = users.clone();
+    f(&mut updated)?;
+    persist(&updated)?;
+    *users = updated;
+    Ok(())
+}
+
+// returns who the passcode belongs to, None if it matches no enabled user or usable guest code
+pub async fn verify_password(checkpass: &str) -> Result<Option<Principal>, anyhow::Error> {
+    if let Some(user) = verify_user(checkpass, |_| true).await? {
+        return Ok(Some(user));
+    }
+    Ok(guest::find_usable(checkpass)
+        .await?
+        .map(|code| Principal::Guest {
+            name: code.name,
+            unlock_only: code.unlock_only,
+        }))
+}
+
+// any user's, disabled ones too since they can be enabled again
+pub async fn passcode_in_use(checkpass: &str) -> Result<bool, anyhow::Error> {
+    let users = USERS.read().await.clone();
+    for user in &users {
+        if password_matches_async(checkpass, &user.password_hash).await? {
+            return Ok(true);
         }
+    }
+    Ok(false)
+}
+
+// same, but only enabled admins are tried. for the admin forms
+pub async fn verify_admin_password(checkpass: &str) -> Result<Option<Principal>, anyhow::Error> {
+    verify_user(checkpass, |user| user.role == Role::Admin).await
+}
+
+async fn verify_user<F>(checkpass: &str, wanted: F) -> Result<Option<Principal>, anyhow::Error>
+where
+    F: Fn(&User) -> bool,
+{
+    let users = USERS.read().await.clone();
+    if users.is_empty() {
+        error!("user cache is empty. Justin you fucked up the control flow");
+    }
+    for user in users.iter().filter(|u| u.enabled && wanted(u)) {
+        if password_matches_async(checkpass, &user.password_hash).await? {
+            return Ok(Some(Principal::User {
+                name: user.name.clone(),
+                role: user.role.clone(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+// whether this principal may send this instruction. guest codes burn a use here,
+// which the lock hands back if it turns the instruction away
+pub async fn authorize(
+    principal: &Principal,
+    instruction: &LockInstruction,
+) -> Result<bool, anyhow::Error> {
+    let allowed = is_allowed(principal, instruction).await?;
+    if !allowed {
+        audit::record_denied(instruction);
+    }
+    Ok(allowed)
+}
+
+async fn is_allowed(
+    principal: &Principal,
+    instruction: &LockInstruction,
+) -> Result<bool, anyhow::Error> {
+    // these clear faults, which takes someone who can go and check on the bolt
+    if instruction.needs_admin() && !principal.is_admin() {
+        info!(
+            "{principal} is not an admin, refusing {}",
+            instruction.kind()
+        );
+        return Ok(false);
+    }
+    match principal {
+        Principal::User { .. } => Ok(true),
+        Principal::Guest { name, unlock_only } => {
+            if *unlock_only && !matches!(instruction, LockInstruction::EnsureUnlocked(_)) {
+                info!(
+                    "Guest code '{name}' is unlock only, refusing {}",
+                    instruction.kind()
+                );
+                return Ok(false);
+            }
+            guest::record_use(name, instruction).await
+        }
+        Principal::Token { name, scope } => {
+            let permitted = scope.permits(instruction);
+            if !permitted {
+                info!(
+                    "API token '{name}' has scope {scope}, refusing {}",
+                    instruction.kind()
+                );
+            }
+            Ok(permitted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, Utc};
+
+    use super::*;
+    use crate::{
+        guest::{NewGuestCode, create_code},
+        testing,
     };
-    let parsed_hash = PasswordHash::new(&hash)?;
 
-    let argon2 = Argon2::default();
-    let result = argon2
-        .verify_password(checkpass.as_bytes(), &parsed_hash)
-        .is_ok();
+    #[tokio::test]
+    async fn a_user_cant_take_a_guest_code() {
+        testing::config();
+        create_code(NewGuestCode {
+            name: "walker".to_string(),
+            code: "606060".to_string(),
+            valid_from: Utc::now(),
+            valid_until: Utc::now() + TimeDelta::hours(1),
+            max_uses: None,
+            schedule: None,
+            unlock_only: true,
+        })
+        .await
+        .unwrap();
 
-    Ok(result)
+        assert!(add_user("taker", "606060", Role::Admin).await.is_err());
+        add_user("taker", "707070", Role::Admin).await.unwrap();
+        assert!(set_user_password("taker", "606060").await.is_err());
+        assert_eq!(
+            verify_password("606060").await.unwrap().unwrap().name(),
+            "walker"
+        );
+    }
 }
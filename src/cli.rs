@@ -0,0 +1,372 @@
+use std::{
+    fs,
+    io::{IsTerminal, Write, stderr, stdin},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use serde_json::Value;
+
+use crate::{
+    audit::{self, AuditQuery},
+    auth::{self, Role},
+    config::{self, config},
+    control::{self, ControlInstruction, ControlReply, ControlRequest},
+    lock::LockAction,
+    motion,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Runs and administers the doorknob lock")]
+pub struct Cli {
+    /// TOML config file. Defaults to ./doorknob.toml when it exists
+    #[arg(long, env = "DOORKNOB_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the lock. This is what happens without a subcommand
+    Serve,
+    /// Set a user's passcode from stdin or --file. With no users yet, creates them as an admin
+    SetPassword {
+        #[arg(long, default_value = "admin")]
+        user: String,
+        /// Read the passcode from the first line of this file
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Add, remove or list users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Show what the lock is doing
+    Status,
+    /// Lock the door
+    Lock {
+        /// Return once the instruction is queued instead of when the bolt has moved
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Unlock the door
+    Unlock {
+        /// Return once the instruction is queued instead of when the bolt has moved
+        #[arg(long)]
+        no_wait: bool,
+        /// Don't autolock afterwards
+        #[arg(long)]
+        hold_open: bool,
+    },
+    /// Lock the door if it's unlocked and the other way around
+    Toggle {
+        /// Return once the instruction is queued instead of when the bolt has moved
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Say where the bolt is without moving it, after checking it by hand. Clears a jam
+    Mark {
+        #[arg(value_parser = ["locked", "unlocked"])]
+        state: String,
+    },
+    /// Run the bolt to the locked end-stop from wherever it is. Clears a jam too
+    Home {
+        /// Return once the instruction is queued instead of when the bolt has moved
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// Show the audit log, oldest first
+    History {
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+        /// Only entries at or after this RFC 3339 time, e.g. 2024-05-01T18:00:00Z
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        /// Only entries at or before this RFC 3339 time
+        #[arg(long)]
+        until: Option<DateTime<Utc>>,
+        /// Only this source: button, api, auto_sensor, auto_lock, control_socket or mqtt
+        #[arg(long)]
+        source: Option<String>,
+        /// Only this user, token or guest code
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Print lock events as they happen
+    Watch,
+    /// Send a test event to every webhook endpoint
+    TestWebhooks,
+    /// Validate the config and print it with every default filled in
+    CheckConfig,
+    /// Print the step timing a move will use, as CSV for plotting
+    MotionTable {
+        #[arg(value_enum, default_value = "lock")]
+        action: LockAction,
+        /// JSON instead of CSV
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Add a user. The passcode is read from stdin or --file
+    Add {
+        name: String,
+        #[arg(long, value_enum, default_value = "resident")]
+        role: Role,
+        /// Read the passcode from the first line of this file
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Remove a user
+    Remove { name: String },
+    /// List users
+    List,
+}
+
+// doesn't go through config::init, a broken config is exactly what this is for
+pub fn check_config(path: Option<&Path>) -> Result<(), anyhow::Error> {
+    let mut config = config::load_from(path)?;
+    if !config.mqtt.password.is_empty() {
+        config.mqtt.password = "<set>".to_string();
+    }
+    for endpoint in &mut config.webhooks.endpoints {
+        endpoint.secret = "<set>".to_string();
+    }
+    print!("{}", toml::to_string_pretty(&config)?);
+    eprintln!("config ok");
+    Ok(())
+}
+
+pub async fn run(command: Command) -> Result<(), anyhow::Error> {
+    match command {
+        Command::Serve | Command::CheckConfig => unreachable!("main handles these itself"),
+        Command::SetPassword { user, file } => {
+            let password = read_passcode(file.as_deref())?;
+            user_request(ControlRequest::SetPassword {
+                user: user.clone(),
+                password,
+            })
+            .await?;
+            println!("Passcode set for '{user}'");
+        }
+        Command::User(UserCommand::Add { name, role, file }) => {
+            let password = read_passcode(file.as_deref())?;
+            user_request(ControlRequest::AddUser {
+                name: name.clone(),
+                password,
+                role,
+            })
+            .await?;
+            println!("Added '{name}'");
+        }
+        Command::User(UserCommand::Remove { name }) => {
+            user_request(ControlRequest::RemoveUser { name: name.clone() }).await?;
+            println!("Removed '{name}'");
+        }
+        Command::User(UserCommand::List) => {
+            let users = user_request(ControlRequest::ListUsers).await?;
+            for user in users.as_array().into_iter().flatten() {
+                println!(
+                    "{:<20} {:<10} {}",
+                    text(&user["name"]),
+                    text(&user["role"]),
+                    if user["enabled"] == true {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                );
+            }
+        }
+        Command::Status => {
+            let status = daemon_request(ControlRequest::Status).await?;
+            match status["since"].as_str() {
+                Some(since) => println!("{} since {since}", text(&status["state_name"])),
+                None => println!("{}", text(&status["state_name"])),
+            }
+            if let Some(fault) = status["fault"].as_str() {
+                println!("{fault}");
+            }
+            println!("queue policy: {}", text(&status["queue_policy"]));
+            match status["autolock_secs"].as_u64() {
+                Some(0) | None => println!("autolock: off"),
+                Some(secs) => println!("autolock: {secs}s after an unlock"),
+            }
+        }
+        Command::Lock { no_wait } => instruct(ControlInstruction::Lock, no_wait).await?,
+        Command::Unlock {
+            no_wait,
+            hold_open: false,
+        } => instruct(ControlInstruction::Unlock, no_wait).await?,
+        Command::Unlock {
+            no_wait,
+            hold_open: true,
+        } => instruct(ControlInstruction::HoldOpen, no_wait).await?,
+        Command::Toggle { no_wait } => instruct(ControlInstruction::Toggle, no_wait).await?,
+        Command::Home { no_wait } => instruct(ControlInstruction::Home, no_wait).await?,
+        Command::Mark { state } => {
+            let instruction = match state.as_str() {
+                "locked" => ControlInstruction::MarkLocked,
+                _ => ControlInstruction::MarkUnlocked,
+            };
+            instruct(instruction, false).await?
+        }
+        Command::History {
+            limit,
+            since,
+            until,
+            source,
+            user,
+        } => {
+            let query = AuditQuery {
+                since,
+                until,
+                source,
+                user,
+                limit: Some(limit),
+            };
+            // the log is just a file, reading it doesn't need the daemon
+            let records = match control::request(&ControlRequest::History(query.clone())).await? {
+                Some(reply) => into_result(reply)?,
+                None => serde_json::to_value(audit::query(query).await?)?,
+            };
+            for record in records.as_array().into_iter().flatten() {
+                print_event(record);
+            }
+        }
+        Command::Watch => {
+            let mut subscription = control::subscribe().await?.ok_or_else(not_running)?;
+            while let Some(event) = subscription.next().await? {
+                print_event(&event);
+            }
+            eprintln!("doorknob went away");
+        }
+        Command::MotionTable { action, json } => {
+            let motion = &config().motion;
+            let profile = match action {
+                LockAction::Lock => &motion.lock,
+                LockAction::Unlock => &motion.unlock,
+            };
+            let table = motion::step_table(profile, motion.steps);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&table)?);
+                return Ok(());
+            }
+            println!("step,at_ms,delay_ms,speed");
+            for row in table {
+                println!(
+                    "{},{:.3},{:.3},{:.3}",
+                    row.step, row.at_ms, row.delay_ms, row.speed
+                );
+            }
+        }
+        Command::TestWebhooks => {
+            let reply = daemon_request(ControlRequest::TestWebhooks).await?;
+            match reply["queued"].as_u64() {
+                Some(0) | None => println!("no webhook endpoints configured"),
+                Some(queued) => println!("queued for {queued} endpoint(s)"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn print_event(event: &Value) {
+    let mut rest = event.clone();
+    let Some(details) = rest.as_object_mut() else {
+        return println!("{event}");
+    };
+    let at = details.remove("at").unwrap_or_default();
+    let kind = details.remove("type").unwrap_or_default();
+    match details.is_empty() {
+        true => println!("{} {}", text(&at), text(&kind)),
+        false => println!("{} {} {rest}", text(&at), text(&kind)),
+    }
+}
+
+async fn instruct(instruction: ControlInstruction, no_wait: bool) -> Result<(), anyhow::Error> {
+    let outcome = daemon_request(ControlRequest::Instruct {
+        instruction,
+        wait: !no_wait,
+    })
+    .await?;
+    print_outcome(&outcome)
+}
+
+fn print_outcome(outcome: &Value) -> Result<(), anyhow::Error> {
+    let head = format!("#{} {}", outcome["id"], text(&outcome["instruction"]));
+    match &outcome["result"] {
+        Value::Null => println!("{head} queued"),
+        result if result["status"] == "failed" || result["status"] == "refused" => {
+            return Err(anyhow!(
+                "{head} {}. {}",
+                text(&result["status"]),
+                text(&result["reason"])
+            ));
+        }
+        result => println!("{head} {}", text(&result["status"])),
+    }
+    Ok(())
+}
+
+// the passcode never goes on the command line, where ps and shell history would see it
+fn read_passcode(file: Option<&Path>) -> Result<String, anyhow::Error> {
+    let raw = match file {
+        Some(file) => fs::read_to_string(file)?,
+        None => {
+            if stdin().is_terminal() {
+                eprint!("Passcode: ");
+                let _ = stderr().flush();
+            }
+            let mut line = String::new();
+            stdin().read_line(&mut line)?;
+            line
+        }
+    };
+    let password = raw.lines().next().unwrap_or_default().to_string();
+    if password.is_empty() {
+        return Err(anyhow!("no passcode given"));
+    }
+    Ok(password)
+}
+
+fn into_result(reply: ControlReply) -> Result<Value, anyhow::Error> {
+    match reply.ok {
+        true => Ok(reply.data.unwrap_or_default()),
+        false => Err(anyhow!(reply.error.unwrap_or_default())),
+    }
+}
+
+async fn daemon_request(request: ControlRequest) -> Result<Value, anyhow::Error> {
+    match control::request(&request).await? {
+        Some(reply) => into_result(reply),
+        None => Err(not_running()),
+    }
+}
+
+fn not_running() -> anyhow::Error {
+    anyhow!(
+        "doorknob isn't running, nothing is listening on {}",
+        config().control.socket.display()
+    )
+}
+
+// goes through the daemon when it's up so its copy of the users stays current, otherwise straight to the file
+async fn user_request(request: ControlRequest) -> Result<Value, anyhow::Error> {
+    if let Some(reply) = control::request(&request).await? {
+        return into_result(reply);
+    }
+    auth::load_users().await?;
+    control::handle_user_request(request).await
+}
@@ -0,0 +1,102 @@
+#[cfg(test)]
+use std::sync::{Condvar, Mutex};
+use std::{
+    fmt,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+#[cfg(test)]
+use tokio::sync::watch;
+
+pub type SharedClock = Arc<dyn Clock>;
+
+// everything that waits on purpose (motor steps, debounce, the autolock timers) asks this instead of
+// std or tokio directly, so a test can swap in a VirtualClock and decide when time passes
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now() + duration)
+    }
+
+    // for the motor thread, which sleeps between pulses without a runtime
+    fn block_for(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+
+    fn block_for(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+// stands still until advance is called, then wakes every sleeper whose deadline has passed.
+// blocking sleepers too, so whatever calls advance must be on another thread than the motor.
+// the daemon only ever runs on SystemClock, so this is only built for the tests
+#[cfg(test)]
+#[derive(Debug)]
+pub struct VirtualClock {
+    now: watch::Sender<Instant>,
+    // advance holds this while moving time so a blocking sleeper can't miss the wakeup
+    moved: Mutex<()>,
+    moved_signal: Condvar,
+}
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            now: watch::Sender::new(Instant::now()),
+            moved: Mutex::new(()),
+            moved_signal: Condvar::new(),
+        })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let _moving = self.moved.lock().unwrap();
+        self.now.send_modify(|now| *now += by);
+        self.moved_signal.notify_all();
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // the sender lives as long as the clock, and a dropped clock means nobody's advancing it anyway
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        })
+    }
+
+    fn block_for(&self, duration: Duration) {
+        let deadline = self.now() + duration;
+        let mut moving = self.moved.lock().unwrap();
+        while self.now() < deadline {
+            moving = self.moved_signal.wait(moving).unwrap();
+        }
+    }
+}
@@ -0,0 +1,811 @@
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::{lock::QueuePolicy, rpi::MIN_STEP_DELAY};
+
+pub const DEFAULT_CONFIG_FILE: &str = "doorknob.toml";
+const ENV_PREFIX: &str = "DOORKNOB";
+const CONFIG_FILE_VAR: &str = "DOORKNOB_CONFIG"; // the cli's --config, not a key
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+// everything defaults to how the lock was originally wired, so an empty file is a valid config
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub gpio: GpioConfig,
+    pub motion: MotionConfig,
+    pub feedback: FeedbackConfig,
+    pub sensor: SensorConfig,
+    pub server: ServerConfig,
+    pub lock: LockConfig,
+    pub control: ControlConfig,
+    pub audit: AuditConfig,
+    pub log: LogConfig,
+    pub mqtt: MqttConfig,
+    pub webhooks: WebhooksConfig,
+    pub files: FilesConfig,
+}
+
+// BCM pin numbers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpioConfig {
+    pub backend: GpioBackend,
+    pub ready_led_pin: u8,
+    pub in_use_led_pin: u8,
+    pub motor_dir_pin: u8,
+    pub motor_step_pin: u8,
+    pub motor_enable_pin: u8,
+    pub motor_sleep_pin: u8,
+    pub button_pin: u8,
+    pub ultrasonic_trigger_pin: u8,
+    pub ultrasonic_echo_pin: u8,
+    // bolt feedback, all optional. leave out whatever isn't wired
+    pub locked_stop_pin: Option<u8>, // closes to ground with the bolt fully thrown
+    pub unlocked_stop_pin: Option<u8>, // closes to ground with the bolt fully retracted
+    pub encoder_a_pin: Option<u8>,
+    pub encoder_b_pin: Option<u8>,
+}
+
+impl Default for GpioConfig {
+    fn default() -> Self {
+        Self {
+            backend: GpioBackend::default(),
+            ready_led_pin: 17,
+            in_use_led_pin: 22,
+            motor_dir_pin: 23,
+            motor_step_pin: 24,
+            motor_enable_pin: 18,
+            motor_sleep_pin: 4,
+            button_pin: 21,
+            ultrasonic_trigger_pin: 16,
+            ultrasonic_echo_pin: 20,
+            locked_stop_pin: None,
+            unlocked_stop_pin: None,
+            encoder_a_pin: None,
+            encoder_b_pin: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpioBackend {
+    Mock,      // no hardware, inputs sit idle. what you get on a dev machine
+    Simulator, // a virtual board you can poke at through /debug/sim
+    Rppal,     // the pi's gpio, only in builds with the hardware feature
+}
+
+impl Default for GpioBackend {
+    fn default() -> Self {
+        match cfg!(feature = "hardware") {
+            true => GpioBackend::Rppal,
+            false => GpioBackend::Mock,
+        }
+    }
+}
+
+impl GpioConfig {
+    pub fn assignments(&self) -> Vec<(&'static str, u8)> {
+        let optional = [
+            ("locked_stop_pin", self.locked_stop_pin),
+            ("unlocked_stop_pin", self.unlocked_stop_pin),
+            ("encoder_a_pin", self.encoder_a_pin),
+            ("encoder_b_pin", self.encoder_b_pin),
+        ];
+        let mut assignments = vec![
+            ("ready_led_pin", self.ready_led_pin),
+            ("in_use_led_pin", self.in_use_led_pin),
+            ("motor_dir_pin", self.motor_dir_pin),
+            ("motor_step_pin", self.motor_step_pin),
+            ("motor_enable_pin", self.motor_enable_pin),
+            ("motor_sleep_pin", self.motor_sleep_pin),
+            ("button_pin", self.button_pin),
+            ("ultrasonic_trigger_pin", self.ultrasonic_trigger_pin),
+            ("ultrasonic_echo_pin", self.ultrasonic_echo_pin),
+        ];
+        assignments.extend(
+            optional
+                .into_iter()
+                .filter_map(|(name, pin)| pin.map(|pin| (name, pin))),
+        );
+        assignments
+    }
+
+    pub fn encoder_pins(&self) -> Option<(u8, u8)> {
+        self.encoder_a_pin.zip(self.encoder_b_pin)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotionConfig {
+    pub steps: u64,
+    pub lock: MotionProfile,
+    pub unlock: MotionProfile, // its own, a deadbolt often wants a gentler start one way
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            steps: 60,
+            lock: MotionProfile::default(),
+            unlock: MotionProfile::default(),
+        }
+    }
+}
+
+impl MotionConfig {
+    pub fn profiles(&self) -> [(&'static str, &MotionProfile); 2] {
+        [("lock", &self.lock), ("unlock", &self.unlock)]
+    }
+}
+
+// speeds in steps/s. ramps up from start_speed, cruises at max_speed and ramps back down to stop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotionProfile {
+    pub shape: ProfileShape,
+    pub start_speed: f64,  // first and last step
+    pub max_speed: f64,    // cruising
+    pub acceleration: f64, // steps/s²
+    pub jerk: f64,         // steps/s³, how quickly the acceleration itself builds. s_curve only
+}
+
+impl Default for MotionProfile {
+    // roughly the old 40ms -> 30ms ramp
+    fn default() -> Self {
+        Self {
+            shape: ProfileShape::Trapezoidal,
+            start_speed: 25.0,
+            max_speed: 33.0,
+            acceleration: 25.0,
+            jerk: 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileShape {
+    Trapezoidal, // constant acceleration, speed ramps in straight lines
+    SCurve,      // acceleration ramps in and out at `jerk`, easier on the motor and the bolt
+}
+
+// what the end-stops and encoder in [gpio] are checked against
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedbackConfig {
+    pub encoder_counts: i64, // counts from locked to fully unlocked, negative if the encoder counts down that way
+    pub encoder_tolerance: i64, // counts off either way before a move or an idle bolt is wrong
+    pub overtravel_steps: u64, // how far past motion.steps a move keeps looking for its end-stop
+    pub check_interval_ms: u64, // how often an idle lock reads its end-stops and encoder
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        Self {
+            encoder_counts: 60,
+            encoder_tolerance: 4,
+            overtravel_steps: 10,
+            check_interval_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorConfig {
+    pub frame_threshold_cm: u64, // closer than this means the door is shut
+    pub autolock_interval_secs: u64,
+    pub err_tolerance: u32, // far readings in a row before we believe the door is open
+    pub poll_interval_ms: u64,
+    pub cooldown_ms: u64, // after the sensor autolocks
+    pub button_poll_ms: u64,
+    pub button_debounce_ms: u64,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            frame_threshold_cm: 6,
+            autolock_interval_secs: 10,
+            err_tolerance: 3,
+            poll_interval_ms: 1000,
+            cooldown_ms: 5000,
+            button_poll_ms: 100,
+            button_debounce_ms: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub metrics: bool, // serve /metrics for prometheus. no auth, like most scrape targets
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:3000".to_string(),
+            metrics: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+    pub queue_policy: QueuePolicy,
+    pub autolock_secs: u64, // 0 turns the timed autolock off
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            queue_policy: QueuePolicy::Queue,
+            autolock_secs: 60,
+        }
+    }
+}
+
+impl LockConfig {
+    pub fn autolock_delay(&self) -> Option<Duration> {
+        (self.autolock_secs > 0).then(|| Duration::from_secs(self.autolock_secs))
+    }
+}
+
+// the local socket the cli talks to. whoever can open it can do anything, so keep the mode tight
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub socket: PathBuf,
+    pub socket_mode: u32,
+    pub socket_group: String, // empty leaves it with ours
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from("doorknob.sock"),
+            socket_mode: 0o600,
+            socket_group: String::new(),
+        }
+    }
+}
+
+// files.audit_log rolls over to .1, .2... once it reaches max_bytes. the oldest past keep is dropped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+// filter uses RUST_LOG syntax, e.g. "info,doorknob::lock=debug". RUST_LOG itself still wins when set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,     // one line per event with timestamps, for a terminal or a plain log file
+    Json,     // one json object per line, for shipping somewhere
+    Journald, // straight to the systemd journal with levels and fields intact
+}
+
+// the broker's own auth and ACLs are what keep strangers off base_topic/lock/set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: String, // empty connects anonymously
+    pub password: String,
+    pub base_topic: String,
+    pub discovery_prefix: String, // home assistant's, "homeassistant" unless you changed it there
+    pub device_name: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "doorknob".to_string(),
+            username: String::new(),
+            password: String::new(),
+            base_topic: "doorknob".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            device_name: "Doorknob".to_string(),
+        }
+    }
+}
+
+// when the events fire, how long undeliverable ones are retried, and who gets what
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub door_open_secs: u64, // door_left_open after this long, 0 turns it off
+    pub auth_burst: usize,   // auth_failure_burst at this many failures...
+    pub auth_burst_window_secs: u64, // ...within this many seconds, from anywhere
+    pub retry_for_secs: u64, // an endpoint that's down this long loses the event
+    pub max_backoff_secs: u64,
+    pub endpoints: Vec<WebhookEndpoint>,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            door_open_secs: 300,
+            auth_burst: 5,
+            auth_burst_window_secs: 60,
+            retry_for_secs: 24 * 60 * 60,
+            max_backoff_secs: 15 * 60,
+            endpoints: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    pub secret: String, // HMAC-SHA256 key for the X-Doorknob-Signature header
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Unlocked,
+    Locked,
+    AuthFailureBurst,
+    DoorLeftOpen,
+    MotorFault,
+    Test, // only from `doorknob test-webhooks`, every endpoint gets it
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    pub lock_state: PathBuf,
+    pub users: PathBuf,
+    pub legacy_password_hash: PathBuf, // only read, to import the pre-users admin passcode
+    pub guest_codes: PathBuf,
+    pub tokens: PathBuf,
+    pub lockouts: PathBuf,
+    pub audit_log: PathBuf,
+    pub webhook_queue: PathBuf,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            lock_state: PathBuf::from("lock_state.json"),
+            users: PathBuf::from("users.json"),
+            legacy_password_hash: PathBuf::from("password_hash.txt"),
+            guest_codes: PathBuf::from("guest_codes.json"),
+            tokens: PathBuf::from("tokens.json"),
+            lockouts: PathBuf::from("lockouts.json"),
+            audit_log: PathBuf::from("audit.jsonl"),
+            webhook_queue: PathBuf::from("webhook_queue.json"),
+        }
+    }
+}
+
+// every problem with a config at once, so fixing it isn't a game of whack-a-mole
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut problems = Vec::new();
+
+        let mut pins: HashMap<u8, Vec<&str>> = HashMap::new();
+        for (name, pin) in self.gpio.assignments() {
+            if pin > 27 {
+                problems.push(format!("gpio.{name} = {pin} is not a BCM GPIO pin (0-27)"));
+            }
+            pins.entry(pin).or_default().push(name);
+        }
+        let mut shared: Vec<_> = pins.iter().filter(|(_, names)| names.len() > 1).collect();
+        shared.sort();
+        for (pin, names) in shared {
+            problems.push(format!(
+                "gpio pin {pin} is assigned more than once: {}",
+                names.join(", ")
+            ));
+        }
+        if self.gpio.backend == GpioBackend::Rppal && !cfg!(feature = "hardware") {
+            problems.push(
+                "gpio.backend = \"rppal\" needs a build with --features hardware".to_string(),
+            );
+        }
+
+        if self.motion.steps == 0 {
+            problems.push("motion.steps must be at least 1".to_string());
+        }
+        let fastest = 1.0 / MIN_STEP_DELAY.as_secs_f64();
+        for (name, profile) in self.motion.profiles() {
+            let positive = |value: f64| value.is_finite() && value > 0.0;
+            if !positive(profile.start_speed) || !positive(profile.max_speed) {
+                problems.push(format!(
+                    "motion.{name} start_speed and max_speed must be above 0"
+                ));
+            } else if profile.start_speed > profile.max_speed {
+                problems.push(format!(
+                    "motion.{name}.start_speed ({}) can't be above its max_speed ({})",
+                    profile.start_speed, profile.max_speed
+                ));
+            }
+            if profile.max_speed > fastest {
+                problems.push(format!(
+                    "motion.{name}.max_speed can be at most {fastest:.0} steps/s, the driver needs {}ms between steps",
+                    MIN_STEP_DELAY.as_millis()
+                ));
+            }
+            if !positive(profile.acceleration) {
+                problems.push(format!("motion.{name}.acceleration must be above 0"));
+            }
+            if profile.shape == ProfileShape::SCurve && !positive(profile.jerk) {
+                problems.push(format!("motion.{name}.jerk must be above 0 for an s_curve"));
+            }
+        }
+
+        if self.gpio.encoder_a_pin.is_some() != self.gpio.encoder_b_pin.is_some() {
+            problems.push("gpio.encoder_a_pin and gpio.encoder_b_pin go together".to_string());
+        }
+        if self.gpio.encoder_pins().is_some() && self.feedback.encoder_counts == 0 {
+            problems.push("feedback.encoder_counts can't be 0 with an encoder wired".to_string());
+        }
+        if self.feedback.encoder_tolerance < 0 || self.feedback.check_interval_ms == 0 {
+            problems.push(
+                "feedback.encoder_tolerance can't be negative and feedback.check_interval_ms must be above 0"
+                    .to_string(),
+            );
+        }
+
+        if self.sensor.frame_threshold_cm == 0 {
+            problems.push("sensor.frame_threshold_cm must be above 0".to_string());
+        }
+        if self.sensor.poll_interval_ms == 0 || self.sensor.button_poll_ms == 0 {
+            problems.push("sensor poll intervals must be above 0".to_string());
+        }
+
+        if let Err(e) = self.server.bind.parse::<SocketAddr>() {
+            problems.push(format!(
+                "server.bind '{}' is not an address. {e}",
+                self.server.bind
+            ));
+        }
+
+        if self.control.socket_mode > 0o777 {
+            problems.push(format!(
+                "control.socket_mode {:o} is not a file mode",
+                self.control.socket_mode
+            ));
+        }
+
+        if self.audit.keep == 0 || self.audit.max_bytes == 0 {
+            problems.push("audit.keep and audit.max_bytes must be above 0".to_string());
+        }
+        if self.mqtt.enabled {
+            if self.mqtt.host.is_empty() || self.mqtt.client_id.is_empty() {
+                problems.push("mqtt.host and mqtt.client_id can't be empty".to_string());
+            }
+            // it's the node id in the discovery topics, which home assistant is picky about
+            if !self
+                .mqtt
+                .client_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                problems.push(format!(
+                    "mqtt.client_id '{}' can only have letters, digits, _ and -",
+                    self.mqtt.client_id
+                ));
+            }
+            let topics = [&self.mqtt.base_topic, &self.mqtt.discovery_prefix];
+            if topics
+                .iter()
+                .any(|topic| topic.is_empty() || topic.contains(['+', '#']))
+            {
+                problems.push(
+                    "mqtt.base_topic and mqtt.discovery_prefix must be set and can't have wildcards"
+                        .to_string(),
+                );
+            }
+        }
+        for (i, endpoint) in self.webhooks.endpoints.iter().enumerate() {
+            if !endpoint.url.starts_with("http://") && !endpoint.url.starts_with("https://") {
+                problems.push(format!(
+                    "webhooks.endpoints[{i}].url '{}' must be http:// or https://",
+                    endpoint.url
+                ));
+            }
+            if endpoint.secret.is_empty() {
+                problems.push(format!(
+                    "webhooks.endpoints[{i}].secret can't be empty, it signs every payload"
+                ));
+            }
+            if endpoint.events.is_empty() {
+                problems.push(format!("webhooks.endpoints[{i}].events is empty"));
+            }
+        }
+        if self.webhooks.auth_burst == 0 || self.webhooks.max_backoff_secs == 0 {
+            problems.push(
+                "webhooks.auth_burst and webhooks.max_backoff_secs must be above 0".to_string(),
+            );
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.filter) {
+            problems.push(format!(
+                "log.filter '{}' is not a valid filter. {e}",
+                self.log.filter
+            ));
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigErrors(problems)),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.server
+            .bind
+            .parse()
+            .expect("server.bind is checked by validate")
+    }
+}
+
+// DOORKNOB_<SECTION>_<KEY> wins over the file, e.g. DOORKNOB_SERVER_BIND=127.0.0.1:8080.
+// a value is read as whatever the key already holds, see override_value. the key doesn't have to be
+// in the table yet, optional ones like gpio.locked_stop_pin only show up once they're set. one that
+// isn't a key at all lands in the table anyway, where deny_unknown_fields turns it down
+fn apply_env_overrides<I>(table: &mut toml::Table, vars: I)
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(var, _)| var.starts_with(&format!("{ENV_PREFIX}_")) && var != CONFIG_FILE_VAR)
+        .collect();
+    vars.sort();
+    for (var, raw) in vars {
+        let (values, mut path, key) = override_target(table, &var[ENV_PREFIX.len() + 1..]);
+        let value = override_value(values.get(&key), raw);
+        values.insert(key.clone(), value);
+        path.push(key);
+        eprintln!("{var} overrides {}", path.join("."));
+    }
+}
+
+// a string key takes the value as is, so a passcode of 123456 or a quoted name stays a string.
+// a number or bool has to parse as one, otherwise it goes in as a string and deserializing turns it
+// down. anything else, and keys that aren't in the table yet, are read as toml
+fn override_value(existing: Option<&toml::Value>, raw: String) -> toml::Value {
+    let parsed = match existing {
+        Some(toml::Value::String(_)) => return toml::Value::String(raw),
+        Some(toml::Value::Integer(_)) => raw.parse().ok().map(toml::Value::Integer),
+        Some(toml::Value::Float(_)) => raw.parse().ok().map(toml::Value::Float),
+        Some(toml::Value::Boolean(_)) => raw.parse().ok().map(toml::Value::Boolean),
+        _ => format!("v = {raw}")
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut parsed| parsed.remove("v")),
+    };
+    parsed.unwrap_or(toml::Value::String(raw))
+}
+
+// follows the tables the name starts with down as far as they go, e.g. MOTION_LOCK_MAX_SPEED
+// ends up in motion.lock with max_speed left over as the key
+fn override_target<'a>(
+    table: &'a mut toml::Table,
+    name: &str,
+) -> (&'a mut toml::Table, Vec<String>, String) {
+    let mut values = table;
+    let mut path = Vec::new();
+    let mut rest = name.to_lowercase();
+    loop {
+        let section = values
+            .iter()
+            .filter(|(_, value)| value.is_table())
+            .map(|(section, _)| section.clone())
+            .find(|section| {
+                rest.strip_prefix(section.as_str())
+                    .is_some_and(|after| after.starts_with('_'))
+            });
+        let Some(section) = section else {
+            return (values, path, rest);
+        };
+        rest = rest[section.len() + 1..].to_string();
+        values = values
+            .get_mut(&section)
+            .and_then(|value| value.as_table_mut())
+            .expect("just found it");
+        path.push(section);
+    }
+}
+
+// logging is set up from what this returns, so anything it has to say goes straight to stderr
+pub fn load_from(path: Option<&Path>) -> Result<Config, anyhow::Error> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+    };
+    let from_file: Config = if path.exists() {
+        let raw = fs::read_to_string(&path)?;
+        toml::from_str(&raw).map_err(|e| anyhow!("{}: {e}", path.display()))?
+    } else if required {
+        return Err(anyhow!("config file {} does not exist", path.display()));
+    } else {
+        eprintln!("No {DEFAULT_CONFIG_FILE}, using defaults");
+        Config::default()
+    };
+
+    // round trip through a table so every key exists and can be overridden
+    let mut table = toml::Table::try_from(&from_file)?;
+    apply_env_overrides(&mut table, env::vars());
+    let config: Config = table.try_into()?;
+    config.validate()?;
+    Ok(config)
+}
+
+pub fn init(path: Option<&Path>) -> Result<&'static Config, anyhow::Error> {
+    let config = load_from(path)?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+// the tests' init, see testing::config. whoever gets here first decides for the whole run
+#[cfg(test)]
+pub fn init_for_tests(make: impl FnOnce() -> Config) -> &'static Config {
+    CONFIG.get_or_init(make)
+}
+
+pub fn config() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("config is loaded first thing in main. Justin you fucked up the control flow")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overridden(vars: &[(&str, &str)]) -> Result<Config, toml::de::Error> {
+        let mut table = toml::Table::try_from(Config::default()).unwrap();
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        apply_env_overrides(&mut table, vars);
+        table.try_into()
+    }
+
+    #[test]
+    fn env_overrides_existing_keys() {
+        let config = overridden(&[
+            ("DOORKNOB_SERVER_BIND", "127.0.0.1:8080"),
+            ("DOORKNOB_GPIO_BUTTON_PIN", "26"),
+            ("DOORKNOB_LOCK_AUTOLOCK_SECS", "0"),
+        ])
+        .unwrap();
+        assert_eq!(config.server.bind, "127.0.0.1:8080");
+        assert_eq!(config.gpio.button_pin, 26);
+        assert_eq!(config.lock.autolock_secs, 0);
+    }
+
+    #[test]
+    fn env_reaches_nested_tables() {
+        let config = overridden(&[("DOORKNOB_MOTION_LOCK_MAX_SPEED", "40")]).unwrap();
+        assert_eq!(config.motion.lock.max_speed, 40.0);
+        assert_eq!(
+            config.motion.unlock.max_speed,
+            Config::default().motion.unlock.max_speed
+        );
+    }
+
+    #[test]
+    fn env_sets_optional_keys_missing_from_the_table() {
+        let config = overridden(&[
+            ("DOORKNOB_GPIO_LOCKED_STOP_PIN", "5"),
+            ("DOORKNOB_GPIO_ENCODER_A_PIN", "12"),
+            ("DOORKNOB_GPIO_ENCODER_B_PIN", "13"),
+        ])
+        .unwrap();
+        assert_eq!(config.gpio.locked_stop_pin, Some(5));
+        assert_eq!(config.gpio.unlocked_stop_pin, None);
+        assert_eq!(config.gpio.encoder_pins(), Some((12, 13)));
+    }
+
+    #[test]
+    fn env_keeps_strings_as_given() {
+        let config = overridden(&[
+            ("DOORKNOB_MQTT_PASSWORD", "123456"),
+            ("DOORKNOB_MQTT_USERNAME", "true"),
+            ("DOORKNOB_MQTT_CLIENT_ID", "1e3"),
+            ("DOORKNOB_MQTT_DEVICE_NAME", "\"Front door\""),
+        ])
+        .unwrap();
+        assert_eq!(config.mqtt.password, "123456");
+        assert_eq!(config.mqtt.username, "true");
+        assert_eq!(config.mqtt.client_id, "1e3");
+        assert_eq!(config.mqtt.device_name, "\"Front door\"");
+    }
+
+    #[test]
+    fn env_reads_numbers_by_the_key_type() {
+        let config = overridden(&[
+            ("DOORKNOB_MOTION_UNLOCK_ACCELERATION", "250"),
+            ("DOORKNOB_MQTT_ENABLED", "true"),
+        ])
+        .unwrap();
+        assert_eq!(config.motion.unlock.acceleration, 250.0);
+        assert!(config.mqtt.enabled);
+        assert!(overridden(&[("DOORKNOB_GPIO_BUTTON_PIN", "twenty")]).is_err());
+    }
+
+    #[test]
+    fn env_rejects_unknown_keys() {
+        assert!(overridden(&[("DOORKNOB_GPIO_LOKED_STOP_PIN", "5")]).is_err());
+        assert!(overridden(&[("DOORKNOB_NOPE", "1")]).is_err());
+    }
+
+    #[test]
+    fn env_ignores_other_variables() {
+        let config = overridden(&[
+            ("DOORKNOB_CONFIG", "/etc/doorknob.toml"),
+            ("PATH", "/usr/bin"),
+        ])
+        .unwrap();
+        assert_eq!(config.server.bind, Config::default().server.bind);
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt, chown},
+    path::Path,
+    process,
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        UnixListener, UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    select,
+    sync::{
+        broadcast::{Receiver, error::RecvError},
+        mpsc::Sender,
+    },
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{self, AuditQuery},
+    auth::{self, Role},
+    config::{WebhookEvent, config},
+    events::{self, LockEvent},
+    lock::{
+        InstructionSource, LockFaulted, LockInstruction, LockInstructor, QueuedInstruction,
+        current_state,
+    },
+    state_file, webhooks,
+};
+
+// one json object per line each way. every request gets exactly one reply,
+// except subscribe, which turns the connection into a stream of events after its reply
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    State,
+    Instruct {
+        instruction: ControlInstruction,
+        #[serde(default)]
+        wait: bool,
+    },
+    Subscribe,
+    // same filters as /api/v1/history, all optional
+    History(AuditQuery),
+    // a test event to every webhook endpoint, replies with how many were queued
+    TestWebhooks,
+    SetPassword {
+        user: String,
+        password: String,
+    },
+    AddUser {
+        name: String,
+        password: String,
+        role: Role,
+    },
+    RemoveUser {
+        name: String,
+    },
+    ListUsers,
+}
+
+// every LockInstruction, spelled the way LockInstruction::kind spells them
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlInstruction {
+    Lock,
+    Unlock,
+    Toggle,
+    HoldOpen,
+    MarkLocked,
+    MarkUnlocked,
+    Home,
+}
+
+impl ControlInstruction {
+    fn with_source(self, source: InstructionSource) -> LockInstruction {
+        match self {
+            ControlInstruction::Lock => LockInstruction::EnsureLocked(source),
+            ControlInstruction::Unlock => LockInstruction::EnsureUnlocked(source),
+            ControlInstruction::Toggle => LockInstruction::Reverse(source),
+            ControlInstruction::HoldOpen => LockInstruction::HoldOpen(source),
+            ControlInstruction::MarkLocked => LockInstruction::MarkLocked(source),
+            ControlInstruction::MarkUnlocked => LockInstruction::MarkUnlocked(source),
+            ControlInstruction::Home => LockInstruction::Home(source),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlReply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<Value, anyhow::Error>> for ControlReply {
+    fn from(result: Result<Value, anyhow::Error>) -> Self {
+        match result {
+            Ok(data) => ControlReply {
+                ok: true,
+                data: Some(data),
+                error: None,
+            },
+            Err(e) => ControlReply {
+                ok: false,
+                data: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+// a leftover socket from a crash is removed, a live one means we're already running
+async fn bind(path: &Path) -> Result<UnixListener, anyhow::Error> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow!(
+                "something is already serving {}, is doorknob running twice?",
+                path.display()
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    // its mode is all the auth the socket has, so it's made in a directory nobody else can get
+    // into and only moved into place once it has the right mode and group
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+    let staging = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    let _ = fs::remove_dir_all(&staging);
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let bound = (|| {
+        let listener = UnixListener::bind(&staged)?;
+        let control = &config().control;
+        if !control.socket_group.is_empty() {
+            chown(&staged, None, Some(resolve_group(&control.socket_group)?))?;
+        }
+        fs::set_permissions(&staged, fs::Permissions::from_mode(control.socket_mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+// a gid, or a group name looked up in /etc/group
+fn resolve_group(group: &str) -> Result<u32, anyhow::Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    fs::read_to_string("/etc/group")?
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            match (fields.next(), fields.nth(1)) {
+                (Some(name), Some(gid)) if name == group => gid.parse().ok(),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| anyhow!("no group '{group}' in /etc/group"))
+}
+
+pub async fn serve(lock_tx: Arc<Sender<QueuedInstruction>>) -> Result<(), anyhow::Error> {
+    let path = &config().control.socket;
+    let listener = bind(path).await?;
+    info!(
+        "Control socket at {} (mode {:o}{})",
+        path.display(),
+        config().control.socket_mode,
+        match config().control.socket_group.as_str() {
+            "" => String::new(),
+            group => format!(", group {group}"),
+        }
+    );
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let lock_tx = Arc::clone(&lock_tx);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, lock_tx).await {
+                warn!("Control connection died. {e}");
+            }
+        });
+    }
+}
+
+async fn write_line<T: Serialize>(writer: &mut OwnedWriteHalf, value: &T) -> io::Result<()> {
+    let mut out = serde_json::to_vec(value)?;
+    out.push(b'\n');
+    writer.write_all(&out).await
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    lock_tx: Arc<Sender<QueuedInstruction>>,
+) -> Result<(), anyhow::Error> {
+    // the socket's permissions already decided they're allowed in. the uid is just for the record
+    let source = InstructionSource::ControlSocket {
+        uid: stream.peer_cred().ok().map(|cred| cred.uid()),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let request = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let reply: ControlReply = Err(anyhow!("bad request. {e}")).into();
+                write_line(&mut writer, &reply).await?;
+                continue;
+            }
+        };
+        if let ControlRequest::Subscribe = request {
+            // subscribe before answering so nothing slips through in between
+            let events = events::subscribe();
+            write_line(&mut writer, &ControlReply::from(Ok(Value::Null))).await?;
+            return forward_events(events, lines, writer).await;
+        }
+        let reply: ControlReply = handle(&lock_tx, &source, request).await.into();
+        write_line(&mut writer, &reply).await?;
+    }
+    Ok(())
+}
+
+// until the client hangs up. anything else they send is ignored
+async fn forward_events(
+    mut events: Receiver<LockEvent>,
+    mut lines: ReplyLines,
+    mut writer: OwnedWriteHalf,
+) -> Result<(), anyhow::Error> {
+    loop {
+        select! {
+            event = events.recv() => match event {
+                Ok(event) => write_line(&mut writer, &event).await?,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Control subscriber fell behind, skipped {skipped} event(s)")
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            line = lines.next_line() => if line?.is_none() {
+                return Ok(());
+            },
+        }
+    }
+}
+
+async fn handle(
+    lock_tx: &Arc<Sender<QueuedInstruction>>,
+    source: &InstructionSource,
+    request: ControlRequest,
+) -> Result<Value, anyhow::Error> {
+    match request {
+        ControlRequest::Status => {
+            let since = state_file::load().ok().flatten().map(|r| r.recorded_at);
+            let state = current_state();
+            let fault = state.is_fault().then(|| {
+                LockFaulted {
+                    state: state.clone(),
+                }
+                .to_string()
+            });
+            Ok(json!({
+                "state": state,
+                "state_name": state.name(),
+                "fault": fault,
+                "since": since,
+                "queue_policy": config().lock.queue_policy,
+                "autolock_secs": config().lock.autolock_secs,
+            }))
+        }
+        ControlRequest::State => Ok(json!({ "state": current_state() })),
+        ControlRequest::Instruct { instruction, wait } => {
+            instruct(lock_tx, instruction.with_source(source.clone()), wait).await
+        }
+        ControlRequest::History(query) => Ok(serde_json::to_value(audit::query(query).await?)?),
+        ControlRequest::TestWebhooks => Ok(json!({
+            "queued": webhooks::raise(WebhookEvent::Test, json!({ "requested_by": source.user() })),
+        })),
+        request => handle_user_request(request).await,
+    }
+}
+
+// everything that doesn't need the lock. the cli runs these itself when the daemon is down
+pub async fn handle_user_request(request: ControlRequest) -> Result<Value, anyhow::Error> {
+    match request {
+        ControlRequest::SetPassword { user, password } => {
+            auth::set_password(&user, &password).await?;
+            Ok(Value::Null)
+        }
+        ControlRequest::AddUser {
+            name,
+            password,
+            role,
+        } => {
+            auth::add_user(&name, &password, role).await?;
+            Ok(Value::Null)
+        }
+        ControlRequest::RemoveUser { name } => {
+            auth::remove_user(&name).await?;
+            Ok(Value::Null)
+        }
+        ControlRequest::ListUsers => Ok(serde_json::to_value(auth::list_users().await)?),
+        other => Err(anyhow!("{other:?} needs the running daemon")),
+    }
+}
+
+async fn instruct(
+    lock_tx: &Arc<Sender<QueuedInstruction>>,
+    instruction: LockInstruction,
+    wait: bool,
+) -> Result<Value, anyhow::Error> {
+    let kind = instruction.kind();
+    let ticket = lock_tx.send_instruction(instruction).await?;
+    let id = ticket.id;
+    let result = match wait {
+        true => Some(ticket.completion().await),
+        false => None,
+    };
+    Ok(json!({ "id": id, "instruction": kind, "result": result }))
+}
+
+// client side. Ok(None) means nobody is listening, i.e. the daemon isn't running
+async fn connect() -> Result<Option<UnixStream>, anyhow::Error> {
+    let path = &config().control.socket;
+    match UnixStream::connect(path).await {
+        Ok(stream) => Ok(Some(stream)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(anyhow!("can't open {}. {e}", path.display())),
+    }
+}
+
+type ReplyLines = Lines<BufReader<OwnedReadHalf>>;
+
+async fn send(
+    stream: UnixStream,
+    request: &ControlRequest,
+) -> Result<(ControlReply, ReplyLines, OwnedWriteHalf), anyhow::Error> {
+    let (reader, mut writer) = stream.into_split();
+    write_line(&mut writer, request).await?;
+    let mut lines = BufReader::new(reader).lines();
+    let line = lines
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("doorknob hung up without answering"))?;
+    Ok((serde_json::from_str(&line)?, lines, writer))
+}
+
+pub async fn request(request: &ControlRequest) -> Result<Option<ControlReply>, anyhow::Error> {
+    match connect().await? {
+        Some(stream) => Ok(Some(send(stream, request).await?.0)),
+        None => Ok(None),
+    }
+}
+
+pub struct Subscription {
+    lines: ReplyLines,
+    _writer: OwnedWriteHalf, // dropping it would read as hanging up
+}
+
+impl Subscription {
+    // None once the daemon goes away
+    pub async fn next(&mut self) -> Result<Option<Value>, anyhow::Error> {
+        match self.lines.next_line().await? {
+            Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub async fn subscribe() -> Result<Option<Subscription>, anyhow::Error> {
+    let Some(stream) = connect().await? else {
+        return Ok(None);
+    };
+    let (reply, lines, writer) = send(stream, &ControlRequest::Subscribe).await?;
+    if !reply.ok {
+        return Err(anyhow!(reply.error.unwrap_or_default()));
+    }
+    Ok(Some(Subscription {
+        lines,
+        _writer: writer,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn the_socket_only_ever_shows_up_with_its_mode() {
+        let config = testing::config();
+        let path = testing::temp_path("control.sock");
+        let _ = fs::remove_file(&path);
+        let _listener = bind(&path).await.unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, config.control.socket_mode);
+        let staging = path.with_file_name(format!(".control.sock.{}", process::id()));
+        assert!(!staging.exists());
+
+        // a second daemon finds it in use, rather than taking it over
+        assert!(bind(&path).await.is_err());
+        UnixStream::connect(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::lock::{InstructionId, InstructionSource, LockAction, LockState};
+
+// slow subscribers skip ahead rather than holding anyone up
+const EVENT_BUFFER: usize = 64;
+
+static EVENTS: Lazy<Sender<LockEvent>> = Lazy::new(|| broadcast::channel(EVENT_BUFFER).0);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LockEventKind {
+    InstructionReceived {
+        id: InstructionId,
+        instruction: &'static str,
+        source: InstructionSource,
+    },
+    MotionStarted {
+        action: LockAction,
+    },
+    MotionFinished {
+        action: LockAction,
+    },
+    StateChanged {
+        state: LockState,
+    },
+    DoorOpened,
+    DoorClosed,
+    AutolockScheduled {
+        fires_at: DateTime<Utc>,
+    },
+    AutolockCancelled,
+    InstructionDropped {
+        id: InstructionId,
+        instruction: &'static str,
+        source: InstructionSource,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LockEvent {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: LockEventKind,
+}
+
+impl LockEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LockEventKind::InstructionReceived { .. } => "instruction_received",
+            LockEventKind::MotionStarted { .. } => "motion_started",
+            LockEventKind::MotionFinished { .. } => "motion_finished",
+            LockEventKind::StateChanged { .. } => "state_changed",
+            LockEventKind::DoorOpened => "door_opened",
+            LockEventKind::DoorClosed => "door_closed",
+            LockEventKind::AutolockScheduled { .. } => "autolock_scheduled",
+            LockEventKind::AutolockCancelled => "autolock_cancelled",
+            LockEventKind::InstructionDropped { .. } => "instruction_dropped",
+        }
+    }
+}
+
+pub fn publish(kind: LockEventKind) {
+    let event = LockEvent {
+        at: Utc::now(),
+        kind,
+    };
+    // an error only means nobody is listening right now
+    let _ = EVENTS.send(event);
+}
+
+pub fn subscribe() -> Receiver<LockEvent> {
+    EVENTS.subscribe()
+}
@@ -0,0 +1,415 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Local, Timelike, Utc, Weekday};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::{
+    auth::{self, hash_password, password_matches_async},
+    config::config,
+    lock::LockInstruction,
+    state_file::write_atomic,
+};
+
+static GUEST_CODES: Lazy<RwLock<Vec<GuestCode>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+// recurring window in local time, e.g. weekdays 9-17 for the dog walker.
+// end_hour is exclusive, so 9-17 stops working at 17:00
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub weekdays: Vec<Weekday>,
+    pub start_hour: u32,
+    pub end_hour: u32,
+}
+
+impl Schedule {
+    fn allows(&self, at: DateTime<Local>) -> bool {
+        self.weekdays.contains(&at.weekday())
+            && at.hour() >= self.start_hour
+            && at.hour() < self.end_hour
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestCodeUse {
+    pub at: DateTime<Utc>,
+    pub requested: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestCode {
+    pub name: String,
+    pub code_hash: String,
+    pub valid_from: DateTime<Utc>,
+    pub valid_until: DateTime<Utc>,
+    pub max_uses: Option<u32>,
+    pub schedule: Option<Schedule>,
+    pub unlock_only: bool,
+    pub uses: Vec<GuestCodeUse>,
+}
+
+impl GuestCode {
+    pub fn is_usable_at(&self, now: DateTime<Utc>) -> bool {
+        let in_window = now >= self.valid_from && now < self.valid_until;
+        let uses_left = self
+            .max_uses
+            .is_none_or(|max| (self.uses.len() as u32) < max);
+        let on_schedule = self
+            .schedule
+            .as_ref()
+            .is_none_or(|s| s.allows(now.with_timezone(&Local)));
+        in_window && uses_left && on_schedule
+    }
+}
+
+// what an admin hands us to mint a code
+#[derive(Debug, Clone)]
+pub struct NewGuestCode {
+    pub name: String,
+    pub code: String,
+    pub valid_from: DateTime<Utc>,
+    pub valid_until: DateTime<Utc>,
+    pub max_uses: Option<u32>,
+    pub schedule: Option<Schedule>,
+    pub unlock_only: bool,
+}
+
+pub fn guest_codes_file_path() -> PathBuf {
+    config().files.guest_codes.clone()
+}
+
+fn persist(codes: &[GuestCode]) -> Result<(), anyhow::Error> {
+    write_atomic(
+        &guest_codes_file_path(),
+        serde_json::to_string_pretty(codes)?.as_bytes(),
+    )
+}
+
+pub async fn load_codes() -> Result<(), anyhow::Error> {
+    let path = guest_codes_file_path();
+    let loaded: Vec<GuestCode> = if path.exists() {
+        serde_json::from_str(&fs::read_to_string(&path)?)?
+    } else {
+        Vec::new()
+    };
+    info!("Loaded {} guest code(s)", loaded.len());
+    *GUEST_CODES.write().await = loaded;
+    Ok(())
+}
+
+async fn update_codes<F, T>(f: F) -> Result<T, anyhow::Error>
+where
+    F: FnOnce(&mut Vec<GuestCode>) -> Result<T, anyhow::Error>,
+{
+    let mut codes = GUEST_CODES.write().await;
+    let mut updated = codes.clone();
+    let result = f(&mut updated)?;
+    persist(&updated)?;
+    *codes = updated;
+    Ok(result)
+}
+
+pub async fn create_code(new: NewGuestCode) -> Result<(), anyhow::Error> {
+    if new.name.trim().is_empty() || new.code.is_empty() {
+        return Err(anyhow!("name and code can't be empty"));
+    }
+    if new.valid_until <= new.valid_from {
+        return Err(anyhow!("code would expire before it starts"));
+    }
+    if let Some(schedule) = &new.schedule
+        && (schedule.weekdays.is_empty()
+            || schedule.start_hour >= schedule.end_hour
+            || schedule.end_hour > 24)
+    {
+        return Err(anyhow!("schedule never allows entry"));
+    }
+    // same deal as users, a typed code has to map to exactly one identity. that means every code,
+    // a spent one still matches first in find_usable and would hide the new one
+    if auth::passcode_in_use(&new.code).await? || code_in_use(&new.code).await? {
+        return Err(anyhow!("code is already in use"));
+    }
+
+    update_codes(|codes| {
+        if codes.iter().any(|c| c.name == new.name) {
+            return Err(anyhow!("guest code '{}' already exists", new.name));
+        }
+        codes.push(GuestCode {
+            name: new.name.clone(),
+            code_hash: hash_password(&new.code),
+            valid_from: new.valid_from,
+            valid_until: new.valid_until,
+            max_uses: new.max_uses,
+            schedule: new.schedule.clone(),
+            unlock_only: new.unlock_only,
+            uses: Vec::new(),
+        });
+        Ok(())
+    })
+    .await?;
+    info!(
+        "Created guest code '{}' valid {} to {}",
+        new.name, new.valid_from, new.valid_until
+    );
+    Ok(())
+}
+
+pub async fn revoke_code(name: &str) -> Result<(), anyhow::Error> {
+    update_codes(|codes| {
+        let before = codes.len();
+        codes.retain(|c| c.name != name);
+        if codes.len() == before {
+            return Err(anyhow!("no guest code '{name}'"));
+        }
+        Ok(())
+    })
+    .await?;
+    info!("Revoked guest code '{name}'");
+    Ok(())
+}
+
+pub async fn list_codes() -> Vec<GuestCode> {
+    GUEST_CODES.read().await.clone()
+}
+
+// every code's hash, usable or not, for checking a user's passcode against
+pub async fn code_hashes() -> Vec<String> {
+    GUEST_CODES
+        .read()
+        .await
+        .iter()
+        .map(|code| code.code_hash.clone())
+        .collect()
+}
+
+// any code at all, usable or not
+pub async fn code_in_use(checkpass: &str) -> Result<bool, anyhow::Error> {
+    for code in GUEST_CODES.read().await.iter() {
+        if password_matches_async(checkpass, &code.code_hash).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// returns the matching code if it can be used right now.
+// a code that matches but is expired, used up or off schedule looks exactly like a wrong one
+pub async fn find_usable(checkpass: &str) -> Result<Option<GuestCode>, anyhow::Error> {
+    let now = Utc::now();
+    for code in GUEST_CODES.read().await.iter() {
+        if password_matches_async(checkpass, &code.code_hash).await? {
+            if code.is_usable_at(now) {
+                return Ok(Some(code.clone()));
+            }
+            info!("Guest code '{}' entered outside of its validity", code.name);
+            return Ok(None);
+        }
+    }
+    Ok(None)
+}
+
+// hands back the latest use of this kind, for when the lock turned the instruction away
+pub async fn refund_use(name: String, requested: &'static str) {
+    let refunded = update_codes(|codes| {
+        let Some(code) = codes.iter_mut().find(|c| c.name == name) else {
+            return Ok(false);
+        };
+        match code.uses.iter().rposition(|u| u.requested == requested) {
+            Some(i) => {
+                code.uses.remove(i);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    })
+    .await;
+    match refunded {
+        Ok(true) => info!("Guest code '{name}' gets its use back, the lock didn't {requested}"),
+        Ok(false) => {}
+        Err(e) => error!("Could not refund a use of guest code '{name}'. {e}"),
+    }
+}
+
+// burns one use. checked again under the write lock so two quick submits can't both get the last use
+pub async fn record_use(name: &str, instruction: &LockInstruction) -> Result<bool, anyhow::Error> {
+    let now = Utc::now();
+    let recorded = update_codes(|codes| {
+        let code = codes
+            .iter_mut()
+            .find(|c| c.name == name)
+            .ok_or_else(|| anyhow!("no guest code '{name}'"))?;
+        if !code.is_usable_at(now) {
+            return Ok(false);
+        }
+        code.uses.push(GuestCodeUse {
+            at: now,
+            requested: instruction.kind().to_string(),
+        });
+        Ok(true)
+    })
+    .await?;
+    if recorded {
+        info!("Guest code '{name}' used to {}", instruction.kind());
+    }
+    Ok(recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeDelta, TimeZone};
+
+    use super::*;
+    use crate::{
+        auth::{Role, add_user},
+        lock::InstructionSource,
+        testing,
+    };
+
+    fn code(name: &str) -> GuestCode {
+        GuestCode {
+            name: name.to_string(),
+            code_hash: String::new(),
+            valid_from: Utc::now() - TimeDelta::hours(1),
+            valid_until: Utc::now() + TimeDelta::hours(1),
+            max_uses: None,
+            schedule: None,
+            unlock_only: false,
+            uses: Vec::new(),
+        }
+    }
+
+    fn new_code(name: &str, code: &str, valid_from: DateTime<Utc>) -> NewGuestCode {
+        NewGuestCode {
+            name: name.to_string(),
+            code: code.to_string(),
+            valid_from,
+            valid_until: valid_from + TimeDelta::hours(1),
+            max_uses: None,
+            schedule: None,
+            unlock_only: false,
+        }
+    }
+
+    fn unlock() -> LockInstruction {
+        LockInstruction::EnsureUnlocked(InstructionSource::Button)
+    }
+
+    #[test]
+    fn usable_only_inside_its_window() {
+        let code = code("window");
+        assert!(code.is_usable_at(Utc::now()));
+        assert!(!code.is_usable_at(code.valid_from - TimeDelta::seconds(1)));
+        assert!(!code.is_usable_at(code.valid_until));
+    }
+
+    #[test]
+    fn usable_until_the_uses_run_out() {
+        let mut code = code("uses");
+        code.max_uses = Some(2);
+        for _ in 0..2 {
+            assert!(code.is_usable_at(Utc::now()));
+            code.uses.push(GuestCodeUse {
+                at: Utc::now(),
+                requested: "unlock".to_string(),
+            });
+        }
+        assert!(!code.is_usable_at(Utc::now()));
+    }
+
+    #[test]
+    fn usable_only_on_schedule() {
+        let mut code = code("schedule");
+        code.valid_from = DateTime::<Utc>::MIN_UTC;
+        code.valid_until = DateTime::<Utc>::MAX_UTC;
+        code.schedule = Some(Schedule {
+            weekdays: vec![Weekday::Mon],
+            start_hour: 9,
+            end_hour: 17,
+        });
+        // 2024-05-06 was a monday
+        let at = |day, hour| {
+            let local = NaiveDate::from_ymd_opt(2024, 5, day)
+                .unwrap()
+                .and_hms_opt(hour, 30, 0)
+                .unwrap();
+            Local.from_local_datetime(&local).unwrap().to_utc()
+        };
+        assert!(code.is_usable_at(at(6, 9)));
+        assert!(code.is_usable_at(at(6, 16)));
+        assert!(!code.is_usable_at(at(6, 8)));
+        assert!(!code.is_usable_at(at(6, 17)));
+        assert!(!code.is_usable_at(at(7, 12)));
+    }
+
+    #[tokio::test]
+    async fn only_a_usable_code_is_found() {
+        testing::config();
+        create_code(new_code(
+            "find-now",
+            "111111",
+            Utc::now() - TimeDelta::minutes(1),
+        ))
+        .await
+        .unwrap();
+        create_code(new_code(
+            "find-later",
+            "111112",
+            Utc::now() + TimeDelta::days(1),
+        ))
+        .await
+        .unwrap();
+        let found = find_usable("111111").await.unwrap().unwrap();
+        assert_eq!(found.name, "find-now");
+        assert!(find_usable("111112").await.unwrap().is_none());
+        assert!(find_usable("111113").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn a_refund_hands_back_the_latest_use_of_its_kind() {
+        testing::config();
+        let mut once = new_code("refund", "222222", Utc::now() - TimeDelta::minutes(1));
+        once.max_uses = Some(1);
+        create_code(once).await.unwrap();
+
+        assert!(record_use("refund", &unlock()).await.unwrap());
+        assert!(!record_use("refund", &unlock()).await.unwrap());
+        assert!(find_usable("222222").await.unwrap().is_none());
+
+        // nothing to give back for a lock that was never asked for
+        refund_use("refund".to_string(), "lock").await;
+        assert!(find_usable("222222").await.unwrap().is_none());
+        refund_use("refund".to_string(), "unlock").await;
+        assert!(find_usable("222222").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn a_new_code_cant_match_any_other() {
+        testing::config();
+        create_code(new_code(
+            "expired",
+            "333333",
+            Utc::now() - TimeDelta::days(2),
+        ))
+        .await
+        .unwrap();
+        add_user("collides", "444444", Role::Resident)
+            .await
+            .unwrap();
+
+        let now = Utc::now();
+        // an expired code still matches first, so it would hide the new one
+        assert!(
+            create_code(new_code("over-expired", "333333", now))
+                .await
+                .is_err()
+        );
+        assert!(
+            create_code(new_code("over-user", "444444", now))
+                .await
+                .is_err()
+        );
+        assert!(create_code(new_code("fresh", "555555", now)).await.is_ok());
+    }
+}
@@ -1,40 +1,171 @@
 use std::{
-    cmp::min,
+    collections::VecDeque,
     env,
     error::Error,
     fmt,
     io::{Write, stdin, stdout},
-    sync::Arc,
-    time::Duration,
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use chrono::Utc;
-use more_asserts::assert_ge;
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::{
     sync::{
-        Mutex,
-        mpsc::{Receiver, Sender},
+        mpsc::{
+            Receiver, Sender, UnboundedReceiver, UnboundedSender, error::TrySendError,
+            unbounded_channel,
+        },
+        watch,
     },
-    time::sleep,
+    task::{JoinError, JoinHandle},
 };
+use tracing::{Instrument, Span, debug, error, info, info_span, trace, warn};
 
-use crate::rpi::{LED, LEDState, MotorDirection, StepMotor};
+use crate::{
+    audit,
+    auth::Principal,
+    clock::SharedClock,
+    config::{WebhookEvent, config},
+    events::{self, LockEventKind},
+    guest, metrics, motion,
+    motor::{MotorDriver, MotorFault, MotorReport},
+    rpi::{
+        self, EndStop, EndStops, GpioProvider, LED, LEDState, MotorDirection, QuadratureEncoder,
+        StepMotor,
+    },
+    state_file, webhooks,
+};
 
-pub static STATE: Lazy<Mutex<LockState>> = Lazy::new(|| Mutex::new(LockState::from_env()));
-static LOCK_IN_USE: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+// only the lock handler writes this. everyone else reads through current_state or subscribes
+pub static STATE: Lazy<watch::Sender<LockState>> =
+    Lazy::new(|| watch::Sender::new(LockState::load()));
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum LockState {
     Unlocked,
     Locked,
+    Locking,
+    Unlocking,
+    // the move didn't finish, so the bolt is somewhere in between
+    Jammed {
+        during: LockAction,
+        reason: FaultReason,
+    },
+    // nothing to go on about where the bolt is
+    Unknown {
+        reason: FaultReason,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultReason {
+    MotorFault { detail: String }, // the motor thread died partway through
+    Interrupted { during: LockAction }, // doorknob went down mid-move
+    PositionMismatch { detail: String }, // the end-stop or encoder disagrees with the move
+    MovedByHand { detail: String }, // the bolt moved while idle, to nowhere the end-stops can see
+}
+
+impl fmt::Display for FaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultReason::MotorFault { detail } => write!(f, "motor fault: {detail}"),
+            FaultReason::Interrupted { during } => {
+                write!(f, "doorknob stopped partway through {}", during.moving())
+            }
+            FaultReason::PositionMismatch { detail } => write!(f, "position mismatch: {detail}"),
+            FaultReason::MovedByHand { detail } => write!(f, "moved by hand: {detail}"),
+        }
+    }
+}
+
+// everything that moves STATE. anything not in LockState::next's table is a bug
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transition {
+    Start(LockAction),
+    Finish,
+    Fail(FaultReason),
+    Mark(LockState), // an operator or the end-stops saying where the bolt is, Locked or Unlocked
+    Home,            // run to the locked end-stop, from wherever
+    Lost(FaultReason), // the idle check saw the bolt move off
+}
+
+// a fault state turns instructions away until someone marks the bolt locked or unlocked
+#[derive(Debug)]
+pub struct LockFaulted {
+    pub state: LockState,
+}
+
+impl fmt::Display for LockFaulted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.state {
+            LockState::Jammed { during, reason } => write!(
+                f,
+                "lock jammed while {} ({reason}). check the bolt, then mark it locked or unlocked or home it",
+                during.moving()
+            ),
+            LockState::Unknown { reason } => write!(
+                f,
+                "bolt position unknown ({reason}). check the bolt, then mark it locked or unlocked or home it"
+            ),
+            state => write!(f, "lock is {}", state.name()),
+        }
+    }
+}
+
+impl Error for LockFaulted {}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum InstructionSource {
     Button,
-    Api,
+    Api {
+        principal: Principal,
+        client_ip: IpAddr,
+    },
     AutoSensor,
+    AutoLock,
+    ControlSocket {
+        uid: Option<u32>,
+    }, // the local socket. its file mode is the auth
+    Mqtt, // a command on mqtt.base_topic/lock/set. the broker's ACLs are the auth
+}
+
+impl InstructionSource {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InstructionSource::Button => "button",
+            InstructionSource::Api { .. } => "api",
+            InstructionSource::AutoSensor => "auto_sensor",
+            InstructionSource::AutoLock => "auto_lock",
+            InstructionSource::ControlSocket { .. } => "control_socket",
+            InstructionSource::Mqtt => "mqtt",
+        }
+    }
+
+    // who to blame, as far as we know
+    pub fn user(&self) -> Option<String> {
+        match self {
+            InstructionSource::Api { principal, .. } => Some(principal.name().to_string()),
+            InstructionSource::ControlSocket { uid: Some(uid) } => Some(format!("uid:{uid}")),
+            _ => None,
+        }
+    }
+
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match self {
+            InstructionSource::Api { client_ip, .. } => Some(*client_ip),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -42,14 +173,75 @@ pub enum LockInstruction {
     EnsureLocked(InstructionSource),
     EnsureUnlocked(InstructionSource),
     Reverse(InstructionSource),
+    HoldOpen(InstructionSource), // unlock and skip the autolock this time
+    // admin only. says where the bolt is without moving it, which is what clears a fault
+    MarkLocked(InstructionSource),
+    MarkUnlocked(InstructionSource),
+    // admin only. runs to the locked end-stop from wherever the bolt is, the other way to clear a fault
+    Home(InstructionSource),
 }
 
-#[derive(Debug, Clone)]
+impl LockInstruction {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LockInstruction::EnsureLocked(_) => "lock",
+            LockInstruction::EnsureUnlocked(_) => "unlock",
+            LockInstruction::Reverse(_) => "toggle",
+            LockInstruction::HoldOpen(_) => "hold_open",
+            LockInstruction::MarkLocked(_) => "mark_locked",
+            LockInstruction::MarkUnlocked(_) => "mark_unlocked",
+            LockInstruction::Home(_) => "home",
+        }
+    }
+
+    pub fn source(&self) -> &InstructionSource {
+        match self {
+            LockInstruction::EnsureLocked(source)
+            | LockInstruction::EnsureUnlocked(source)
+            | LockInstruction::Reverse(source)
+            | LockInstruction::HoldOpen(source)
+            | LockInstruction::MarkLocked(source)
+            | LockInstruction::MarkUnlocked(source)
+            | LockInstruction::Home(source) => source,
+        }
+    }
+
+    // the ones that clear faults. they take an admin and always run as themselves
+    pub fn needs_admin(&self) -> bool {
+        matches!(
+            self,
+            LockInstruction::MarkLocked(_)
+                | LockInstruction::MarkUnlocked(_)
+                | LockInstruction::Home(_)
+        )
+    }
+
+    // what a mark instruction says the bolt is
+    pub fn marks(&self) -> Option<LockState> {
+        match self {
+            LockInstruction::MarkLocked(_) => Some(LockState::Locked),
+            LockInstruction::MarkUnlocked(_) => Some(LockState::Unlocked),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum LockAction {
     Lock,
     Unlock,
 }
 
+impl LockAction {
+    pub fn moving(&self) -> &'static str {
+        match self {
+            LockAction::Lock => "locking",
+            LockAction::Unlock => "unlocking",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LockInUse;
 
@@ -66,25 +258,129 @@ impl fmt::Display for LockInUse {
 impl Error for LockInUse {}
 
 impl LockState {
-    pub fn to_action(&self, instruction: LockInstruction) -> Option<LockAction> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LockState::Unlocked => "unlocked",
+            LockState::Locked => "locked",
+            LockState::Locking => "locking",
+            LockState::Unlocking => "unlocking",
+            LockState::Jammed { .. } => "jammed",
+            LockState::Unknown { .. } => "unknown",
+        }
+    }
+
+    pub fn is_fault(&self) -> bool {
+        matches!(self, LockState::Jammed { .. } | LockState::Unknown { .. })
+    }
+
+    // mark instructions never move the motor, see Transition::Mark
+    pub fn to_action(
+        &self,
+        instruction: LockInstruction,
+    ) -> Result<Option<LockAction>, LockFaulted> {
         match (self, instruction) {
+            (_, LockInstruction::MarkLocked(_) | LockInstruction::MarkUnlocked(_)) => Ok(None),
+            (
+                LockState::Locked
+                | LockState::Unlocked
+                | LockState::Jammed { .. }
+                | LockState::Unknown { .. },
+                LockInstruction::Home(_),
+            ) => Ok(Some(LockAction::Lock)),
             (
                 LockState::Unlocked,
                 LockInstruction::EnsureLocked(_) | LockInstruction::Reverse(_),
-            ) => Some(LockAction::Lock),
+            ) => Ok(Some(LockAction::Lock)),
             (
                 LockState::Locked,
-                LockInstruction::EnsureUnlocked(_) | LockInstruction::Reverse(_),
-            ) => Some(LockAction::Unlock),
+                LockInstruction::EnsureUnlocked(_)
+                | LockInstruction::Reverse(_)
+                | LockInstruction::HoldOpen(_),
+            ) => Ok(Some(LockAction::Unlock)),
+            (LockState::Locked | LockState::Unlocked, _) => Ok(None),
+            (state, _) => Err(LockFaulted {
+                state: state.clone(),
+            }),
+        }
+    }
+
+    pub fn next(&self, transition: Transition) -> Option<LockState> {
+        match (self, transition) {
+            (LockState::Unlocked, Transition::Start(LockAction::Lock)) => Some(LockState::Locking),
+            (LockState::Locked, Transition::Start(LockAction::Unlock)) => {
+                Some(LockState::Unlocking)
+            }
+            (LockState::Locking, Transition::Finish) => Some(LockState::Locked),
+            (LockState::Unlocking, Transition::Finish) => Some(LockState::Unlocked),
+            (LockState::Locking, Transition::Fail(reason)) => Some(LockState::Jammed {
+                during: LockAction::Lock,
+                reason,
+            }),
+            (LockState::Unlocking, Transition::Fail(reason)) => Some(LockState::Jammed {
+                during: LockAction::Unlock,
+                reason,
+            }),
+            // from a settled state too, for when someone turned the bolt by hand
+            (
+                LockState::Locked
+                | LockState::Unlocked
+                | LockState::Jammed { .. }
+                | LockState::Unknown { .. },
+                Transition::Mark(marked @ (LockState::Locked | LockState::Unlocked)),
+            ) => Some(marked),
+            (
+                LockState::Locked
+                | LockState::Unlocked
+                | LockState::Jammed { .. }
+                | LockState::Unknown { .. },
+                Transition::Home,
+            ) => Some(LockState::Locking),
+            (LockState::Locked | LockState::Unlocked, Transition::Lost(reason)) => {
+                Some(LockState::Unknown { reason })
+            }
             _ => None,
         }
     }
 
-    pub fn set_reverse(&mut self) {
-        match self {
-            LockState::Unlocked => *self = LockState::Locked,
-            LockState::Locked => *self = LockState::Unlocked,
+    // the state file is the source of truth when we have one we can believe.
+    // env and the stdin prompt are only for first boot. a crash mid-move comes back Unknown
+    pub fn load() -> Self {
+        match state_file::load() {
+            Ok(Some(record)) if record.is_trustworthy() => {
+                info!(
+                    "Restored lock state {:?} from {:?}, recorded at {}",
+                    record.state,
+                    state_file::state_file_path(),
+                    record.recorded_at
+                );
+                return record.state;
+            }
+            Ok(Some(record)) => {
+                let during = record
+                    .in_motion
+                    .expect("untrustworthy record is always in motion");
+                warn!(
+                    "Last run crashed while moving {:?} -> {:?} at {}. Bolt position is unknown until it's marked",
+                    record.state, during, record.recorded_at
+                );
+                let state = LockState::Unknown {
+                    reason: FaultReason::Interrupted { during },
+                };
+                // so it stays unknown across restarts until someone marks it
+                if let Err(e) = state_file::record_settled(&state) {
+                    error!("Could not write lock state file. {e}");
+                }
+                return state;
+            }
+            Ok(None) => info!("No lock state file found"),
+            Err(e) => warn!("Lock state file unreadable, ignoring it. {e}"),
         }
+
+        let state = Self::from_env();
+        if let Err(e) = state_file::record_settled(&state) {
+            error!("Could not write lock state file. {e}");
+        }
+        state
     }
 
     pub fn from_env() -> Self {
@@ -125,125 +421,1266 @@ impl From<LockAction> for MotorDirection {
     }
 }
 
-const READY_LED_PIN: u8 = 17;
-const IN_USE_LED_PIN: u8 = 22;
-
 pub struct Lock {
     ready_led: LED,
     in_use_led: LED,
-    motor: StepMotor,
+    motor: MotorDriver,
+    lock_delays: Vec<Duration>, // worked out once from config().motion
+    unlock_delays: Vec<Duration>,
+    clock: SharedClock,
+    end_stops: EndStops,
+    encoder: Option<QuadratureEncoder>,
+    settled_count: i64, // the encoder when the bolt last settled somewhere we believe
+}
+
+#[derive(Debug)]
+pub enum MoveError {
+    Motor(MotorFault),
+    Mismatch(String), // the move ran, but the bolt isn't where it should be
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::Motor(e) => write!(f, "{e}"),
+            MoveError::Mismatch(detail) => {
+                write!(f, "bolt is not where the move left it. {detail}")
+            }
+        }
+    }
+}
+
+impl Error for MoveError {}
+
+impl From<MotorFault> for MoveError {
+    fn from(e: MotorFault) -> Self {
+        MoveError::Motor(e)
+    }
+}
+
+// what an idle check found out about the bolt
+#[derive(Debug)]
+enum BoltCheck {
+    Moved(LockState), // an end-stop has it at the other end now
+    Lost(String),     // it isn't where we left it, and nothing says where it went
 }
 
 impl Lock {
-    pub fn new() -> Self {
-        Self {
-            ready_led: LED::new(READY_LED_PIN).with_state(LEDState::On),
-            in_use_led: LED::new(IN_USE_LED_PIN).with_state(LEDState::Off),
-            motor: StepMotor::new(),
+    pub fn new(gpio: &dyn GpioProvider, clock: SharedClock) -> Result<Self, anyhow::Error> {
+        let pins = &config().gpio;
+        let motion = &config().motion;
+        let end_stop = |pin: Option<u8>| pin.map(|pin| EndStop::from_gpio(gpio, pin)).transpose();
+        let end_stops = EndStops {
+            counter_clockwise: end_stop(pins.locked_stop_pin)?, // see From<LockAction> for MotorDirection
+            clockwise: end_stop(pins.unlocked_stop_pin)?,
+        };
+        let encoder = pins
+            .encoder_pins()
+            .map(|encoder_pins| QuadratureEncoder::from_gpio(gpio, encoder_pins))
+            .transpose()?;
+        Ok(Self {
+            ready_led: LED::from_gpio(gpio, pins.ready_led_pin)?.with_state(LEDState::On),
+            in_use_led: LED::from_gpio(gpio, pins.in_use_led_pin)?.with_state(LEDState::Off),
+            motor: MotorDriver::spawn(
+                StepMotor::from_gpio(gpio, pins, Arc::clone(&clock))?,
+                end_stops.clone(),
+            )?,
+            lock_delays: motion::delays(&motion::step_table(&motion.lock, motion.steps)),
+            unlock_delays: motion::delays(&motion::step_table(&motion.unlock, motion.steps)),
+            clock,
+            end_stops,
+            encoder,
+            settled_count: 0,
+        })
+    }
+
+    pub fn has_feedback(&self) -> bool {
+        self.end_stops.clockwise.is_some()
+            || self.end_stops.counter_clockwise.is_some()
+            || self.encoder.is_some()
+    }
+
+    pub fn can_home(&self) -> bool {
+        self.end_stops.towards(&LockAction::Lock.into()).is_some()
+    }
+
+    // wherever the bolt is now, we believe it
+    fn settle(&mut self) {
+        if let Some(encoder) = &self.encoder {
+            self.settled_count = encoder.count();
         }
     }
 
-    pub async fn act(&mut self, action: &LockAction) {
-        println!("Currently taking {:?} action", action);
+    // the stepping itself happens on the motor thread, this only waits on its reports
+    pub async fn act(&mut self, action: &LockAction) -> Result<(), MoveError> {
+        info!("Currently taking {:?} action", action);
+        let started = self.clock.now();
         self.ready_led.set_state(LEDState::Off);
         self.in_use_led.set_state(LEDState::On);
-        self.motor.activate();
-        self.motor.set_direction(action.clone().into());
-
-        let steps: u64 = 60;
-        let target_delay_ms: u64 = 30;
-        let base_delay_ms: u64 = 40;
-        let acceleration_factor: u64 = 1;
-        for step in 0..steps {
-            let delay = get_delay(
-                step,
-                steps,
-                acceleration_factor,
-                target_delay_ms,
-                base_delay_ms,
-            );
 
-            println!("delay {}", delay.as_millis());
-            self.motor.take_step(delay);
+        let direction = MotorDirection::from(action.clone());
+        let mut delays = match action {
+            LockAction::Lock => self.lock_delays.clone(),
+            LockAction::Unlock => self.unlock_delays.clone(),
+        };
+        // with an end-stop to stop on, a dropped step or a bolt starting short of the far end is no problem
+        if self.end_stops.towards(&direction).is_some()
+            && let Some(&last) = delays.last()
+        {
+            let overtravel = config().feedback.overtravel_steps as usize;
+            delays.extend(std::iter::repeat_n(last, overtravel));
         }
-        self.motor.deactivate();
+        let before = self.encoder.as_ref().map(QuadratureEncoder::count);
+        let mut reports = self.motor.start(direction, delays)?;
+        let (steps, end_stop) = loop {
+            match reports.recv().await.ok_or(MotorFault)? {
+                MotorReport::Stepped { step, steps } => trace!(step, steps, "step"),
+                MotorReport::Finished { steps, end_stop } => break (steps, end_stop),
+            }
+        };
         self.ready_led.set_state(LEDState::On);
         self.in_use_led.set_state(LEDState::Off);
-        println!("done with {:?} action", action);
-    }
-}
-
-fn get_delay(
-    step: u64,
-    steps: u64,
-    accel_factor: u64,
-    target_delay_ms: u64,
-    base_delay_ms: u64,
-) -> Duration {
-    assert_ge!(base_delay_ms, target_delay_ms);
-    let offset = min((steps - 1) - step, step) * accel_factor;
-    let acceleration_space = base_delay_ms - target_delay_ms;
-    if offset >= acceleration_space {
-        return Duration::from_millis(target_delay_ms);
-    }
-    Duration::from_millis(base_delay_ms - offset)
-}
-
-pub async fn handle_lock_instruction(mut rx: Receiver<LockInstruction>) {
-    let mut lock = Lock::new();
-    loop {
-        // main poll, lets us see if there's a message ready without actually consuming
-        if rx.is_empty() {
-            sleep(Duration::from_millis(100)).await;
-            continue;
-        }
-
-        let _lock_guard = LOCK_IN_USE.lock().await;
-
-        match rx.recv().await {
-            Some(instruction) => {
-                println!("Received lock instruction {:?}", instruction);
-                let mut state = STATE.lock().await;
-                if let Some(action) = state.to_action(instruction) {
-                    lock.act(&action).await;
-                    state.set_reverse();
-                } else {
-                    println!("No change to lock state needed")
+        metrics::record_motor_run(action, self.clock.now() - started);
+        info!(steps, ?end_stop, "done with {:?} action", action);
+        self.verify(action, steps, end_stop, before)
+    }
+
+    // the end-stop that way has the final say, then the encoder. with neither we go by the step count
+    fn verify(
+        &mut self,
+        action: &LockAction,
+        steps: usize,
+        end_stop: Option<bool>,
+        before: Option<i64>,
+    ) -> Result<(), MoveError> {
+        let feedback = &config().feedback;
+        let (end, expected) = match action {
+            LockAction::Lock => ("locked", -feedback.encoder_counts),
+            LockAction::Unlock => ("unlocked", feedback.encoder_counts),
+        };
+        let mismatch = match (end_stop, &self.encoder, before) {
+            (Some(true), _, _) => None,
+            (Some(false), _, _) => Some(format!("{end} end-stop still open after {steps} steps")),
+            (None, Some(encoder), Some(before)) => {
+                let moved = encoder.count() - before;
+                ((moved - expected).abs() > feedback.encoder_tolerance)
+                    .then(|| format!("encoder moved {moved} counts, expected {expected}"))
+            }
+            _ => None,
+        };
+        match mismatch {
+            Some(detail) => Err(MoveError::Mismatch(detail)),
+            None => {
+                self.settle();
+                Ok(())
+            }
+        }
+    }
+
+    // only means anything while Locked or Unlocked, and the actor only asks then
+    fn check_idle(&self, state: &LockState) -> Option<BoltCheck> {
+        let at_locked = self
+            .end_stops
+            .counter_clockwise
+            .as_ref()
+            .map(EndStop::is_closed);
+        let at_unlocked = self.end_stops.clockwise.as_ref().map(EndStop::is_closed);
+        let check = match (state, at_locked, at_unlocked) {
+            (_, Some(true), Some(true)) => {
+                Some(BoltCheck::Lost("both end-stops read closed".to_string()))
+            }
+            (LockState::Unlocked, Some(true), _) => Some(BoltCheck::Moved(LockState::Locked)),
+            (LockState::Locked, _, Some(true)) => Some(BoltCheck::Moved(LockState::Unlocked)),
+            (LockState::Locked, Some(false), _) => {
+                Some(BoltCheck::Lost("the locked end-stop opened".to_string()))
+            }
+            (LockState::Unlocked, _, Some(false)) => {
+                Some(BoltCheck::Lost("the unlocked end-stop opened".to_string()))
+            }
+            _ => None,
+        };
+        if check.is_some() {
+            return check;
+        }
+        let drift = self.encoder.as_ref()?.count() - self.settled_count;
+        (drift.abs() > config().feedback.encoder_tolerance)
+            .then(|| BoltCheck::Lost(format!("the encoder moved {drift} counts")))
+    }
+}
+
+pub type InstructionId = u64;
+
+static NEXT_INSTRUCTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// what to do with an instruction that shows up while the lock is busy
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuePolicy {
+    Reject,   // LockInUse, like it always was
+    Queue,    // run everything in order
+    Coalesce, // only the end state of everything pending matters
+}
+
+pub const QUEUE_CAPACITY: usize = 16;
+
+// everything the actor tells a sender about its instruction, in order.
+// Queued or Rejected always comes first, then exactly one of the rest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum InstructionStatus {
+    Queued { position: usize },
+    Rejected,
+    Executed { action: LockAction },
+    NoOp,
+    Coalesced { into: InstructionId },
+    Failed { reason: String },
+    Refused { reason: String },  // the lock is faulted, see LockFaulted
+    Marked { state: LockState }, // a mark instruction that changed what we think the bolt is
+}
+
+impl InstructionStatus {
+    pub fn is_final(&self) -> bool {
+        !matches!(self, InstructionStatus::Queued { .. })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstructionStatus::Queued { .. } => "queued",
+            InstructionStatus::Rejected => "rejected",
+            InstructionStatus::Executed { .. } => "executed",
+            InstructionStatus::NoOp => "no_op",
+            InstructionStatus::Coalesced { .. } => "coalesced",
+            InstructionStatus::Failed { .. } => "failed",
+            InstructionStatus::Refused { .. } => "refused",
+            InstructionStatus::Marked { .. } => "marked",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueuedInstruction {
+    pub id: InstructionId,
+    pub instruction: LockInstruction,
+    replies: UnboundedSender<InstructionStatus>,
+    received: Option<(SharedClock, Instant)>, // on the actor's clock, None until it gets there
+    span: Span, // everything logged about this instruction, from queueing to the bolt settling, lands in here
+}
+
+impl QueuedInstruction {
+    fn new(
+        id: InstructionId,
+        instruction: LockInstruction,
+        replies: UnboundedSender<InstructionStatus>,
+    ) -> Self {
+        let source = instruction.source();
+        let span = info_span!(
+            "instruction",
+            id,
+            kind = instruction.kind(),
+            source = source.kind(),
+            user = source.user(),
+            client_ip = source.client_ip().map(tracing::field::display),
+        );
+        Self {
+            id,
+            instruction,
+            replies,
+            received: None,
+            span,
+        }
+    }
+
+    fn reply(&self, status: InstructionStatus) {
+        if status.is_final() {
+            let _entered = self.span.enter();
+            info!(?status, "Instruction finished");
+            let took = self
+                .received
+                .as_ref()
+                .map_or(Duration::ZERO, |(clock, at)| clock.now() - *at);
+            audit::record_instruction(self.id, &self.instruction, &status, took);
+            metrics::record_instruction(&self.instruction, &status);
+            self.refund_guest_use(&status);
+        }
+        // the caller is allowed to stop caring
+        let _ = self.replies.send(status);
+    }
+
+    // authorize burns a guest code use up front. if the lock never took the instruction, it comes back
+    fn refund_guest_use(&self, status: &InstructionStatus) {
+        let turned_away = matches!(
+            status,
+            InstructionStatus::Rejected | InstructionStatus::Refused { .. }
+        );
+        if let InstructionSource::Api {
+            principal: Principal::Guest { name, .. },
+            ..
+        } = self.instruction.source()
+            && turned_away
+        {
+            tokio::spawn(guest::refund_use(name.clone(), self.instruction.kind()));
+        }
+    }
+}
+
+// handed back to whoever sent the instruction, once the actor has accepted it
+#[derive(Debug)]
+pub struct InstructionTicket {
+    pub id: InstructionId,
+    pub position: usize,
+    replies: UnboundedReceiver<InstructionStatus>,
+}
+
+impl InstructionTicket {
+    pub async fn completion(mut self) -> InstructionStatus {
+        while let Some(status) = self.replies.recv().await {
+            if status.is_final() {
+                return status;
+            }
+        }
+        InstructionStatus::Failed {
+            reason: "lock handler went away".to_string(),
+        }
+    }
+}
+
+pub fn current_state() -> LockState {
+    STATE.borrow().clone()
+}
+
+// the state we'd end up in after running instruction from current
+fn state_after(current: &LockState, instruction: &LockInstruction) -> LockState {
+    match current.to_action(instruction.clone()) {
+        Ok(Some(LockAction::Lock)) => LockState::Locked,
+        Ok(Some(LockAction::Unlock)) => LockState::Unlocked,
+        _ => current.clone(),
+    }
+}
+
+// the move runs as its own task so the handler keeps answering senders while the bolt moves
+type Motion = JoinHandle<(Lock, Result<(), MoveError>)>;
+
+// the one owner of the lock hardware and of STATE. everybody else sends it instructions
+struct LockActor {
+    lock: Option<Lock>, // None while it's off moving the bolt
+    pending: VecDeque<QueuedInstruction>,
+    in_flight: Option<(QueuedInstruction, LockAction)>,
+    autolock_at: Option<Instant>,
+    check_at: Option<Instant>, // the next idle look at the end-stops and encoder, None without any
+    clock: SharedClock,        // the lock's own, which it takes along while moving
+    policy: QueuePolicy,
+}
+
+impl LockActor {
+    fn new(lock: Lock) -> Self {
+        let clock = Arc::clone(&lock.clock);
+        Self {
+            check_at: lock.has_feedback().then(|| clock.now()),
+            clock,
+            lock: Some(lock),
+            pending: VecDeque::new(),
+            in_flight: None,
+            autolock_at: None,
+            policy: config().lock.queue_policy,
+        }
+    }
+
+    // every unlock restarts the countdown, a lock, a hold-open or a fault stops it
+    fn update_autolock(&mut self, instruction: &LockInstruction, state: &LockState) {
+        let schedule = match (instruction, state) {
+            (LockInstruction::HoldOpen(_), _) => None,
+            (_, LockState::Unlocked) => config().lock.autolock_delay(),
+            _ => None,
+        };
+        match schedule {
+            Some(delay) => {
+                self.autolock_at = Some(self.clock.now() + delay);
+                info!("Autolock in {}s", delay.as_secs());
+                events::publish(LockEventKind::AutolockScheduled {
+                    fires_at: Utc::now() + delay,
+                });
+            }
+            None => {
+                if self.autolock_at.take().is_some() {
+                    info!("Autolock cancelled by {}", instruction.kind());
+                    events::publish(LockEventKind::AutolockCancelled);
                 }
             }
-            None => println!(
-                "Receiving None after passing empty check should never happen. Channel is fucked"
-            ),
         }
+    }
+
+    fn autolock_fired(&mut self) {
+        self.autolock_at = None;
+        let id = NEXT_INSTRUCTION_ID.fetch_add(1, Ordering::Relaxed);
+        info!("Autolock timer is up, sending #{id}");
+        metrics::record_autolock("timer");
+        // nobody waits on our own instruction, the replies go nowhere
+        let (replies, _) = unbounded_channel();
+        self.accept(QueuedInstruction::new(
+            id,
+            LockInstruction::EnsureLocked(InstructionSource::AutoLock),
+            replies,
+        ));
+    }
+
+    fn is_busy(&self) -> bool {
+        self.in_flight.is_some() || !self.pending.is_empty()
+    }
+
+    fn accept(&mut self, mut queued: QueuedInstruction) {
+        queued.received = Some((Arc::clone(&self.clock), self.clock.now()));
+        let _entered = queued.span.clone().entered();
+        if self.policy == QueuePolicy::Reject && self.is_busy() {
+            info!("Lock busy, rejecting");
+            queued.reply(InstructionStatus::Rejected);
+            return;
+        }
+        info!("Received lock instruction");
+        events::publish(LockEventKind::InstructionReceived {
+            id: queued.id,
+            instruction: queued.instruction.kind(),
+            source: queued.instruction.source().clone(),
+        });
+        let position = self.pending.len() + usize::from(self.in_flight.is_some());
+        queued.reply(InstructionStatus::Queued { position });
+        self.pending.push_back(queued);
+    }
+
+    // folds everything pending into one instruction that lands on the same final state.
+    // the last one wins, everyone before it is told what they were folded into
+    fn coalesce_pending(&mut self, current: &LockState) -> Option<QueuedInstruction> {
+        let mut latest = self.pending.pop_front()?;
+        // marks and homing run as themselves, so nothing folds into one or across one
+        if latest.instruction.needs_admin() {
+            return Some(latest);
+        }
+        let mut target = state_after(current, &latest.instruction);
+        while let Some(next) = self.pending.pop_front() {
+            if next.instruction.needs_admin() {
+                self.pending.push_front(next);
+                break;
+            }
+            target = state_after(&target, &next.instruction);
+            let superseded = std::mem::replace(&mut latest, next);
+            superseded.reply(InstructionStatus::Coalesced { into: latest.id });
+        }
+        let source = latest.instruction.source().clone();
+        latest.instruction = match (target, &latest.instruction) {
+            (LockState::Locked, _) => LockInstruction::EnsureLocked(source),
+            (LockState::Unlocked, LockInstruction::HoldOpen(_)) => {
+                LockInstruction::HoldOpen(source)
+            }
+            (LockState::Unlocked, _) => LockInstruction::EnsureUnlocked(source),
+            // faulted, so it gets refused whatever it is
+            (_, instruction) => instruction.clone(),
+        };
+        Some(latest)
+    }
+
+    // starts the next pending instruction that actually needs the motor.
+    // no-ops are answered on the spot
+    fn start_next(&mut self) -> Option<Motion> {
+        while self.in_flight.is_none() {
+            let current = current_state();
+            let next = match self.policy {
+                QueuePolicy::Coalesce => self.coalesce_pending(&current),
+                _ => self.pending.pop_front(),
+            }?;
+            let _entered = next.span.clone().entered();
+            if let Some(marked) = next.instruction.marks() {
+                self.mark(next, &current, marked);
+                continue;
+            }
+            let action = match current.to_action(next.instruction.clone()) {
+                Ok(Some(action)) => action,
+                Ok(None) => {
+                    debug!("No change to lock state needed");
+                    self.update_autolock(&next.instruction, &current);
+                    next.reply(InstructionStatus::NoOp);
+                    continue;
+                }
+                Err(faulted) => {
+                    warn!("Refusing instruction. {faulted}");
+                    next.reply(InstructionStatus::Refused {
+                        reason: faulted.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let homing = matches!(next.instruction, LockInstruction::Home(_));
+            if homing && !self.lock.as_ref().is_some_and(Lock::can_home) {
+                warn!("Refusing to home without a locked end-stop");
+                next.reply(InstructionStatus::Refused {
+                    reason: "homing needs gpio.locked_stop_pin wired".to_string(),
+                });
+                continue;
+            }
+
+            self.transition(match homing {
+                true => Transition::Home,
+                false => Transition::Start(action.clone()),
+            });
+            events::publish(LockEventKind::MotionStarted {
+                action: action.clone(),
+            });
+            let mut lock = self
+                .lock
+                .take()
+                .expect("lock is home when nothing is in flight");
+            let motion_action = action.clone();
+            let span = next.span.clone();
+            self.in_flight = Some((next, action));
+            return Some(tokio::spawn(
+                async move {
+                    let moved = lock.act(&motion_action).await;
+                    (lock, moved)
+                }
+                .instrument(span),
+            ));
+        }
+        None
+    }
+
+    // the only place STATE changes. it's journaled before anyone hears about it
+    fn transition(&self, transition: Transition) -> LockState {
+        let current = current_state();
+        let Some(state) = current.next(transition.clone()) else {
+            error!(
+                "No transition from {:?} on {:?}, state stays put",
+                current, transition
+            );
+            return current;
+        };
+        let journaled = match &transition {
+            // the settled state we left, so a crash from here on reads as interrupted
+            Transition::Start(action) => state_file::record_motion(&current, action),
+            Transition::Home => state_file::record_motion(&current, &LockAction::Lock),
+            _ => state_file::record_settled(&state),
+        };
+        if let Err(e) = journaled {
+            error!("Could not journal lock state {:?}. {e}", state);
+        }
+        STATE.send_replace(state.clone());
+        events::publish(LockEventKind::StateChanged {
+            state: state.clone(),
+        });
+        state
+    }
+
+    fn mark(&mut self, queued: QueuedInstruction, current: &LockState, marked: LockState) {
+        if *current == marked {
+            debug!("Lock is already {}", marked.name());
+            queued.reply(InstructionStatus::NoOp);
+            return;
+        }
+        let state = self.transition(Transition::Mark(marked));
+        warn!(was = current.name(), "Lock marked {} by hand", state.name());
+        if let Some(lock) = &mut self.lock {
+            lock.settle();
+        }
+        self.update_autolock(&queued.instruction, &state);
+        queued.reply(InstructionStatus::Marked { state });
+    }
+
+    fn finish(&mut self, joined: Result<(Lock, Result<(), MoveError>), JoinError>) {
+        let Some((queued, action)) = self.in_flight.take() else {
+            return;
+        };
+        let _entered = queued.span.clone().entered();
+        let (lock, fault) = match joined {
+            Ok((lock, Ok(()))) => (Some(lock), None),
+            // the motor's fine, the bolt just isn't where it should be
+            Ok((lock, Err(MoveError::Mismatch(detail)))) => {
+                (Some(lock), Some(FaultReason::PositionMismatch { detail }))
+            }
+            // dropping it joins the dead motor thread and hands back the led pins too
+            Ok((lock, Err(MoveError::Motor(e)))) => {
+                drop(lock);
+                let detail = e.to_string();
+                (None, Some(FaultReason::MotorFault { detail }))
+            }
+            Err(e) => {
+                let detail = e.to_string();
+                (None, Some(FaultReason::MotorFault { detail }))
+            }
+        };
+        self.lock = lock;
+        if let Some(reason) = fault {
+            // jammed until someone goes and looks, restart or not
+            error!("Move failed. {reason}");
+            webhooks::raise(
+                WebhookEvent::MotorFault,
+                json!({
+                    "instruction_id": queued.id,
+                    "action": action,
+                    "reason": reason.to_string(),
+                }),
+            );
+            let failed = reason.to_string();
+            let state = self.transition(Transition::Fail(reason));
+            self.update_autolock(&queued.instruction, &state);
+            if self.lock.is_none() {
+                // its pins went with it, so they're free to take again
+                match Lock::new(rpi::gpio(), Arc::clone(&self.clock)) {
+                    Ok(lock) => self.lock = Some(lock),
+                    Err(e) => error!(
+                        "Could not take the lock's pins back, it won't move again until a restart. {e}"
+                    ),
+                }
+            }
+            queued.reply(InstructionStatus::Failed { reason: failed });
+            return;
+        }
+        events::publish(LockEventKind::MotionFinished {
+            action: action.clone(),
+        });
+        let state = self.transition(Transition::Finish);
+        let source = queued.instruction.source();
+        let event = match state {
+            LockState::Locked => Some(WebhookEvent::Locked),
+            LockState::Unlocked => Some(WebhookEvent::Unlocked),
+            _ => None,
+        };
+        if let Some(event) = event {
+            webhooks::raise(
+                event,
+                json!({
+                    "instruction_id": queued.id,
+                    "instruction": queued.instruction.kind(),
+                    "source": source.kind(),
+                    "user": source.user(),
+                    "client_ip": source.client_ip(),
+                }),
+            );
+        }
+        self.update_autolock(&queued.instruction, &state);
+        queued.reply(InstructionStatus::Executed { action });
+        debug!("Lock use completed, ready for the next instruction")
+    }
 
-        println!("Lock use completed. Dropping mutex guard and channel is open for business again")
+    // the bolt can move without us, by hand or with a slipping motor. only looked at while it's settled
+    fn check_position(&mut self) {
+        self.check_at =
+            Some(self.clock.now() + Duration::from_millis(config().feedback.check_interval_ms));
+        let current = current_state();
+        if self.in_flight.is_some() || !matches!(current, LockState::Locked | LockState::Unlocked) {
+            return;
+        }
+        let Some(check) = self
+            .lock
+            .as_ref()
+            .and_then(|lock| lock.check_idle(&current))
+        else {
+            return;
+        };
+        let state = match check {
+            BoltCheck::Moved(moved) => {
+                warn!(
+                    "End-stops have the bolt {} now, it was turned by hand",
+                    moved.name()
+                );
+                self.transition(Transition::Mark(moved))
+            }
+            BoltCheck::Lost(detail) => {
+                warn!(
+                    "Bolt moved while idle, {detail}. Position unknown until it's marked or homed"
+                );
+                self.transition(Transition::Lost(FaultReason::MovedByHand { detail }))
+            }
+        };
+        if let Some(lock) = &mut self.lock {
+            lock.settle();
+        }
+        if state != LockState::Unlocked && self.autolock_at.take().is_some() {
+            info!("Autolock cancelled, the bolt moved by hand");
+            events::publish(LockEventKind::AutolockCancelled);
+        }
+    }
+
+    async fn run(mut self, mut rx: Receiver<QueuedInstruction>) {
+        let mut motion: Option<Motion> = None;
+        loop {
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Some(queued) => self.accept(queued),
+                    None => {
+                        info!("Every instruction sender is gone, lock handler stopping");
+                        return;
+                    }
+                },
+                joined = async { motion.as_mut().expect("guarded by is_some").await }, if motion.is_some() => {
+                    motion = None;
+                    self.finish(joined);
+                }
+                _ = async { self.clock.sleep_until(self.autolock_at.expect("guarded by is_some")).await }, if self.autolock_at.is_some() => {
+                    self.autolock_fired();
+                }
+                _ = async { self.clock.sleep_until(self.check_at.expect("guarded by is_some")).await }, if self.check_at.is_some() => {
+                    self.check_position();
+                }
+            }
+            // take in everything that's already waiting so the policy sees the whole picture
+            while let Ok(queued) = rx.try_recv() {
+                self.accept(queued);
+            }
+            if motion.is_none() {
+                motion = self.start_next();
+            }
+        }
     }
 }
 
+pub async fn handle_lock_instruction(rx: Receiver<QueuedInstruction>, lock: Lock) {
+    LockActor::new(lock).run(rx).await;
+}
+
 pub trait LockInstructor {
-    fn send_instruction(&self, instruction: LockInstruction) -> Result<(), LockInUse>;
-}
-
-impl LockInstructor for Arc<Sender<LockInstruction>> {
-    fn send_instruction(&self, instruction: LockInstruction) -> Result<(), LockInUse> {
-        match LOCK_IN_USE.try_lock() {
-            Ok(_) => {
-                if self.try_send(instruction.clone()).is_err() {
-                    println!(
-                        "Unexpected error on try_send instruction {:?}, Justin you fucked up the control flow.",
-                        instruction
-                    );
-                    return Err(LockInUse);
+    // resolves once the lock handler has either queued the instruction or turned it away
+    fn send_instruction(
+        &self,
+        instruction: LockInstruction,
+    ) -> impl Future<Output = Result<InstructionTicket, LockInUse>> + Send;
+}
+
+impl LockInstructor for Arc<Sender<QueuedInstruction>> {
+    async fn send_instruction(
+        &self,
+        instruction: LockInstruction,
+    ) -> Result<InstructionTicket, LockInUse> {
+        let id = NEXT_INSTRUCTION_ID.fetch_add(1, Ordering::Relaxed);
+        let sent = send_and_await_queued(self, id, instruction.clone()).await;
+        if let Err(e) = &sent {
+            events::publish(LockEventKind::InstructionDropped {
+                id,
+                instruction: instruction.kind(),
+                source: instruction.source().clone(),
+                reason: e.to_string(),
+            });
+        }
+        sent
+    }
+}
+
+async fn send_and_await_queued(
+    lock_tx: &Sender<QueuedInstruction>,
+    id: InstructionId,
+    instruction: LockInstruction,
+) -> Result<InstructionTicket, LockInUse> {
+    let (replies_tx, mut replies) = unbounded_channel();
+    let queued = QueuedInstruction::new(id, instruction, replies_tx);
+    if let Err(e) = lock_tx.try_send(queued) {
+        let queued = match e {
+            TrySendError::Full(queued) => queued,
+            TrySendError::Closed(queued) => {
+                error!("Lock handler is gone. Justin you fucked up the control flow.");
+                queued
+            }
+        };
+        let _entered = queued.span.clone().entered();
+        warn!("Lock queue is full, dropping");
+        // it never reached the actor, so answer for it. that's what gets it audited
+        queued.reply(InstructionStatus::Rejected);
+        return Err(LockInUse);
+    }
+    match replies.recv().await {
+        Some(InstructionStatus::Queued { position }) => Ok(InstructionTicket {
+            id,
+            position,
+            replies,
+        }),
+        _ => Err(LockInUse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::{
+        clock::{self, VirtualClock},
+        rpi::{MockGpio, Simulator, simulator::BoardUpdate},
+        testing,
+    };
+
+    fn reason() -> FaultReason {
+        FaultReason::MovedByHand {
+            detail: "turned by hand".to_string(),
+        }
+    }
+
+    fn jammed() -> LockState {
+        LockState::Jammed {
+            during: LockAction::Unlock,
+            reason: reason(),
+        }
+    }
+
+    fn unknown() -> LockState {
+        LockState::Unknown { reason: reason() }
+    }
+
+    fn states() -> Vec<LockState> {
+        vec![
+            LockState::Unlocked,
+            LockState::Locked,
+            LockState::Locking,
+            LockState::Unlocking,
+            jammed(),
+            unknown(),
+        ]
+    }
+
+    fn transitions() -> Vec<Transition> {
+        vec![
+            Transition::Start(LockAction::Lock),
+            Transition::Start(LockAction::Unlock),
+            Transition::Finish,
+            Transition::Fail(reason()),
+            Transition::Mark(LockState::Locked),
+            Transition::Mark(LockState::Unlocked),
+            Transition::Mark(LockState::Locking),
+            Transition::Mark(unknown()),
+            Transition::Home,
+            Transition::Lost(reason()),
+        ]
+    }
+
+    // the actor runs alongside the test and always gets polled first, so once the test
+    // has settled, the actor has dealt with everything it was woken for
+    async fn with_actor<F: Future>(
+        lock: Lock,
+        test: impl FnOnce(Arc<Sender<QueuedInstruction>>) -> F,
+    ) -> F::Output {
+        with_policy(config().lock.queue_policy, lock, test).await
+    }
+
+    async fn with_policy<F: Future>(
+        policy: QueuePolicy,
+        lock: Lock,
+        test: impl FnOnce(Arc<Sender<QueuedInstruction>>) -> F,
+    ) -> F::Output {
+        let (lock_tx, lock_rx) = channel(QUEUE_CAPACITY);
+        let lock_tx = Arc::new(lock_tx);
+        let mut actor = LockActor::new(lock);
+        actor.policy = policy;
+        tokio::select! {
+            biased;
+            _ = actor.run(lock_rx) => unreachable!("lock_tx is still here"),
+            done = test(Arc::clone(&lock_tx)) => done,
+        }
+    }
+
+    // a hand's pace is real time, so this waits on the wall clock while the actor's stands still
+    async fn turn_by_hand(sim: &Simulator, to: i64) {
+        sim.update(BoardUpdate {
+            bolt_position: Some(to),
+            ..Default::default()
+        })
+        .unwrap();
+        while sim.snapshot().bolt_position != to {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    fn moving() -> bool {
+        matches!(current_state(), LockState::Locking | LockState::Unlocking)
+    }
+
+    fn instructions() -> Vec<LockInstruction> {
+        vec![
+            LockInstruction::EnsureLocked(InstructionSource::Button),
+            LockInstruction::EnsureUnlocked(InstructionSource::Button),
+            LockInstruction::Reverse(InstructionSource::Button),
+            LockInstruction::HoldOpen(InstructionSource::Button),
+        ]
+    }
+
+    #[test]
+    fn next_allows_only_its_table() {
+        let mut allowed = vec![
+            (
+                LockState::Unlocked,
+                Transition::Start(LockAction::Lock),
+                LockState::Locking,
+            ),
+            (
+                LockState::Locked,
+                Transition::Start(LockAction::Unlock),
+                LockState::Unlocking,
+            ),
+            (LockState::Locking, Transition::Finish, LockState::Locked),
+            (
+                LockState::Unlocking,
+                Transition::Finish,
+                LockState::Unlocked,
+            ),
+            (
+                LockState::Locking,
+                Transition::Fail(reason()),
+                LockState::Jammed {
+                    during: LockAction::Lock,
+                    reason: reason(),
+                },
+            ),
+            (
+                LockState::Unlocking,
+                Transition::Fail(reason()),
+                LockState::Jammed {
+                    during: LockAction::Unlock,
+                    reason: reason(),
+                },
+            ),
+            (LockState::Locked, Transition::Lost(reason()), unknown()),
+            (LockState::Unlocked, Transition::Lost(reason()), unknown()),
+        ];
+        for from in [LockState::Locked, LockState::Unlocked, jammed(), unknown()] {
+            for marked in [LockState::Locked, LockState::Unlocked] {
+                allowed.push((from.clone(), Transition::Mark(marked.clone()), marked));
+            }
+            allowed.push((from, Transition::Home, LockState::Locking));
+        }
+
+        for state in states() {
+            for transition in transitions() {
+                let expected = allowed
+                    .iter()
+                    .find(|(from, allowed, _)| *from == state && *allowed == transition)
+                    .map(|(_, _, to)| to.clone());
+                assert_eq!(
+                    state.next(transition.clone()),
+                    expected,
+                    "{state:?} on {transition:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn faults_refuse_every_move() {
+        for state in [jammed(), unknown()] {
+            for instruction in instructions() {
+                let refused = state.to_action(instruction.clone()).unwrap_err();
+                assert_eq!(refused.state, state, "{instruction:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn settled_states_move_only_the_other_way() {
+        for instruction in instructions() {
+            let from_locked = match instruction {
+                LockInstruction::EnsureLocked(_) => None,
+                _ => Some(LockAction::Unlock),
+            };
+            let from_unlocked = match instruction {
+                LockInstruction::EnsureLocked(_) | LockInstruction::Reverse(_) => {
+                    Some(LockAction::Lock)
                 }
-                Ok(())
+                _ => None,
+            };
+            assert_eq!(
+                LockState::Locked.to_action(instruction.clone()).unwrap(),
+                from_locked
+            );
+            assert_eq!(
+                LockState::Unlocked.to_action(instruction).unwrap(),
+                from_unlocked
+            );
+        }
+    }
+
+    #[test]
+    fn mark_and_home_clear_faults() {
+        let source = InstructionSource::Button;
+        for state in [jammed(), unknown()] {
+            for mark in [
+                LockInstruction::MarkLocked(source.clone()),
+                LockInstruction::MarkUnlocked(source.clone()),
+            ] {
+                assert_eq!(state.to_action(mark.clone()).unwrap(), None);
+                let marked = mark.marks().unwrap();
+                assert_eq!(state.next(Transition::Mark(marked.clone())), Some(marked));
             }
-            Err(_) => Err(LockInUse),
+
+            let home = LockInstruction::Home(source.clone());
+            assert_eq!(state.to_action(home).unwrap(), Some(LockAction::Lock));
+            let homing = state.next(Transition::Home).unwrap();
+            assert_eq!(homing.next(Transition::Finish), Some(LockState::Locked));
         }
     }
-}
 
-impl Default for Lock {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn coalescing_never_folds_across_admin_instructions() {
+        testing::config();
+        let lock = Lock::new(&MockGpio::new(), clock::system()).unwrap();
+        let mut actor = LockActor::new(lock);
+        let mut replies = Vec::new();
+        for (id, instruction) in [
+            (
+                1,
+                LockInstruction::EnsureUnlocked(InstructionSource::Button),
+            ),
+            (2, LockInstruction::Reverse(InstructionSource::Button)),
+            (3, LockInstruction::MarkUnlocked(InstructionSource::Button)),
+            (4, LockInstruction::Reverse(InstructionSource::Button)),
+            (5, LockInstruction::HoldOpen(InstructionSource::Button)),
+        ] {
+            let (tx, rx) = unbounded_channel();
+            actor
+                .pending
+                .push_back(QueuedInstruction::new(id, instruction, tx));
+            replies.push(rx);
+        }
+
+        // unlock then toggle from locked ends up locked, and stops short of the mark
+        let folded = actor.coalesce_pending(&LockState::Locked).unwrap();
+        assert_eq!(folded.id, 2);
+        assert!(matches!(
+            folded.instruction,
+            LockInstruction::EnsureLocked(_)
+        ));
+        assert!(matches!(
+            replies[0].try_recv(),
+            Ok(InstructionStatus::Coalesced { into: 2 })
+        ));
+
+        let mark = actor.coalesce_pending(&LockState::Locked).unwrap();
+        assert_eq!(mark.id, 3);
+        assert!(matches!(mark.instruction, LockInstruction::MarkUnlocked(_)));
+
+        let rest = actor.coalesce_pending(&LockState::Unlocked).unwrap();
+        assert_eq!(rest.id, 5);
+        assert!(matches!(rest.instruction, LockInstruction::HoldOpen(_)));
+        assert!(matches!(
+            replies[3].try_recv(),
+            Ok(InstructionStatus::Coalesced { into: 5 })
+        ));
+        assert!(actor.coalesce_pending(&LockState::Unlocked).is_none());
+        // the ones that came out whole haven't been told anything yet
+        for id in [1, 2, 4] {
+            assert!(replies[id].try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn autolock_fires_right_on_the_delay() {
+        let _serial = testing::serial(LockState::Unlocked).await;
+        let delay = config().lock.autolock_delay().unwrap();
+        let clock = VirtualClock::new();
+        let (_sim, lock) = testing::simulated_lock(&clock, config().motion.steps as i64);
+        with_actor(lock, |lock_tx| async move {
+            // already unlocked, but it still starts the countdown
+            let ticket = lock_tx
+                .send_instruction(LockInstruction::EnsureUnlocked(InstructionSource::Button))
+                .await
+                .unwrap();
+            assert!(matches!(ticket.completion().await, InstructionStatus::NoOp));
+
+            clock.advance(delay - Duration::from_millis(1));
+            testing::settle().await;
+            assert_eq!(current_state(), LockState::Unlocked);
+            clock.advance(Duration::from_millis(1));
+            testing::settle().await;
+            assert_eq!(current_state(), LockState::Locking);
+
+            testing::pump_until(&clock, || current_state() == LockState::Locked).await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reject_turns_away_anything_sent_while_busy() {
+        let _serial = testing::serial(LockState::Locked).await;
+        let clock = VirtualClock::new();
+        let (_sim, lock) = testing::simulated_lock(&clock, 0);
+        with_policy(QueuePolicy::Reject, lock, |lock_tx| async move {
+            let unlock = lock_tx
+                .send_instruction(LockInstruction::EnsureUnlocked(InstructionSource::Button))
+                .await
+                .unwrap();
+            assert_eq!(unlock.position, 0);
+            assert!(moving());
+            let lock = lock_tx
+                .send_instruction(LockInstruction::EnsureLocked(InstructionSource::Button))
+                .await;
+            assert!(matches!(lock, Err(LockInUse)));
+
+            testing::pump_until(&clock, || !moving()).await;
+            assert!(matches!(
+                unlock.completion().await,
+                InstructionStatus::Executed {
+                    action: LockAction::Unlock
+                }
+            ));
+            // free again, so the next one goes through
+            let lock = lock_tx
+                .send_instruction(LockInstruction::EnsureLocked(InstructionSource::Button))
+                .await
+                .unwrap();
+            testing::pump_until(&clock, || current_state() == LockState::Locked).await;
+            assert!(matches!(
+                lock.completion().await,
+                InstructionStatus::Executed {
+                    action: LockAction::Lock
+                }
+            ));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn queue_runs_everything_in_the_order_it_came() {
+        let _serial = testing::serial(LockState::Locked).await;
+        let clock = VirtualClock::new();
+        let (_sim, lock) = testing::simulated_lock(&clock, 0);
+        with_policy(QueuePolicy::Queue, lock, |lock_tx| async move {
+            let mut tickets = Vec::new();
+            for instruction in [
+                LockInstruction::EnsureUnlocked(InstructionSource::Button),
+                LockInstruction::EnsureLocked(InstructionSource::Button),
+                LockInstruction::EnsureLocked(InstructionSource::Button),
+                LockInstruction::Reverse(InstructionSource::Button),
+            ] {
+                let ticket = lock_tx.send_instruction(instruction).await.unwrap();
+                assert_eq!(ticket.position, tickets.len());
+                tickets.push(tokio::spawn(ticket.completion()));
+            }
+
+            testing::pump_until(&clock, || tickets.iter().all(|t| t.is_finished())).await;
+            let mut outcomes = Vec::new();
+            for ticket in tickets {
+                outcomes.push(ticket.await.unwrap());
+            }
+            assert!(matches!(
+                outcomes.as_slice(),
+                [
+                    InstructionStatus::Executed {
+                        action: LockAction::Unlock
+                    },
+                    InstructionStatus::Executed {
+                        action: LockAction::Lock
+                    },
+                    InstructionStatus::NoOp,
+                    InstructionStatus::Executed {
+                        action: LockAction::Unlock
+                    },
+                ]
+            ));
+            assert_eq!(current_state(), LockState::Unlocked);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn a_faulted_lock_refuses_but_still_queues_first() {
+        let _serial = testing::serial(jammed()).await;
+        let clock = VirtualClock::new();
+        let (_sim, lock) = testing::simulated_lock(&clock, 0);
+        with_actor(lock, |lock_tx| async move {
+            let ticket = lock_tx
+                .send_instruction(LockInstruction::EnsureLocked(InstructionSource::Button))
+                .await
+                .unwrap();
+            assert_eq!(ticket.position, 0);
+            assert!(matches!(
+                ticket.completion().await,
+                InstructionStatus::Refused { .. }
+            ));
+            assert_eq!(current_state(), jammed());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn a_slipping_motor_jams_the_lock() {
+        let _serial = testing::serial(LockState::Locked).await;
+        let clock = VirtualClock::new();
+        let (sim, lock) = testing::simulated_lock(&clock, 0);
+        sim.update(BoardUpdate {
+            skip_steps: Some(u32::MAX),
+            ..Default::default()
+        })
+        .unwrap();
+        with_actor(lock, |lock_tx| async move {
+            let ticket = lock_tx
+                .send_instruction(LockInstruction::EnsureUnlocked(InstructionSource::Button))
+                .await
+                .unwrap();
+            testing::pump_until(&clock, || !moving()).await;
+            assert!(matches!(
+                ticket.completion().await,
+                InstructionStatus::Failed { .. }
+            ));
+        })
+        .await;
+        assert!(matches!(
+            current_state(),
+            LockState::Jammed {
+                during: LockAction::Unlock,
+                reason: FaultReason::PositionMismatch { .. },
+            }
+        ));
+        assert_eq!(sim.snapshot().bolt_position, 0);
+    }
+
+    #[tokio::test]
+    async fn turning_the_bolt_to_the_other_end_marks_it() {
+        let _serial = testing::serial(LockState::Locked).await;
+        let check = Duration::from_millis(config().feedback.check_interval_ms);
+        let clock = VirtualClock::new();
+        let (sim, lock) = testing::simulated_lock(&clock, 0);
+        with_actor(lock, |_| async move {
+            turn_by_hand(&sim, config().motion.steps as i64).await;
+            clock.advance(check);
+            testing::settle().await;
+        })
+        .await;
+        assert_eq!(current_state(), LockState::Unlocked);
+    }
+
+    #[tokio::test]
+    async fn turning_the_bolt_partway_loses_it() {
+        let _serial = testing::serial(LockState::Locked).await;
+        let check = Duration::from_millis(config().feedback.check_interval_ms);
+        let clock = VirtualClock::new();
+        let (sim, lock) = testing::simulated_lock(&clock, 0);
+        with_actor(lock, |_| async move {
+            turn_by_hand(&sim, config().motion.steps as i64 / 2).await;
+            clock.advance(check);
+            testing::settle().await;
+        })
+        .await;
+        assert!(matches!(
+            current_state(),
+            LockState::Unknown {
+                reason: FaultReason::MovedByHand { .. }
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn homing_finds_the_bolt_again() {
+        let _serial = testing::serial(unknown()).await;
+        let clock = VirtualClock::new();
+        let (sim, lock) = testing::simulated_lock(&clock, config().motion.steps as i64 / 2);
+        with_actor(lock, |lock_tx| async move {
+            let ticket = lock_tx
+                .send_instruction(LockInstruction::Home(InstructionSource::Button))
+                .await
+                .unwrap();
+            testing::settle().await;
+            assert_eq!(current_state(), LockState::Locking);
+            testing::pump_until(&clock, || !moving()).await;
+            assert!(matches!(
+                ticket.completion().await,
+                InstructionStatus::Executed {
+                    action: LockAction::Lock
+                }
+            ));
+        })
+        .await;
+        assert_eq!(current_state(), LockState::Locked);
+        assert_eq!(sim.snapshot().bolt_position, 0);
     }
 }
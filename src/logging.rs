@@ -0,0 +1,51 @@
+use std::{
+    env,
+    io::{IsTerminal, stderr},
+};
+
+use tracing::warn;
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+
+use crate::config::{LogConfig, LogFormat};
+
+// the cli subcommands print their own answers, logs from them are only worth seeing when something's wrong
+const CLI_FILTER: &str = "warn";
+
+fn filter(default: &str) -> Result<EnvFilter, anyhow::Error> {
+    match env::var("RUST_LOG") {
+        Ok(directives) => Ok(EnvFilter::try_new(directives)?),
+        Err(_) => Ok(EnvFilter::try_new(default)?),
+    }
+}
+
+// everything goes to stderr, stdout belongs to the cli's output
+pub fn init(log: &LogConfig, daemon: bool) -> Result<(), anyhow::Error> {
+    let text = fmt::layer()
+        .with_writer(stderr)
+        .with_ansi(stderr().is_terminal());
+    if !daemon {
+        tracing_subscriber::registry()
+            .with(filter(CLI_FILTER)?)
+            .with(text)
+            .try_init()?;
+        return Ok(());
+    }
+
+    let registry = tracing_subscriber::registry().with(filter(&log.filter)?);
+    match log.format {
+        LogFormat::Text => registry.with(text).try_init()?,
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_writer(stderr))
+            .try_init()?,
+        LogFormat::Journald => match tracing_journald::layer() {
+            Ok(journald) => registry
+                .with(journald.with_syslog_identifier("doorknob".to_string()))
+                .try_init()?,
+            Err(e) => {
+                registry.with(text).try_init()?;
+                warn!("Can't reach journald, logging to stderr instead. {e}");
+            }
+        },
+    }
+    Ok(())
+}
@@ -1,34 +1,106 @@
 use std::sync::Arc;
 
 use auth::setup_password;
-use lock::{LockInstruction, STATE, handle_lock_instruction};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::GpioBackend;
+use lock::{
+    Lock, LockState, QUEUE_CAPACITY, QueuedInstruction, STATE, current_state,
+    handle_lock_instruction,
+};
 use once_cell::sync::Lazy;
+use rpi::{Button, Simulator, UltrasonicSensor};
 use sensors::{expose_button_interface, expose_closed_detection_interface};
 use tokio::{select, sync::mpsc::channel};
+use tracing::info;
 
+pub mod api;
+pub mod audit;
 pub mod auth;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod events;
+pub mod guest;
 pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod motion;
+pub mod motor;
+pub mod mqtt;
 pub mod routes;
 pub mod rpi;
 pub mod sensors;
 pub mod server;
+pub mod state_file;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+pub mod tokens;
+pub mod webhooks;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
 async fn main() -> Result<(), anyhow::Error> {
-    println!("Setting password");
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::CheckConfig => cli::check_config(cli.config.as_deref()),
+        Command::Serve => {
+            logging::init(&config::init(cli.config.as_deref())?.log, true)?;
+            serve().await
+        }
+        command => {
+            logging::init(&config::init(cli.config.as_deref())?.log, false)?;
+            cli::run(command).await
+        }
+    }
+}
+
+async fn serve() -> Result<(), anyhow::Error> {
+    let config = config::config();
+    info!("Setting password");
     setup_password().await;
-    println!("Validating args");
-    Lazy::force(&STATE); // if an env variable is not given for lock state, we need the user to set it
+    guest::load_codes().await?;
+    throttle::load()?;
+    tokens::load_tokens().await?;
+    webhooks::load()?;
+    metrics::init();
+    info!("Validating args");
+    Lazy::force(&STATE); // restores from the state file, falls back to env or asking the user
+
+    let clock = clock::system();
+    let gpio = rpi::init(config.gpio.backend)?;
+    if config.gpio.backend == GpioBackend::Simulator {
+        // the virtual bolt starts wherever the state file says the real one is
+        let steps = config.motion.steps as i64;
+        Simulator::shared().place_bolt(match current_state() {
+            LockState::Locked => 0,
+            LockState::Unlocked => steps,
+            _ => steps / 2,
+        });
+    }
+    info!(backend = ?config.gpio.backend, "Claiming gpio pins");
+    let lock = Lock::new(gpio, Arc::clone(&clock))?;
+    let button = Button::from_gpio(gpio, config.gpio.button_pin, Arc::clone(&clock))?;
+    let ultrasonic_sensor = UltrasonicSensor::from_gpio(gpio, &config.gpio)?;
 
-    let (lock_tx, lock_rx) = channel::<LockInstruction>(1); // buffer is flushed after first message is processed
+    info!(queue_policy = ?config.lock.queue_policy, "Instruction queue policy set");
+    match config.lock.autolock_delay() {
+        Some(delay) => info!("Autolocking {}s after an unlock", delay.as_secs()),
+        None => info!("Autolock is off"),
+    }
+    let (lock_tx, lock_rx) = channel::<QueuedInstruction>(QUEUE_CAPACITY);
     let arc_lock_tx = Arc::new(lock_tx);
-    println!("Initialized lock channels, starting hot threads");
+    info!("Initialized lock channels, starting hot threads");
 
     select! {
-        _ = handle_lock_instruction(lock_rx) => {},
-        _ = server::run_app(Arc::clone(&arc_lock_tx)) => {},
-        _ = expose_button_interface(Arc::clone(&arc_lock_tx)) => {},
-        _ = expose_closed_detection_interface(Arc::clone(&arc_lock_tx)) => {}
+        _ = handle_lock_instruction(lock_rx, lock) => {},
+        res = server::run_app(Arc::clone(&arc_lock_tx)) => res?,
+        res = control::serve(Arc::clone(&arc_lock_tx)) => res?,
+        res = mqtt::run(Arc::clone(&arc_lock_tx)), if config.mqtt.enabled => res?,
+        res = webhooks::run(), if !config.webhooks.endpoints.is_empty() => res?,
+        _ = expose_button_interface(Arc::clone(&arc_lock_tx), button, Arc::clone(&clock)) => {},
+        _ = expose_closed_detection_interface(Arc::clone(&arc_lock_tx), ultrasonic_sensor, clock) => {}
     };
 
     Ok(())
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use axum::{
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge,
+};
+use tracing::error;
+
+use crate::{
+    lock::{InstructionStatus, LockAction, LockInstruction, LockState, current_state},
+    rpi::ReadEchoError,
+};
+
+// every instruction once it's settled, whatever became of it
+static INSTRUCTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "doorknob_instructions_total",
+        "Lock instructions by kind, source and outcome",
+        &["instruction", "source", "outcome"]
+    )
+    .unwrap()
+});
+
+// the ones that actually moved the bolt
+static LOCK_ACTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "doorknob_lock_actions_total",
+        "Times the bolt was moved, by direction and source",
+        &["action", "source"]
+    )
+    .unwrap()
+});
+
+static LOCK_IN_USE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "doorknob_lock_in_use_total",
+        "Instructions turned away because the lock was busy or its queue was full",
+        &["source"]
+    )
+    .unwrap()
+});
+
+static AUTH_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "doorknob_auth_failures_total",
+        "Wrong passcodes and tokens",
+        &["credential"]
+    )
+    .unwrap()
+});
+
+static AUTH_THROTTLED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "doorknob_auth_throttled_total",
+        "Attempts refused without checking because the client or everyone was backed off"
+    )
+    .unwrap()
+});
+
+// a move that takes much longer than usual is what a jam looks like from here
+static MOTOR_RUN: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "doorknob_motor_run_seconds",
+        "How long the motor ran per move",
+        &["action"],
+        vec![0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 5.0, 7.5, 10.0]
+    )
+    .unwrap()
+});
+
+static SENSOR_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "doorknob_ultrasonic_errors_total",
+        "Failed ultrasonic sensor reads",
+        &["error"]
+    )
+    .unwrap()
+});
+
+static DISTANCE: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "doorknob_ultrasonic_distance_cm",
+        "Ultrasonic sensor readings",
+        vec![
+            2.0, 5.0, 10.0, 15.0, 20.0, 30.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0, 400.0
+        ]
+    )
+    .unwrap()
+});
+
+static AUTOLOCKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "doorknob_autolock_triggers_total",
+        "Locks we sent ourselves. sensor is the door being seen shut, timer is lock.autolock_secs",
+        &["trigger"]
+    )
+    .unwrap()
+});
+
+static LOCKED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "doorknob_locked",
+        "1 when the lock is locked, 0 when unlocked"
+    )
+    .unwrap()
+});
+
+static FAULTED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "doorknob_lock_faulted",
+        "1 while the lock is jammed or its bolt position is unknown, until someone marks it"
+    )
+    .unwrap()
+});
+
+// registers everything up front so a scrape shows every metric, not just the ones that happened yet
+pub fn init() {
+    Lazy::force(&INSTRUCTIONS);
+    Lazy::force(&LOCK_ACTIONS);
+    Lazy::force(&LOCK_IN_USE);
+    Lazy::force(&AUTH_FAILURES);
+    Lazy::force(&AUTH_THROTTLED);
+    Lazy::force(&MOTOR_RUN);
+    Lazy::force(&SENSOR_ERRORS);
+    Lazy::force(&DISTANCE);
+    Lazy::force(&AUTOLOCKS);
+    Lazy::force(&LOCKED);
+    Lazy::force(&FAULTED);
+}
+
+pub fn record_instruction(instruction: &LockInstruction, outcome: &InstructionStatus) {
+    let source = instruction.source().kind();
+    INSTRUCTIONS
+        .with_label_values(&[instruction.kind(), source, outcome.name()])
+        .inc();
+    match outcome {
+        InstructionStatus::Executed { action } => LOCK_ACTIONS
+            .with_label_values(&[action_label(action), source])
+            .inc(),
+        InstructionStatus::Rejected => LOCK_IN_USE.with_label_values(&[source]).inc(),
+        _ => {}
+    }
+}
+
+pub fn record_auth_failure(credential: &str) {
+    AUTH_FAILURES.with_label_values(&[credential]).inc();
+}
+
+pub fn record_throttled() {
+    AUTH_THROTTLED.inc();
+}
+
+pub fn record_motor_run(action: &LockAction, took: Duration) {
+    MOTOR_RUN
+        .with_label_values(&[action_label(action)])
+        .observe(took.as_secs_f64());
+}
+
+pub fn record_distance(cm: f64) {
+    DISTANCE.observe(cm);
+}
+
+pub fn record_sensor_error(error: &ReadEchoError) {
+    let label = match error {
+        ReadEchoError::Timeout => "timeout",
+    };
+    SENSOR_ERRORS.with_label_values(&[label]).inc();
+}
+
+pub fn record_autolock(trigger: &str) {
+    AUTOLOCKS.with_label_values(&[trigger]).inc();
+}
+
+fn action_label(action: &LockAction) -> &'static str {
+    match action {
+        LockAction::Lock => "lock",
+        LockAction::Unlock => "unlock",
+    }
+}
+
+// prometheus text format. unauthenticated like most scrape targets, see server.metrics
+pub async fn metrics() -> Response {
+    let state = current_state();
+    LOCKED.set(i64::from(state == LockState::Locked));
+    FAULTED.set(i64::from(state.is_fault()));
+    let mut body = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut body) {
+        error!("Could not encode metrics. {e}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(CONTENT_TYPE, TextEncoder::new().format_type().to_string())],
+        body,
+    )
+        .into_response()
+}
@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{
+    config::{MotionProfile, ProfileShape},
+    rpi::MIN_STEP_DELAY,
+};
+
+// integration step for the s-curve ramp, far finer than the gap between two steps at any speed we allow
+const DT: f64 = 0.000_1;
+
+// one row of a move as it will be stepped. `doorknob motion-table` prints these for plotting
+#[derive(Debug, Clone, Serialize)]
+pub struct StepTiming {
+    pub step: u64,
+    pub at_ms: f64,    // when the pulse goes out, counted from the first one
+    pub delay_ms: f64, // the wait after it
+    pub speed: f64,    // steps/s
+}
+
+// speed at each step when only speeding up, from start_speed until max_speed
+fn trapezoidal_ramp(profile: &MotionProfile, steps: usize) -> Vec<f64> {
+    (0..steps)
+        .map(|step| {
+            let v = (profile.start_speed.powi(2) + 2.0 * profile.acceleration * step as f64).sqrt();
+            v.min(profile.max_speed)
+        })
+        .collect()
+}
+
+// same, but the acceleration builds up and eases out at `jerk`. no closed form worth the trouble
+// once the acceleration cap might or might not be reached, so it's integrated
+fn s_curve_ramp(profile: &MotionProfile, steps: usize) -> Vec<f64> {
+    let mut speeds = Vec::with_capacity(steps);
+    let (mut speed, mut acceleration, mut position) = (profile.start_speed, 0.0_f64, 0.0);
+    let nudge = profile.jerk * DT;
+    while speeds.len() < steps {
+        // every whole step passed gets the speed it was passed at
+        while speeds.len() < steps && position >= speeds.len() as f64 {
+            speeds.push(speed);
+        }
+        if speed >= profile.max_speed {
+            speeds.resize(steps, profile.max_speed);
+            break;
+        }
+        // start easing out early enough to land on max_speed with no acceleration left.
+        // never quite to zero though, or it would creep toward max_speed forever
+        let easing_out = acceleration.powi(2) / (2.0 * profile.jerk) >= profile.max_speed - speed;
+        acceleration = match easing_out {
+            true => (acceleration - nudge).max(nudge),
+            false => (acceleration + nudge).min(profile.acceleration),
+        };
+        speed = (speed + acceleration * DT).min(profile.max_speed);
+        position += speed * DT;
+    }
+    speeds
+}
+
+// the ramp up mirrored for the ramp down. a move too short to reach max_speed peaks in the middle
+pub fn step_table(profile: &MotionProfile, steps: u64) -> Vec<StepTiming> {
+    let count = steps as usize;
+    let ramp = match profile.shape {
+        ProfileShape::Trapezoidal => trapezoidal_ramp(profile, count),
+        ProfileShape::SCurve => s_curve_ramp(profile, count),
+    };
+    let mut at = 0.0;
+    (0..count)
+        .map(|step| {
+            let speed = ramp[step].min(ramp[count - 1 - step]);
+            let delay = (1.0 / speed).max(MIN_STEP_DELAY.as_secs_f64());
+            let timing = StepTiming {
+                step: step as u64,
+                at_ms: at * 1000.0,
+                delay_ms: delay * 1000.0,
+                speed,
+            };
+            at += delay;
+            timing
+        })
+        .collect()
+}
+
+pub fn delays(table: &[StepTiming]) -> Vec<Duration> {
+    table
+        .iter()
+        .map(|timing| Duration::from_secs_f64(timing.delay_ms / 1000.0).max(MIN_STEP_DELAY))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use more_asserts::{assert_ge, assert_gt, assert_le, assert_lt};
+
+    use super::*;
+
+    fn profile(shape: ProfileShape, max_speed: f64) -> MotionProfile {
+        MotionProfile {
+            shape,
+            start_speed: 25.0,
+            max_speed,
+            acceleration: 100.0,
+            jerk: 400.0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} is not {expected}"
+        );
+    }
+
+    const SHAPES: [ProfileShape; 2] = [ProfileShape::Trapezoidal, ProfileShape::SCurve];
+
+    #[test]
+    fn ramp_down_mirrors_ramp_up() {
+        for shape in SHAPES {
+            for steps in [1, 2, 7, 60, 301] {
+                let table = step_table(&profile(shape, 120.0), steps);
+                assert_eq!(table.len(), steps as usize);
+                for (up, down) in table.iter().zip(table.iter().rev()) {
+                    assert_eq!(up.delay_ms, down.delay_ms, "{shape:?} over {steps} steps");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn first_and_last_steps_go_at_start_speed() {
+        for shape in SHAPES {
+            let profile = profile(shape, 120.0);
+            let table = step_table(&profile, 200);
+            let start_delay_ms = 1000.0 / profile.start_speed;
+            assert_close(table[0].delay_ms, start_delay_ms);
+            assert_close(table[199].delay_ms, start_delay_ms);
+            assert_eq!(table[0].at_ms, 0.0);
+        }
+    }
+
+    #[test]
+    fn long_move_cruises_at_max_speed() {
+        for shape in SHAPES {
+            let profile = profile(shape, 120.0);
+            let table = step_table(&profile, 400);
+            let cruise_delay_ms = 1000.0 / profile.max_speed;
+            assert_close(table[200].delay_ms, cruise_delay_ms);
+            for timing in &table {
+                assert_ge!(timing.delay_ms, cruise_delay_ms - 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn short_move_peaks_in_the_middle() {
+        for shape in SHAPES {
+            let profile = profile(shape, 120.0);
+            for steps in [9, 10] {
+                let table = step_table(&profile, steps);
+                let speeds: Vec<f64> = table.iter().map(|timing| timing.speed).collect();
+                let middle = (steps as usize - 1) / 2;
+                assert_lt!(speeds[middle], profile.max_speed, "{shape:?} never cruises");
+                for pair in speeds[..=middle].windows(2) {
+                    assert_lt!(pair[0], pair[1], "{shape:?} speeds up until the middle");
+                }
+                for pair in speeds[steps as usize - 1 - middle..].windows(2) {
+                    assert_gt!(pair[0], pair[1], "{shape:?} slows down after it");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn s_curve_stays_within_acceleration() {
+        let profile = profile(ProfileShape::SCurve, 300.0);
+        let table = step_table(&profile, 600);
+        let mut steepest: f64 = 0.0;
+        for pair in table.windows(2).take(300) {
+            let gained = pair[1].speed - pair[0].speed;
+            let took = pair[0].delay_ms / 1000.0;
+            // the ramp is integrated in DT ticks, so a step's speed can be a tick late
+            assert_le!(gained, profile.acceleration * (took + DT));
+            steepest = steepest.max(gained / took);
+        }
+        // it does get near the cap, or this wouldn't be testing it
+        assert_gt!(steepest, profile.acceleration * 0.9);
+    }
+
+    #[test]
+    fn never_steps_faster_than_the_driver() {
+        for shape in SHAPES {
+            let mut profile = profile(shape, 1000.0);
+            profile.start_speed = 500.0;
+            let table = step_table(&profile, 100);
+            for timing in &table {
+                assert_ge!(timing.delay_ms, MIN_STEP_DELAY.as_secs_f64() * 1000.0);
+            }
+            for delay in delays(&table) {
+                assert_ge!(delay, MIN_STEP_DELAY);
+            }
+        }
+    }
+}
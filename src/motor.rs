@@ -0,0 +1,123 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tracing::{debug, error};
+
+use crate::rpi::{EndStops, MotorDirection, StepMotor};
+
+// one move: every step's delay worked out up front, so the thread just pulses.
+// with an end-stop that way it stops early once that closes
+struct MotorJob {
+    direction: MotorDirection,
+    delays: Vec<Duration>,
+    reports: UnboundedSender<MotorReport>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MotorReport {
+    Stepped {
+        step: usize,
+        steps: usize,
+    },
+    // end_stop is how the end-stop that way read at the end, None with none fitted
+    Finished {
+        steps: usize,
+        end_stop: Option<bool>,
+    },
+}
+
+// the motor thread is gone, most likely it panicked partway through a move
+#[derive(Debug)]
+pub struct MotorFault;
+
+impl fmt::Display for MotorFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "motor thread stopped mid-move")
+    }
+}
+
+impl Error for MotorFault {}
+
+// owns the stepper on a plain OS thread. a move is a couple of seconds of sleeping between pulses,
+// which on a tokio worker would stall the http server and sensors for all of it
+pub struct MotorDriver {
+    jobs: Option<mpsc::Sender<MotorJob>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MotorDriver {
+    pub fn spawn(motor: StepMotor, end_stops: EndStops) -> Result<Self, anyhow::Error> {
+        let (jobs, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("motor".to_string())
+            .spawn(move || drive(motor, end_stops, rx))?;
+        Ok(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        })
+    }
+
+    // reports come back as the steps happen and end with Finished.
+    // if the channel closes before that, the thread died
+    pub fn start(
+        &self,
+        direction: MotorDirection,
+        delays: Vec<Duration>,
+    ) -> Result<UnboundedReceiver<MotorReport>, MotorFault> {
+        let (reports, rx) = unbounded_channel();
+        self.jobs
+            .as_ref()
+            .expect("only taken on drop")
+            .send(MotorJob {
+                direction,
+                delays,
+                reports,
+            })
+            .map_err(|_| MotorFault)?;
+        Ok(rx)
+    }
+}
+
+fn drive(mut motor: StepMotor, end_stops: EndStops, jobs: mpsc::Receiver<MotorJob>) {
+    while let Ok(job) = jobs.recv() {
+        debug!(direction = ?job.direction, steps = job.delays.len(), "Motor thread moving");
+        let end_stop = end_stops.towards(&job.direction);
+        motor.activate();
+        motor.set_direction(job.direction.clone());
+        let steps = job.delays.len();
+        let mut taken = 0;
+        for (step, delay) in job.delays.into_iter().enumerate() {
+            if end_stop.is_some_and(|end_stop| end_stop.is_closed()) {
+                debug!(step, "End-stop reached");
+                break;
+            }
+            motor.take_step(delay);
+            taken += 1;
+            // nobody listening is no reason to leave the bolt halfway
+            let _ = job.reports.send(MotorReport::Stepped { step, steps });
+        }
+        motor.deactivate();
+        let _ = job.reports.send(MotorReport::Finished {
+            steps: taken,
+            end_stop: end_stop.map(|end_stop| end_stop.is_closed()),
+        });
+    }
+}
+
+impl Drop for MotorDriver {
+    // hangs up and waits for the thread, so its pins are free again once this returns
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            error!("Motor thread had panicked");
+        }
+    }
+}
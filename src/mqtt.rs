@@ -0,0 +1,280 @@
+use std::{sync::Arc, time::Duration};
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde_json::{Value, json};
+use tokio::{
+    select,
+    sync::{broadcast::error::RecvError, mpsc::Sender},
+    time::sleep,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{MqttConfig, config},
+    events::{self, LockEvent, LockEventKind},
+    lock::{
+        InstructionSource, LockInstruction, LockInstructor, LockState, QueuedInstruction,
+        current_state,
+    },
+};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// everything goes out with try_publish so a slow broker can't stall the loop that drains this
+const CLIENT_CAPACITY: usize = 64;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+// home assistant's default payloads, spelled out in discovery anyway
+const LOCK_COMMAND: &str = "LOCK";
+const UNLOCK_COMMAND: &str = "UNLOCK";
+
+struct Topics {
+    availability: String,
+    lock_state: String,
+    lock_command: String,
+    door_state: String,
+    lock_discovery: String,
+    door_discovery: String,
+}
+
+impl Topics {
+    fn new(mqtt: &MqttConfig) -> Self {
+        let base = &mqtt.base_topic;
+        let prefix = &mqtt.discovery_prefix;
+        let node = &mqtt.client_id;
+        Self {
+            availability: format!("{base}/availability"),
+            lock_state: format!("{base}/lock/state"),
+            lock_command: format!("{base}/lock/set"),
+            door_state: format!("{base}/door/state"),
+            lock_discovery: format!("{prefix}/lock/{node}/lock/config"),
+            door_discovery: format!("{prefix}/binary_sensor/{node}/door/config"),
+        }
+    }
+}
+
+fn device(mqtt: &MqttConfig) -> Value {
+    json!({
+        "identifiers": [mqtt.client_id],
+        "name": mqtt.device_name,
+        "manufacturer": "doorknob",
+        "model": "stepper deadbolt",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+fn lock_discovery(mqtt: &MqttConfig, topics: &Topics) -> Value {
+    json!({
+        "name": null, // takes the device's name
+        "unique_id": format!("{}_lock", mqtt.client_id),
+        "command_topic": topics.lock_command,
+        "state_topic": topics.lock_state,
+        "availability_topic": topics.availability,
+        "payload_available": ONLINE,
+        "payload_not_available": OFFLINE,
+        "payload_lock": LOCK_COMMAND,
+        "payload_unlock": UNLOCK_COMMAND,
+        "state_locked": "LOCKED",
+        "state_unlocked": "UNLOCKED",
+        "state_locking": "LOCKING",
+        "state_unlocking": "UNLOCKING",
+        "state_jammed": "JAMMED",
+        "optimistic": false,
+        "device": device(mqtt),
+    })
+}
+
+fn door_discovery(mqtt: &MqttConfig, topics: &Topics) -> Value {
+    json!({
+        "name": "Door",
+        "unique_id": format!("{}_door", mqtt.client_id),
+        "device_class": "door",
+        "state_topic": topics.door_state,
+        "availability_topic": topics.availability,
+        "payload_available": ONLINE,
+        "payload_not_available": OFFLINE,
+        "payload_on": "ON", // open
+        "payload_off": "OFF",
+        "device": device(mqtt),
+    })
+}
+
+fn state_payload(state: &LockState) -> &'static str {
+    match state {
+        LockState::Locked => "LOCKED",
+        LockState::Unlocked => "UNLOCKED",
+        LockState::Locking => "LOCKING",
+        LockState::Unlocking => "UNLOCKING",
+        LockState::Jammed { .. } => "JAMMED",
+        LockState::Unknown { .. } => "None", // home assistant's way of saying it doesn't know
+    }
+}
+
+fn publish(client: &AsyncClient, topic: &str, payload: impl Into<Vec<u8>>) {
+    // retained so home assistant has the latest the moment it (re)subscribes
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+        warn!("Could not publish to {topic}. {e}");
+    }
+}
+
+fn options(mqtt: &MqttConfig, topics: &Topics) -> MqttOptions {
+    let mut options = MqttOptions::new(&mqtt.client_id, &mqtt.host, mqtt.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    // the broker says we're gone for us if we drop off without saying goodbye
+    options.set_last_will(LastWill::new(
+        &topics.availability,
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if !mqtt.username.is_empty() {
+        options.set_credentials(&mqtt.username, &mqtt.password);
+    }
+    options
+}
+
+// every (re)connect starts a fresh session, so discovery, state and the subscription all go out again
+fn announce(client: &AsyncClient, mqtt: &MqttConfig, topics: &Topics) {
+    publish(
+        client,
+        &topics.lock_discovery,
+        lock_discovery(mqtt, topics).to_string(),
+    );
+    publish(
+        client,
+        &topics.door_discovery,
+        door_discovery(mqtt, topics).to_string(),
+    );
+    publish(client, &topics.availability, ONLINE);
+    publish(client, &topics.lock_state, state_payload(&current_state()));
+    if let Err(e) = client.try_subscribe(&topics.lock_command, QoS::AtLeastOnce) {
+        warn!("Could not subscribe to {}. {e}", topics.lock_command);
+    }
+}
+
+fn forward_event(client: &AsyncClient, topics: &Topics, event: LockEvent) {
+    match event.kind {
+        LockEventKind::StateChanged { state } => {
+            publish(client, &topics.lock_state, state_payload(&state))
+        }
+        LockEventKind::DoorOpened => publish(client, &topics.door_state, "ON"),
+        LockEventKind::DoorClosed => publish(client, &topics.door_state, "OFF"),
+        _ => {}
+    }
+}
+
+// what a message on the command topic asks for. a retained one isn't anyone asking now, it's an old
+// command the broker hands out again on every reconnect, so it would unlock the door each time
+fn command(message: &Publish) -> Option<LockInstruction> {
+    if message.retain {
+        warn!(
+            "Ignoring retained mqtt command {:?}, commands have to be sent without retain",
+            String::from_utf8_lossy(&message.payload)
+        );
+        return None;
+    }
+    match &message.payload[..] {
+        p if p == LOCK_COMMAND.as_bytes() => {
+            Some(LockInstruction::EnsureLocked(InstructionSource::Mqtt))
+        }
+        p if p == UNLOCK_COMMAND.as_bytes() => {
+            Some(LockInstruction::EnsureUnlocked(InstructionSource::Mqtt))
+        }
+        other => {
+            warn!(
+                "Ignoring mqtt command {:?}, expected {LOCK_COMMAND} or {UNLOCK_COMMAND}",
+                String::from_utf8_lossy(other)
+            );
+            None
+        }
+    }
+}
+
+// same path as the button: straight to the lock handler, which decides what it means
+fn handle_command(lock_tx: &Arc<Sender<QueuedInstruction>>, instruction: LockInstruction) {
+    let lock_tx = Arc::clone(lock_tx);
+    tokio::spawn(async move {
+        if let Err(e) = lock_tx.send_instruction(instruction).await {
+            info!("Mqtt instruction dropped. {e}");
+        }
+    });
+}
+
+pub async fn run(lock_tx: Arc<Sender<QueuedInstruction>>) -> Result<(), anyhow::Error> {
+    let mqtt = &config().mqtt;
+    let topics = Topics::new(mqtt);
+    let (client, mut eventloop) = AsyncClient::new(options(mqtt, &topics), CLIENT_CAPACITY);
+    let mut events = events::subscribe();
+    info!(
+        "Mqtt to {}:{} as '{}', commands on {}",
+        mqtt.host, mqtt.port, mqtt.client_id, topics.lock_command
+    );
+    loop {
+        let polled = select! {
+            polled = eventloop.poll() => polled,
+            event = events.recv() => {
+                match event {
+                    Ok(event) => forward_event(&client, &topics, event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Mqtt fell behind on lock events, skipped {skipped}");
+                        publish(&client, &topics.lock_state, state_payload(&current_state()));
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+                continue;
+            }
+        };
+        match polled {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to mqtt broker {}", mqtt.host);
+                announce(&client, mqtt, &topics);
+            }
+            Ok(Event::Incoming(Packet::Publish(message)))
+                if message.topic == topics.lock_command =>
+            {
+                if let Some(instruction) = command(&message) {
+                    handle_command(&lock_tx, instruction);
+                }
+            }
+            Ok(other) => debug!(?other, "mqtt"),
+            Err(e) => {
+                // polling again is what reconnects, just don't hammer the broker. out here so a
+                // lock event can't cut the wait short, they'll keep until it's over
+                warn!("Mqtt connection to {} failed. {e}", mqtt.host);
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: &str, retain: bool) -> Publish {
+        let mut message = Publish::new("doorknob/lock/set", QoS::AtLeastOnce, payload);
+        message.retain = retain;
+        message
+    }
+
+    #[test]
+    fn commands_map_to_instructions() {
+        assert!(matches!(
+            command(&message(LOCK_COMMAND, false)),
+            Some(LockInstruction::EnsureLocked(InstructionSource::Mqtt))
+        ));
+        assert!(matches!(
+            command(&message(UNLOCK_COMMAND, false)),
+            Some(LockInstruction::EnsureUnlocked(InstructionSource::Mqtt))
+        ));
+        assert!(command(&message("OPEN", false)).is_none());
+    }
+
+    #[test]
+    fn retained_commands_are_ignored() {
+        assert!(command(&message(UNLOCK_COMMAND, true)).is_none());
+        assert!(command(&message(LOCK_COMMAND, true)).is_none());
+    }
+}
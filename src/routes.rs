@@ -1,16 +1,28 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{
     Form,
-    extract::{Query, State},
+    extract::{ConnectInfo, Query, State},
     response::{Html, Redirect},
 };
 use serde::Deserialize;
 use tokio::sync::mpsc::Sender;
+use tracing::{error, info};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc, Weekday};
 
 use crate::{
-    auth::verify_password,
-    lock::{InstructionSource, LockInstruction, LockInstructor},
+    audit::{self, AuditEntry, AuditQuery, AuditRecord},
+    auth::authorize,
+    guest::{self, GuestCode, NewGuestCode, Schedule},
+    lock::{
+        InstructionSource, InstructionStatus, LockInstruction, LockInstructor, QueuedInstruction,
+    },
+    throttle::{self, GuardedVerifyError},
 };
 
 #[derive(Deserialize)]
@@ -31,6 +43,10 @@ pub async fn home(Query(params): Query<HashMap<String, String>>) -> Html<String>
         match error.as_str() {
             "invalid_password" => format_err_message("Invalid password. Please try again."),
             "in_use" => format_err_message("Lock is in use. Please try again later."),
+            "not_allowed" => format_err_message("That code can't do that."),
+            "locked_out" => format_err_message(
+                "Too many failed attempts. Locked out for now, please try again later.",
+            ),
             "internal_error" => format_err_message(
                 "Internal service issue. Please try again. Service may need to be restarted",
             ),
@@ -98,6 +114,10 @@ pub async fn home(Query(params): Query<HashMap<String, String>>) -> Html<String>
                     button.unlock {{
                         background-color: #4CAF50;
                     }}
+                    button.hold {{
+                        margin-top: 10px;
+                        background-color: #2E7D32;
+                    }}
                     button.lock {{
                         margin-top: 10px;
                         background-color: #222222;
@@ -112,6 +132,7 @@ pub async fn home(Query(params): Query<HashMap<String, String>>) -> Html<String>
                     <form action="/door-control" method="post">
                         <input type="password" name="passcode" placeholder="Enter Passcode" required>
                         <button class="unlock" type="submit" name="action" value="unlock">Unlock Door</button>
+                        <button class="hold" type="submit" name="action" value="hold_open">Unlock &amp; Hold Open</button>
                         <button class="lock" type="submit" name="action" value="lock">Lock Door</button>
                     </form>
                 </div>
@@ -122,33 +143,691 @@ pub async fn home(Query(params): Query<HashMap<String, String>>) -> Html<String>
 }
 
 pub async fn door_control(
-    State(lock_tx): State<Arc<Sender<LockInstruction>>>,
+    State(lock_tx): State<Arc<Sender<QueuedInstruction>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Form(form): Form<LockRequest>,
 ) -> Redirect {
-    let instruction = match form.action.as_str() {
-        "lock" => Some(LockInstruction::EnsureLocked(InstructionSource::Api)),
-        "unlock" => Some(LockInstruction::EnsureUnlocked(InstructionSource::Api)),
-        _ => None,
+    let instruction: fn(InstructionSource) -> LockInstruction = match form.action.as_str() {
+        "lock" => LockInstruction::EnsureLocked,
+        "unlock" => LockInstruction::EnsureUnlocked,
+        "hold_open" => LockInstruction::HoldOpen,
+        _ => return Redirect::to("/home?error=wtf_was_that"),
     };
-    if instruction.is_none() {
-        return Redirect::to("/home?error=wtf_was_that");
-    }
-    match verify_password(form.passcode.as_str()).await {
-        Ok(true) => {
-            if let Err(e) = lock_tx.send_instruction(instruction.expect("already checked none")) {
-                println!("Dropping instruction. {}", e);
+    match throttle::verify(addr.ip(), form.passcode.as_str()).await {
+        Ok(Some(principal)) => {
+            info!("Passcode accepted for {}", principal);
+            let instruction = instruction(InstructionSource::Api {
+                principal: principal.clone(),
+                client_ip: addr.ip(),
+            });
+            match authorize(&principal, &instruction).await {
+                Ok(true) => {}
+                Ok(false) => return Redirect::to("/home?error=not_allowed"),
+                Err(e) => {
+                    error!("Could not authorize {}. {:?}", principal, e);
+                    return Redirect::to("/home?error=internal_error");
+                }
+            }
+            if let Err(e) = lock_tx.send_instruction(instruction).await {
+                info!("Dropping instruction. {}", e);
                 return Redirect::to("/home?error=in_use");
             }
 
             Redirect::to("/home?success")
         }
-        Ok(false) => {
-            println!("Bad password entered");
+        Ok(None) => {
+            info!(client_ip = %addr.ip(), "Bad password entered");
             Redirect::to("/home?error=invalid_password")
         }
-        Err(e) => {
-            eprintln!("argon issue with hashed password {:?}", e);
+        Err(GuardedVerifyError::Throttled(throttled)) => {
+            info!("Refusing attempt from {}. {}", addr.ip(), throttled);
+            Redirect::to("/home?error=locked_out")
+        }
+        Err(GuardedVerifyError::Internal(e)) => {
+            error!("argon issue with hashed password {:?}", e);
             Redirect::to("/home?error=internal_error")
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct GuestCodeRequest {
+    pub admin_passcode: String,
+    pub name: String,
+    pub code: String,
+    pub valid_from: String,  // datetime-local, so local time without a zone
+    pub valid_until: String, // same
+    pub max_uses: String,
+    pub weekdays: String, // comma separated, e.g. "mon,wed,fri". empty means no schedule
+    pub start_hour: String,
+    pub end_hour: String,
+    pub unlock_only: Option<String>, // checkbox, only sent when ticked
+}
+
+#[derive(Deserialize)]
+pub struct AdminPasscodeRequest {
+    pub admin_passcode: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeGuestCodeRequest {
+    pub admin_passcode: String,
+    pub name: String,
+}
+
+enum AdminCheck {
+    Admin,
+    NotAdmin,
+    LockedOut,
+    Internal,
+}
+
+async fn verify_admin(ip: IpAddr, passcode: &str) -> AdminCheck {
+    match throttle::verify_admin(ip, passcode).await {
+        Ok(Some(principal)) if principal.is_admin() => AdminCheck::Admin,
+        Ok(_) => AdminCheck::NotAdmin,
+        Err(GuardedVerifyError::Throttled(throttled)) => {
+            info!("Refusing admin attempt from {ip}. {throttled}");
+            AdminCheck::LockedOut
+        }
+        Err(GuardedVerifyError::Internal(e)) => {
+            error!("argon issue with hashed password {:?}", e);
+            AdminCheck::Internal
+        }
+    }
+}
+
+fn parse_local_datetime(s: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M")?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|local| local.with_timezone(&Utc))
+        .ok_or_else(|| anyhow::anyhow!("{s} doesn't exist in local time"))
+}
+
+fn parse_guest_code(form: &GuestCodeRequest) -> Result<NewGuestCode, anyhow::Error> {
+    let max_uses = match form.max_uses.trim() {
+        "" => None,
+        n => Some(n.parse()?),
+    };
+    let schedule = match form.weekdays.trim() {
+        "" => None,
+        days => Some(Schedule {
+            weekdays: days
+                .split(',')
+                .map(|d| d.trim().parse::<Weekday>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| anyhow::anyhow!("bad weekday in '{days}'"))?,
+            start_hour: form.start_hour.trim().parse()?,
+            end_hour: form.end_hour.trim().parse()?,
+        }),
+    };
+    Ok(NewGuestCode {
+        name: form.name.trim().to_string(),
+        code: form.code.clone(),
+        valid_from: parse_local_datetime(&form.valid_from)?,
+        valid_until: parse_local_datetime(&form.valid_until)?,
+        max_uses,
+        schedule,
+        unlock_only: form.unlock_only.is_some(),
+    })
+}
+
+pub async fn guest_codes(Query(params): Query<HashMap<String, String>>) -> Html<String> {
+    let message = match (params.get("error"), params.get("success")) {
+        (Some(error), _) => match error.as_str() {
+            "not_admin" => format_err_message("That is not an admin passcode."),
+            "locked_out" => format_err_message("Too many failed attempts. Try again later."),
+            "invalid" => format_err_message("Could not create that code. Check the fields."),
+            "internal_error" => format_err_message("Internal service issue. Please try again."),
+            _ => String::new(),
+        },
+        (None, Some(_)) => "<p style='color: green;'>Success!</p>".to_string(),
+        _ => String::new(),
+    };
+    guest_codes_page(message, None)
+}
+
+// a post so the admin passcode stays out of urls and logs, same as /history
+pub async fn list_guest_codes(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<AdminPasscodeRequest>,
+) -> Html<String> {
+    let error = |message: &str| guest_codes_page(format_err_message(message), None);
+    match verify_admin(addr.ip(), &form.admin_passcode).await {
+        AdminCheck::Admin => {}
+        AdminCheck::NotAdmin => return error("That is not an admin passcode."),
+        AdminCheck::LockedOut => return error("Too many failed attempts. Try again later."),
+        AdminCheck::Internal => return error("Internal service issue. Please try again."),
+    }
+    guest_codes_page(String::new(), Some(&guest::list_codes().await))
+}
+
+// codes are only listed once an admin passcode has been checked
+fn guest_codes_page(message: String, codes: Option<&[GuestCode]>) -> Html<String> {
+    let now = Utc::now();
+    let rows: String = codes
+        .unwrap_or_default()
+        .iter()
+        .map(|code| {
+            let schedule = match &code.schedule {
+                Some(s) => format!(
+                    "{} {}:00-{}:00",
+                    s.weekdays
+                        .iter()
+                        .map(|d| d.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    s.start_hour,
+                    s.end_hour
+                ),
+                None => "any time".to_string(),
+            };
+            format!(
+                "<tr><td>{}</td><td>{} to {}</td><td>{}/{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&code.name),
+                code.valid_from.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                code.valid_until.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                code.uses.len(),
+                code.max_uses.map_or("∞".to_string(), |m| m.to_string()),
+                schedule,
+                if code.unlock_only { "unlock only" } else { "lock + unlock" },
+                if code.is_usable_at(now) { "active" } else { "inactive" },
+            )
+        })
+        .collect();
+    let listing = match codes {
+        Some(_) => format!(
+            r#"
+                <table>
+                    <tr><th>Name</th><th>Valid</th><th>Uses</th><th>Schedule</th><th>Allowed</th><th>Status</th></tr>
+                    {rows}
+                </table>"#
+        ),
+        None => r#"
+                <form action="/guest-codes/list" method="post">
+                    <h3>Existing codes</h3>
+                    <input type="password" name="admin_passcode" placeholder="Admin passcode" required>
+                    <button type="submit">Show</button>
+                </form>"#
+            .to_string(),
+    };
+
+    Html(format!(
+        r#"
+            <!DOCTYPE html>
+            <html>
+            <head>
+                <title>Guest Codes</title>
+                <meta name="viewport" content="width=device-width, initial-scale=1.0">
+                <style>
+                    body {{
+                        font-family: Arial, sans-serif;
+                        margin: 20px;
+                        background-color: #f5f5f5;
+                    }}
+                    table {{
+                        border-collapse: collapse;
+                        margin-bottom: 20px;
+                    }}
+                    td, th {{
+                        border: 1px solid #ddd;
+                        padding: 6px;
+                    }}
+                    form {{
+                        background: white;
+                        padding: 20px;
+                        border-radius: 10px;
+                        max-width: 400px;
+                        margin-bottom: 20px;
+                    }}
+                    input {{
+                        width: 100%;
+                        padding: 8px;
+                        margin-bottom: 10px;
+                        box-sizing: border-box;
+                    }}
+                </style>
+            </head>
+            <body>
+                <h2>Guest Codes</h2>
+                {message}
+                {listing}
+                <form action="/guest-codes" method="post">
+                    <h3>New code</h3>
+                    <input type="text" name="name" placeholder="Name, e.g. dog walker" required>
+                    <input type="password" name="code" placeholder="Guest code" required>
+                    <label>Valid from</label>
+                    <input type="datetime-local" name="valid_from" required>
+                    <label>Valid until</label>
+                    <input type="datetime-local" name="valid_until" required>
+                    <input type="number" name="max_uses" placeholder="Max uses (blank for unlimited)" min="1">
+                    <input type="text" name="weekdays" placeholder="Days, e.g. mon,wed,fri (blank for any)">
+                    <input type="number" name="start_hour" placeholder="From hour (0-23)" min="0" max="23">
+                    <input type="number" name="end_hour" placeholder="Until hour (1-24)" min="1" max="24">
+                    <label><input type="checkbox" name="unlock_only" style="width: auto;" checked> Unlock only</label>
+                    <input type="password" name="admin_passcode" placeholder="Admin passcode" required>
+                    <button type="submit">Create</button>
+                </form>
+                <form action="/guest-codes/revoke" method="post">
+                    <h3>Revoke code</h3>
+                    <input type="text" name="name" placeholder="Name" required>
+                    <input type="password" name="admin_passcode" placeholder="Admin passcode" required>
+                    <button type="submit">Revoke</button>
+                </form>
+            </body>
+            </html>
+        "#,
+    ))
+}
+
+pub async fn create_guest_code(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<GuestCodeRequest>,
+) -> Redirect {
+    match verify_admin(addr.ip(), &form.admin_passcode).await {
+        AdminCheck::Admin => {}
+        AdminCheck::NotAdmin => return Redirect::to("/guest-codes?error=not_admin"),
+        AdminCheck::LockedOut => return Redirect::to("/guest-codes?error=locked_out"),
+        AdminCheck::Internal => return Redirect::to("/guest-codes?error=internal_error"),
+    }
+    let created = match parse_guest_code(&form) {
+        Ok(new) => guest::create_code(new).await,
+        Err(e) => Err(e),
+    };
+    match created {
+        Ok(()) => Redirect::to("/guest-codes?success"),
+        Err(e) => {
+            info!("Guest code '{}' not created. {}", form.name, e);
+            Redirect::to("/guest-codes?error=invalid")
+        }
+    }
+}
+
+pub async fn revoke_guest_code(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<RevokeGuestCodeRequest>,
+) -> Redirect {
+    match verify_admin(addr.ip(), &form.admin_passcode).await {
+        AdminCheck::Admin => {}
+        AdminCheck::NotAdmin => return Redirect::to("/guest-codes?error=not_admin"),
+        AdminCheck::LockedOut => return Redirect::to("/guest-codes?error=locked_out"),
+        AdminCheck::Internal => return Redirect::to("/guest-codes?error=internal_error"),
+    }
+    match guest::revoke_code(form.name.trim()).await {
+        Ok(()) => Redirect::to("/guest-codes?success"),
+        Err(e) => {
+            info!("{}", e);
+            Redirect::to("/guest-codes?error=invalid")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ClearLockoutRequest {
+    pub admin_passcode: String,
+    pub ip: String, // blank clears every client and the global lockout
+}
+
+pub async fn lockouts(Query(params): Query<HashMap<String, String>>) -> Html<String> {
+    let message = match (params.get("error"), params.get("success")) {
+        (Some(error), _) => match error.as_str() {
+            "not_admin" => format_err_message("That is not an admin passcode."),
+            "locked_out" => format_err_message("Too many failed attempts. Try again later."),
+            "invalid" => format_err_message("That is not an IP address."),
+            "internal_error" => format_err_message("Internal service issue. Please try again."),
+            _ => String::new(),
+        },
+        (None, Some(_)) => "<p style='color: green;'>Cleared!</p>".to_string(),
+        _ => String::new(),
+    };
+    lockouts_page(message, None)
+}
+
+// a post so the admin passcode stays out of urls and logs, same as /history
+pub async fn list_lockouts(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<AdminPasscodeRequest>,
+) -> Html<String> {
+    let error = |message: &str| lockouts_page(format_err_message(message), None);
+    match verify_admin(addr.ip(), &form.admin_passcode).await {
+        AdminCheck::Admin => {}
+        AdminCheck::NotAdmin => return error("That is not an admin passcode."),
+        AdminCheck::LockedOut => return error("Too many failed attempts. Try again later."),
+        AdminCheck::Internal => return error("Internal service issue. Please try again."),
+    }
+    lockouts_page(String::new(), Some(&throttle::snapshot()))
+}
+
+// which clients are being throttled is only shown once an admin passcode has been checked
+fn lockouts_page(message: String, state: Option<&throttle::ThrottleState>) -> Html<String> {
+    let row = |who: String, record: &throttle::FailureRecord| {
+        format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            who,
+            record.failures,
+            record.blocked_until.map_or("-".to_string(), |until| until
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()),
+            if record.locked_out { "locked out" } else { "" },
+        )
+    };
+    let listing = match state {
+        Some(state) => {
+            let mut rows = row("everyone".to_string(), &state.global);
+            for (ip, record) in state.clients.iter() {
+                rows.push_str(&row(ip.to_string(), record));
+            }
+            format!(
+                r#"
+                <table>
+                    <tr><th>Client</th><th>Failures</th><th>Blocked until (UTC)</th><th></th></tr>
+                    {rows}
+                </table>"#
+            )
+        }
+        None => r#"
+                <form action="/lockouts/list" method="post">
+                    <h3>Failed attempts</h3>
+                    <input type="password" name="admin_passcode" placeholder="Admin passcode" required>
+                    <button type="submit">Show</button>
+                </form>"#
+            .to_string(),
+    };
+
+    Html(format!(
+        r#"
+            <!DOCTYPE html>
+            <html>
+            <head>
+                <title>Lockouts</title>
+                <meta name="viewport" content="width=device-width, initial-scale=1.0">
+                <style>
+                    body {{
+                        font-family: Arial, sans-serif;
+                        margin: 20px;
+                        background-color: #f5f5f5;
+                    }}
+                    table {{
+                        border-collapse: collapse;
+                        margin-bottom: 20px;
+                    }}
+                    td, th {{
+                        border: 1px solid #ddd;
+                        padding: 6px;
+                    }}
+                    form {{
+                        background: white;
+                        padding: 20px;
+                        border-radius: 10px;
+                        max-width: 400px;
+                        margin-bottom: 20px;
+                    }}
+                    input {{
+                        width: 100%;
+                        padding: 8px;
+                        margin-bottom: 10px;
+                        box-sizing: border-box;
+                    }}
+                </style>
+            </head>
+            <body>
+                <h2>Failed Attempts</h2>
+                {message}
+                {listing}
+                <form action="/lockouts/clear" method="post">
+                    <h3>Clear lockout</h3>
+                    <input type="text" name="ip" placeholder="Client IP (blank clears everyone)">
+                    <input type="password" name="admin_passcode" placeholder="Admin passcode" required>
+                    <button type="submit">Clear</button>
+                </form>
+            </body>
+            </html>
+        "#,
+    ))
+}
+
+pub async fn clear_lockout(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<ClearLockoutRequest>,
+) -> Redirect {
+    let ip = match form.ip.trim() {
+        "" => None,
+        ip => match ip.parse::<IpAddr>() {
+            Ok(ip) => Some(ip),
+            Err(_) => return Redirect::to("/lockouts?error=invalid"),
+        },
+    };
+    match verify_admin(addr.ip(), &form.admin_passcode).await {
+        AdminCheck::Admin => {}
+        AdminCheck::NotAdmin => return Redirect::to("/lockouts?error=not_admin"),
+        AdminCheck::LockedOut => return Redirect::to("/lockouts?error=locked_out"),
+        AdminCheck::Internal => return Redirect::to("/lockouts?error=internal_error"),
+    }
+    throttle::clear(ip);
+    Redirect::to("/lockouts?success")
+}
+
+#[derive(Deserialize, Default)]
+pub struct HistoryRequest {
+    pub admin_passcode: String,
+    #[serde(default)]
+    pub since: String, // datetime-local, blank for no bound
+    #[serde(default)]
+    pub until: String,
+    #[serde(default)]
+    pub source: String, // blank for any
+    #[serde(default)]
+    pub user: String,
+}
+
+// audit entries carry names people chose, keep them from turning into markup
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn parse_history_query(form: &HistoryRequest) -> Result<AuditQuery, anyhow::Error> {
+    let bound = |s: &str| match s.trim() {
+        "" => Ok(None),
+        s => parse_local_datetime(s).map(Some),
+    };
+    let filter = |s: &str| Some(s.trim().to_string()).filter(|s| !s.is_empty());
+    Ok(AuditQuery {
+        since: bound(&form.since)?,
+        until: bound(&form.until)?,
+        source: filter(&form.source),
+        user: filter(&form.user),
+        limit: None,
+    })
+}
+
+fn describe_outcome(outcome: &InstructionStatus) -> String {
+    match outcome {
+        InstructionStatus::Queued { position } => format!("queued at {position}"),
+        InstructionStatus::Rejected => "rejected, queue full".to_string(),
+        InstructionStatus::Executed { action } => format!("{action:?}"),
+        InstructionStatus::NoOp => "nothing to do".to_string(),
+        InstructionStatus::Coalesced { into } => format!("merged into #{into}"),
+        InstructionStatus::Failed { reason } => format!("failed. {reason}"),
+        InstructionStatus::Refused { reason } => format!("refused. {reason}"),
+        InstructionStatus::Marked { state } => format!("marked {}", state.name()),
+    }
+}
+
+fn history_row(record: &AuditRecord) -> String {
+    let (what, outcome, client_ip) = match &record.entry {
+        AuditEntry::Instruction {
+            id,
+            instruction,
+            client_ip,
+            outcome,
+            duration_ms,
+            ..
+        } => (
+            format!("#{id} {instruction}"),
+            format!("{} ({duration_ms} ms)", describe_outcome(outcome)),
+            *client_ip,
+        ),
+        AuditEntry::Denied {
+            instruction,
+            client_ip,
+            ..
+        } => (instruction.clone(), "denied".to_string(), *client_ip),
+        AuditEntry::AuthFailed {
+            client_ip,
+            credential,
+        } => (
+            "sign in".to_string(),
+            format!("bad {credential}"),
+            Some(*client_ip),
+        ),
+        AuditEntry::Throttled {
+            client_ip,
+            until,
+            locked_out,
+        } => (
+            "sign in".to_string(),
+            match locked_out {
+                true => "locked out".to_string(),
+                false => format!("throttled until {}", until.format("%H:%M:%S")),
+            },
+            Some(*client_ip),
+        ),
+    };
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        record.at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+        record.entry.source(),
+        escape_html(record.entry.user().unwrap_or("-")),
+        client_ip.map_or("-".to_string(), |ip| ip.to_string()),
+        escape_html(&what),
+        escape_html(&outcome),
+    )
+}
+
+fn history_page(form: &HistoryRequest, message: String, records: &[AuditRecord]) -> Html<String> {
+    let rows: String = records.iter().rev().map(history_row).collect();
+    let source_options: String = [
+        "",
+        "button",
+        "api",
+        "auto_sensor",
+        "auto_lock",
+        "control_socket",
+        "mqtt",
+    ]
+    .iter()
+    .map(|source| {
+        format!(
+            "<option value=\"{source}\"{}>{}</option>",
+            if *source == form.source {
+                " selected"
+            } else {
+                ""
+            },
+            if source.is_empty() {
+                "any source"
+            } else {
+                source
+            },
+        )
+    })
+    .collect();
+
+    Html(format!(
+        r#"
+            <!DOCTYPE html>
+            <html>
+            <head>
+                <title>History</title>
+                <meta name="viewport" content="width=device-width, initial-scale=1.0">
+                <style>
+                    body {{
+                        font-family: Arial, sans-serif;
+                        margin: 20px;
+                        background-color: #f5f5f5;
+                    }}
+                    table {{
+                        border-collapse: collapse;
+                        margin-top: 20px;
+                    }}
+                    td, th {{
+                        border: 1px solid #ddd;
+                        padding: 6px;
+                    }}
+                    form {{
+                        background: white;
+                        padding: 20px;
+                        border-radius: 10px;
+                        max-width: 400px;
+                    }}
+                    input, select {{
+                        width: 100%;
+                        padding: 8px;
+                        margin-bottom: 10px;
+                        box-sizing: border-box;
+                    }}
+                </style>
+            </head>
+            <body>
+                <h2>History</h2>
+                {message}
+                <form action="/history" method="post">
+                    <label>From</label>
+                    <input type="datetime-local" name="since" value="{since}">
+                    <label>Until</label>
+                    <input type="datetime-local" name="until" value="{until}">
+                    <select name="source">{source_options}</select>
+                    <input type="text" name="user" placeholder="User, token or guest code (blank for anyone)" value="{user}">
+                    <input type="password" name="admin_passcode" placeholder="Admin passcode" required>
+                    <button type="submit">Show</button>
+                </form>
+                <table>
+                    <tr><th>When</th><th>Source</th><th>User</th><th>Client</th><th>What</th><th>Outcome</th></tr>
+                    {rows}
+                </table>
+            </body>
+            </html>
+        "#,
+        since = escape_html(&form.since),
+        until = escape_html(&form.until),
+        user = escape_html(&form.user),
+    ))
+}
+
+pub async fn history() -> Html<String> {
+    history_page(&HistoryRequest::default(), String::new(), &[])
+}
+
+// a post so the admin passcode stays out of urls and logs. newest at the top
+pub async fn search_history(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<HistoryRequest>,
+) -> Html<String> {
+    let error = |message: &str| history_page(&form, format_err_message(message), &[]);
+    match verify_admin(addr.ip(), &form.admin_passcode).await {
+        AdminCheck::Admin => {}
+        AdminCheck::NotAdmin => return error("That is not an admin passcode."),
+        AdminCheck::LockedOut => return error("Too many failed attempts. Try again later."),
+        AdminCheck::Internal => return error("Internal service issue. Please try again."),
+    }
+    let query = match parse_history_query(&form) {
+        Ok(query) => query,
+        Err(e) => {
+            info!("{}", e);
+            return error("Those dates don't make sense.");
+        }
+    };
+    match audit::query(query).await {
+        Ok(records) => history_page(&form, String::new(), &records),
+        Err(e) => {
+            error!("Could not read the audit log. {e}");
+            error("Internal service issue. Please try again.")
+        }
+    }
+}
@@ -1,35 +1,30 @@
-#[cfg(not(feature = "hardware"))]
-mod mock;
-
-#[cfg(not(feature = "hardware"))]
-mod gpio {
-    pub use super::mock::{MockGpio as Gpio, MockInputPin as InputPin, MockOutputPin as OutputPin};
-}
-
+mod hal;
 #[cfg(feature = "hardware")]
-mod gpio {
-    pub use rppal::gpio::{Gpio, InputPin, OutputPin};
-}
+mod hardware;
+mod mock;
+pub mod simulator;
 
-use gpio::{Gpio, InputPin, OutputPin};
+pub use hal::{GpioProvider, InputLine, OutputLine, Pull, gpio, init};
+pub use mock::MockGpio;
+pub use simulator::Simulator;
 
 use more_asserts::assert_ge;
 use std::{
     error::Error,
     fmt::Display,
-    thread,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicI64, Ordering},
+    },
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
-use tokio::time::sleep;
+use tracing::{debug, error, trace};
 
-const LED_PINS: [u8; 2] = [17, 22];
-const MOTOR_DIR_PIN: u8 = 23;
-const MOTOR_STEP_PIN: u8 = 24;
-const MOTOR_ENABLE_PIN: u8 = 18;
-const MOTOR_SLEEP_PIN: u8 = 4;
-const BUTTON_PIN: u8 = 21;
-const ULTRASONIC_TRIGGER_PIN: u8 = 16;
-const ULTRASONIC_ECHO_PIN: u8 = 20;
+use crate::{
+    clock::SharedClock,
+    config::{GpioConfig, config},
+};
 
 #[derive(Debug)]
 pub enum LEDState {
@@ -37,15 +32,18 @@ pub enum LEDState {
     Off,
 }
 
-pub struct LED {
-    pin: OutputPin,
+pub struct LED<O = Box<dyn OutputLine>> {
+    pin: O,
 }
 
 impl LED {
-    pub fn new(pin: u8) -> Self {
-        assert!(LED_PINS.contains(&pin));
-        let gpio = Gpio::new().unwrap();
-        let pin = gpio.get(pin).unwrap().into_output();
+    pub fn from_gpio(gpio: &dyn GpioProvider, pin: u8) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(gpio.output(pin)?))
+    }
+}
+
+impl<O: OutputLine> LED<O> {
+    pub fn new(pin: O) -> Self {
         Self { pin }
     }
 
@@ -62,6 +60,9 @@ impl LED {
     }
 }
 
+// the driver can't step any faster than this
+pub const MIN_STEP_DELAY: Duration = Duration::from_millis(3);
+
 const CCW_STEP: [u8; 4] = [0b0001, 0b0010, 0b0100, 0b1000];
 const CW_STEP: [u8; 4] = [0b1000, 0b0100, 0b0010, 0b0001];
 
@@ -80,21 +81,38 @@ impl From<MotorDirection> for [u8; 4] {
     }
 }
 
-pub struct StepMotor {
-    pub dir_pin: OutputPin,
-    pub step_pin: OutputPin,
-    enable_pin: OutputPin,
-    sleep_pin: OutputPin,
+pub struct StepMotor<O = Box<dyn OutputLine>> {
+    pub dir_pin: O,
+    pub step_pin: O,
+    enable_pin: O,
+    sleep_pin: O,
+    clock: SharedClock,
 }
 
 impl StepMotor {
-    pub fn new() -> Self {
-        let gpio = Gpio::new().unwrap();
+    pub fn from_gpio(
+        gpio: &dyn GpioProvider,
+        pins: &GpioConfig,
+        clock: SharedClock,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(
+            gpio.output(pins.motor_dir_pin)?,
+            gpio.output(pins.motor_step_pin)?,
+            gpio.output(pins.motor_enable_pin)?,
+            gpio.output(pins.motor_sleep_pin)?,
+            clock,
+        ))
+    }
+}
+
+impl<O: OutputLine> StepMotor<O> {
+    pub fn new(dir_pin: O, step_pin: O, enable_pin: O, sleep_pin: O, clock: SharedClock) -> Self {
         let mut t = Self {
-            dir_pin: gpio.get(MOTOR_DIR_PIN).unwrap().into_output(),
-            step_pin: gpio.get(MOTOR_STEP_PIN).unwrap().into_output(),
-            enable_pin: gpio.get(MOTOR_ENABLE_PIN).unwrap().into_output(),
-            sleep_pin: gpio.get(MOTOR_SLEEP_PIN).unwrap().into_output(),
+            dir_pin,
+            step_pin,
+            enable_pin,
+            sleep_pin,
+            clock,
         };
 
         t.step_pin.set_low();
@@ -104,16 +122,16 @@ impl StepMotor {
 
     pub fn activate(&mut self) {
         self.sleep_pin.set_high();
-        std::thread::sleep(Duration::from_millis(3));
+        self.clock.block_for(Duration::from_millis(3));
         self.enable_pin.set_low();
-        std::thread::sleep(Duration::from_millis(3));
+        self.clock.block_for(Duration::from_millis(3));
     }
 
     pub fn deactivate(&mut self) {
         self.sleep_pin.set_low();
-        std::thread::sleep(Duration::from_millis(3));
+        self.clock.block_for(Duration::from_millis(3));
         self.enable_pin.set_high();
-        std::thread::sleep(Duration::from_millis(3));
+        self.clock.block_for(Duration::from_millis(3));
     }
 
     pub fn set_direction(&mut self, direction: MotorDirection) {
@@ -121,45 +139,192 @@ impl StepMotor {
             MotorDirection::Clockwise => self.dir_pin.set_high(),
             MotorDirection::CounterClockwise => self.dir_pin.set_low(),
         }
-        std::thread::sleep(Duration::from_millis(5)); // thinking maybe the lock is moving before dir is set
+        self.clock.block_for(Duration::from_millis(5)); // thinking maybe the lock is moving before dir is set
     }
 
     pub fn take_step(&mut self, step_delay: Duration) {
-        assert_ge!(step_delay.as_millis(), 3);
+        assert_ge!(step_delay, MIN_STEP_DELAY);
         self.step_pin.set_high();
-        std::thread::sleep(Duration::from_micros(50)); //at least 1.9us
+        self.clock.block_for(Duration::from_micros(50)); //at least 1.9us
         self.step_pin.set_low();
-        std::thread::sleep(step_delay);
+        self.clock.block_for(step_delay);
     }
 }
 
-pub struct Button {
-    pin: InputPin,
+// a switch to ground at one end of the bolt's travel, so closed reads low.
+// the motor thread and the idle check both read it, so clones share the pin
+pub struct EndStop<I = Box<dyn InputLine>> {
+    pin: Arc<Mutex<I>>,
 }
 
-impl Button {
-    pub fn new() -> Self {
-        let gpio = Gpio::new().unwrap();
+impl<I> Clone for EndStop<I> {
+    fn clone(&self) -> Self {
+        Self {
+            pin: Arc::clone(&self.pin),
+        }
+    }
+}
+
+impl EndStop {
+    pub fn from_gpio(gpio: &dyn GpioProvider, pin: u8) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(gpio.input(pin, Pull::Up)?))
+    }
+}
+
+impl<I: InputLine> EndStop<I> {
+    pub fn new(pin: I) -> Self {
+        Self {
+            pin: Arc::new(Mutex::new(pin)),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.pin.lock().unwrap().is_low()
+    }
+}
+
+// whichever end-stops are fitted, by the direction that runs the bolt into them
+pub struct EndStops<I = Box<dyn InputLine>> {
+    pub clockwise: Option<EndStop<I>>,
+    pub counter_clockwise: Option<EndStop<I>>,
+}
+
+impl<I> Clone for EndStops<I> {
+    fn clone(&self) -> Self {
         Self {
-            pin: gpio.get(BUTTON_PIN).unwrap().into_input_pullup(),
+            clockwise: self.clockwise.clone(),
+            counter_clockwise: self.counter_clockwise.clone(),
+        }
+    }
+}
+
+impl<I: InputLine> EndStops<I> {
+    pub fn towards(&self, direction: &MotorDirection) -> Option<&EndStop<I>> {
+        match direction {
+            MotorDirection::Clockwise => self.clockwise.as_ref(),
+            MotorDirection::CounterClockwise => self.counter_clockwise.as_ref(),
+        }
+    }
+}
+
+// fast enough to see every phase of an encoder on the bolt at any speed the motor manages
+const ENCODER_POLL: Duration = Duration::from_micros(200);
+// counting up goes 00 -> 01 -> 11 -> 10 and around again
+const QUADRATURE_ORDER: [u8; 4] = [0b00, 0b01, 0b11, 0b10];
+
+// an incremental encoder on A/B, sampled on its own thread so nothing between two polls of ours
+// gets missed. on real time like the ultrasonic sensor, it's watching the outside world
+pub struct QuadratureEncoder {
+    count: Arc<AtomicI64>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl QuadratureEncoder {
+    pub fn from_gpio(gpio: &dyn GpioProvider, (a, b): (u8, u8)) -> Result<Self, anyhow::Error> {
+        Self::spawn(gpio.input(a, Pull::Up)?, gpio.input(b, Pull::Up)?)
+    }
+
+    pub fn spawn<I: InputLine + 'static>(a: I, b: I) -> Result<Self, anyhow::Error> {
+        let count = Arc::new(AtomicI64::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = thread::Builder::new().name("encoder".to_string()).spawn({
+            let (count, running) = (Arc::clone(&count), Arc::clone(&running));
+            move || sample_encoder(a, b, &count, &running)
+        })?;
+        Ok(Self {
+            count,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    // counts since it was spawned. only differences between two readings mean anything
+    pub fn count(&self) -> i64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+fn encoder_phase(a: &impl InputLine, b: &impl InputLine) -> usize {
+    let bits = (u8::from(a.is_high()) << 1) | u8::from(b.is_high());
+    QUADRATURE_ORDER
+        .iter()
+        .position(|&phase| phase == bits)
+        .expect("every two bits are a phase")
+}
+
+fn sample_encoder(a: impl InputLine, b: impl InputLine, count: &AtomicI64, running: &AtomicBool) {
+    let mut last = encoder_phase(&a, &b);
+    while running.load(Ordering::Relaxed) {
+        let phase = encoder_phase(&a, &b);
+        match (phase + 4 - last) % 4 {
+            1 => {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+            3 => {
+                count.fetch_sub(1, Ordering::Relaxed);
+            }
+            2 => trace!("Encoder skipped a phase, no telling which way"),
+            _ => {}
+        }
+        last = phase;
+        thread::sleep(ENCODER_POLL);
+    }
+}
+
+impl Drop for QuadratureEncoder {
+    // its pins are free again once this returns
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            error!("Encoder thread had panicked");
         }
     }
+}
+
+// wired to ground, so pressed reads low
+pub struct Button<I = Box<dyn InputLine>> {
+    pin: I,
+    clock: SharedClock,
+}
+
+impl Button {
+    pub fn from_gpio(
+        gpio: &dyn GpioProvider,
+        pin: u8,
+        clock: SharedClock,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(gpio.input(pin, Pull::Up)?, clock))
+    }
+}
+
+impl<I: InputLine> Button<I> {
+    pub fn new(pin: I, clock: SharedClock) -> Self {
+        Self { pin, clock }
+    }
+
     pub async fn check_is_pressed_debounced(&self) -> bool {
         if self.pin.is_high() {
             return false;
         };
-        sleep(Duration::from_millis(50)).await;
+        self.clock
+            .sleep(Duration::from_millis(config().sensor.button_debounce_ms))
+            .await;
         if self.pin.is_high() {
             return false;
         }
-        println!("good, returning true");
+        debug!("Button press passed debounce");
         true
     }
 }
 
-pub struct UltrasonicSensor {
-    pub trigger_pin: OutputPin,
-    pub echo_pin: InputPin,
+// times the echo pulse on the wire with real Instants, whatever clock the rest runs on.
+// a virtual clock standing still mid-measurement would never see the echo end
+pub struct UltrasonicSensor<O = Box<dyn OutputLine>, I = Box<dyn InputLine>> {
+    pub trigger_pin: O,
+    pub echo_pin: I,
 }
 
 #[derive(Debug, Clone)]
@@ -205,11 +370,19 @@ impl Display for ReadEchoError {
 impl Error for ReadEchoError {}
 
 impl UltrasonicSensor {
-    pub fn new() -> Self {
-        let gpio = Gpio::new().unwrap();
+    pub fn from_gpio(gpio: &dyn GpioProvider, pins: &GpioConfig) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(
+            gpio.output(pins.ultrasonic_trigger_pin)?,
+            gpio.input(pins.ultrasonic_echo_pin, Pull::Off)?,
+        ))
+    }
+}
+
+impl<O: OutputLine, I: InputLine> UltrasonicSensor<O, I> {
+    pub fn new(trigger_pin: O, echo_pin: I) -> Self {
         Self {
-            trigger_pin: gpio.get(ULTRASONIC_TRIGGER_PIN).unwrap().into_output(),
-            echo_pin: gpio.get(ULTRASONIC_ECHO_PIN).unwrap().into_input(),
+            trigger_pin,
+            echo_pin,
         }
     }
     fn send_trigger(&mut self, micros: u64) {
@@ -225,7 +398,7 @@ impl UltrasonicSensor {
         while self.echo_pin.is_low() {
             if pre.elapsed() > timeout {
                 // timeout reached
-                println!("read echo timed out");
+                trace!("read echo timed out");
                 return Err(ReadEchoError::Timeout);
             }
         }
@@ -242,21 +415,3 @@ impl UltrasonicSensor {
         Ok(SonicDistance::from(echo_time))
     }
 }
-
-impl Default for Button {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl Default for StepMotor {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl Default for UltrasonicSensor {
-    fn default() -> Self {
-        Self::new()
-    }
-}
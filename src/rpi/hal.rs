@@ -0,0 +1,77 @@
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+
+use crate::config::GpioBackend;
+
+use super::{mock::MockGpio, simulator::Simulator};
+
+static GPIO: OnceCell<Box<dyn GpioProvider>> = OnceCell::new();
+
+// &mut for rppal compat, its pins write through a register handle
+pub trait OutputLine: Send {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+}
+
+pub trait InputLine: Send {
+    fn is_high(&self) -> bool;
+    fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pull {
+    Off,
+    Up,
+}
+
+// hands out lines by BCM pin number. a pin can only be held once, like on the real thing
+pub trait GpioProvider: Send + Sync {
+    fn output(&self, pin: u8) -> Result<Box<dyn OutputLine>, anyhow::Error>;
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputLine>, anyhow::Error>;
+}
+
+impl<T: OutputLine + ?Sized> OutputLine for Box<T> {
+    fn set_high(&mut self) {
+        (**self).set_high()
+    }
+    fn set_low(&mut self) {
+        (**self).set_low()
+    }
+}
+
+impl<T: InputLine + ?Sized> InputLine for Box<T> {
+    fn is_high(&self) -> bool {
+        (**self).is_high()
+    }
+    fn is_low(&self) -> bool {
+        (**self).is_low()
+    }
+}
+
+pub fn open(backend: GpioBackend) -> Result<Box<dyn GpioProvider>, anyhow::Error> {
+    match backend {
+        GpioBackend::Mock => Ok(Box::new(MockGpio::new())),
+        GpioBackend::Simulator => Ok(Box::new(Simulator::shared().clone())),
+        #[cfg(feature = "hardware")]
+        GpioBackend::Rppal => Ok(Box::new(super::hardware::RppalGpio::new()?)),
+        #[cfg(not(feature = "hardware"))]
+        GpioBackend::Rppal => Err(anyhow!(
+            "gpio.backend = \"rppal\" needs a build with --features hardware"
+        )),
+    }
+}
+
+pub fn init(backend: GpioBackend) -> Result<&'static dyn GpioProvider, anyhow::Error> {
+    let provider = open(backend)?;
+    GPIO.set(provider)
+        .map_err(|_| anyhow!("the gpio backend is already set up"))?;
+    Ok(gpio())
+}
+
+pub fn gpio() -> &'static dyn GpioProvider {
+    GPIO.get()
+        .expect("rpi::init runs in serve before anything touches a pin")
+        .as_ref()
+}
@@ -0,0 +1,48 @@
+use rppal::gpio::{Gpio, InputPin, OutputPin};
+
+use super::hal::{GpioProvider, InputLine, OutputLine, Pull};
+
+// the pi's own gpio through /dev/gpiomem
+pub struct RppalGpio {
+    gpio: Gpio,
+}
+
+impl RppalGpio {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self { gpio: Gpio::new()? })
+    }
+}
+
+impl GpioProvider for RppalGpio {
+    fn output(&self, pin: u8) -> Result<Box<dyn OutputLine>, anyhow::Error> {
+        Ok(Box::new(self.gpio.get(pin)?.into_output()))
+    }
+
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputLine>, anyhow::Error> {
+        let pin = self.gpio.get(pin)?;
+        Ok(Box::new(match pull {
+            Pull::Off => pin.into_input(),
+            Pull::Up => pin.into_input_pullup(),
+        }))
+    }
+}
+
+impl OutputLine for OutputPin {
+    fn set_high(&mut self) {
+        OutputPin::set_high(self)
+    }
+
+    fn set_low(&mut self) {
+        OutputPin::set_low(self)
+    }
+}
+
+impl InputLine for InputPin {
+    fn is_high(&self) -> bool {
+        InputPin::is_high(self)
+    }
+
+    fn is_low(&self) -> bool {
+        InputPin::is_low(self)
+    }
+}
@@ -1,86 +1,229 @@
-#![allow(dead_code)]
-pub struct MockGpio;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
-impl MockGpio {
-    pub fn new() -> Result<Self, anyhow::Error> {
-        Ok(MockGpio)
-    }
+use anyhow::anyhow;
 
-    pub fn get(&self, pin_id: u8) -> Result<MockPin, anyhow::Error> {
-        Ok(MockPin::new(pin_id))
-    }
+use super::hal::{GpioProvider, InputLine, OutputLine, Pull};
+
+#[derive(Debug, Default)]
+struct Pins {
+    levels: HashMap<u8, bool>, // whatever was last driven, by us or through set_input
+    claimed: HashSet<u8>,
 }
 
-pub struct MockPin {
-    gpio_id: u8,
+// no hardware at all. inputs sit at their idle level (high with a pull-up, low without)
+// unless set_input says otherwise, so the button reads unpressed and the ultrasonic sensor times out
+#[derive(Debug, Clone, Default)]
+pub struct MockGpio {
+    pins: Arc<Mutex<Pins>>,
 }
 
-impl MockPin {
-    fn new(gpio_id: u8) -> Self {
-        Self { gpio_id }
+impl MockGpio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // stands in for the outside world, e.g. holding the button down
+    #[cfg(test)]
+    pub fn set_input(&self, pin: u8, high: bool) {
+        self.pins.lock().unwrap().levels.insert(pin, high);
+    }
+
+    // what an output was last set to, None until something drives it
+    #[cfg(test)]
+    pub fn level(&self, pin: u8) -> Option<bool> {
+        self.pins.lock().unwrap().levels.get(&pin).copied()
     }
 
-    pub fn into_output(self) -> MockOutputPin {
-        self.into()
+    fn claim(&self, pin: u8) -> Result<(), anyhow::Error> {
+        if !self.pins.lock().unwrap().claimed.insert(pin) {
+            return Err(anyhow!("gpio pin {pin} is already in use"));
+        }
+        Ok(())
     }
+}
 
-    pub fn into_input_pullup(self) -> MockInputPin {
-        self.into()
+impl GpioProvider for MockGpio {
+    fn output(&self, pin: u8) -> Result<Box<dyn OutputLine>, anyhow::Error> {
+        self.claim(pin)?;
+        Ok(Box::new(MockOutputLine {
+            pin,
+            pins: Arc::clone(&self.pins),
+        }))
     }
 
-    pub fn into_input(self) -> MockInputPin {
-        self.into()
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputLine>, anyhow::Error> {
+        self.claim(pin)?;
+        Ok(Box::new(MockInputLine {
+            pin,
+            idle_high: pull == Pull::Up,
+            pins: Arc::clone(&self.pins),
+        }))
     }
 }
 
-pub struct MockOutputPin {
-    gpio_id: u8,
+pub struct MockOutputLine {
+    pin: u8,
+    pins: Arc<Mutex<Pins>>,
 }
 
-impl MockOutputPin {
-    pub fn new(id: u8) -> Self {
-        Self { gpio_id: id }
+impl OutputLine for MockOutputLine {
+    fn set_high(&mut self) {
+        self.pins.lock().unwrap().levels.insert(self.pin, true);
     }
 
-    // needs to be &mut for rppal compat
-    pub fn set_high(&mut self) {
-        //println!("set {} pin to high", self.gpio_id);
+    fn set_low(&mut self) {
+        self.pins.lock().unwrap().levels.insert(self.pin, false);
     }
+}
 
-    // needs to be &mut for rppal compat
-    pub fn set_low(&mut self) {
-        //println!("set {} pin to low", self.gpio_id)
+impl Drop for MockOutputLine {
+    fn drop(&mut self) {
+        self.pins.lock().unwrap().claimed.remove(&self.pin);
     }
 }
 
-pub struct MockInputPin {
-    gpio_id: u8,
+pub struct MockInputLine {
+    pin: u8,
+    idle_high: bool,
+    pins: Arc<Mutex<Pins>>,
 }
 
-impl MockInputPin {
-    pub fn new(id: u8) -> Self {
-        Self { gpio_id: id }
+impl InputLine for MockInputLine {
+    fn is_high(&self) -> bool {
+        let pins = self.pins.lock().unwrap();
+        pins.levels
+            .get(&self.pin)
+            .copied()
+            .unwrap_or(self.idle_high)
     }
+}
 
-    pub fn is_high(&self) -> bool {
-        //println!("checking is high, this is unpressed state");
-        true
+impl Drop for MockInputLine {
+    fn drop(&mut self) {
+        self.pins.lock().unwrap().claimed.remove(&self.pin);
     }
+}
 
-    pub fn is_low(&self) -> bool {
-        //println!("checking is high, this is unpressed state");
-        true
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        clock::{self, VirtualClock},
+        config::GpioConfig,
+        rpi::{
+            Button, LED, LEDState, MIN_STEP_DELAY, MotorDirection, ReadEchoError, StepMotor,
+            UltrasonicSensor,
+        },
+        testing,
+    };
+
+    #[test]
+    fn pins_are_held_once_until_dropped() {
+        let gpio = MockGpio::new();
+        let held = gpio.output(17).unwrap();
+        assert!(gpio.output(17).is_err());
+        assert!(gpio.input(17, Pull::Up).is_err());
+        drop(held);
+        assert!(gpio.input(17, Pull::Up).is_ok());
     }
-}
 
-impl From<MockPin> for MockOutputPin {
-    fn from(value: MockPin) -> Self {
-        MockOutputPin::new(value.gpio_id)
+    #[test]
+    fn led_drives_its_pin() {
+        let gpio = MockGpio::new();
+        let mut led = LED::from_gpio(&gpio, 17).unwrap();
+        assert_eq!(gpio.level(17), None);
+        led.set_state(LEDState::On);
+        assert_eq!(gpio.level(17), Some(true));
+        led.set_state(LEDState::Off);
+        assert_eq!(gpio.level(17), Some(false));
+    }
+
+    #[test]
+    fn step_motor_drives_the_driver_pins() {
+        let gpio = MockGpio::new();
+        let pins = GpioConfig::default();
+        let mut motor = StepMotor::from_gpio(&gpio, &pins, clock::system()).unwrap();
+        // asleep and disabled until a move
+        assert_eq!(gpio.level(pins.motor_sleep_pin), Some(false));
+        assert_eq!(gpio.level(pins.motor_enable_pin), Some(true));
+        assert_eq!(gpio.level(pins.motor_step_pin), Some(false));
+
+        motor.activate();
+        assert_eq!(gpio.level(pins.motor_sleep_pin), Some(true));
+        assert_eq!(gpio.level(pins.motor_enable_pin), Some(false));
+
+        motor.set_direction(MotorDirection::Clockwise);
+        assert_eq!(gpio.level(pins.motor_dir_pin), Some(true));
+        motor.set_direction(MotorDirection::CounterClockwise);
+        assert_eq!(gpio.level(pins.motor_dir_pin), Some(false));
+
+        motor.take_step(MIN_STEP_DELAY);
+        assert_eq!(gpio.level(pins.motor_step_pin), Some(false));
+
+        motor.deactivate();
+        assert_eq!(gpio.level(pins.motor_sleep_pin), Some(false));
+        assert_eq!(gpio.level(pins.motor_enable_pin), Some(true));
+    }
+
+    #[test]
+    #[should_panic]
+    fn step_motor_refuses_to_step_faster_than_the_driver() {
+        let gpio = MockGpio::new();
+        let mut motor =
+            StepMotor::from_gpio(&gpio, &GpioConfig::default(), clock::system()).unwrap();
+        motor.take_step(MIN_STEP_DELAY - Duration::from_micros(1));
+    }
+
+    #[tokio::test]
+    async fn button_reads_unpressed_until_held_down() {
+        let debounce = Duration::from_millis(testing::config().sensor.button_debounce_ms);
+        let gpio = MockGpio::new();
+        let clock = VirtualClock::new();
+        let pin = GpioConfig::default().button_pin;
+        let button = Button::from_gpio(&gpio, pin, clock.clone()).unwrap();
+        assert!(!button.check_is_pressed_debounced().await);
+
+        gpio.set_input(pin, false);
+        let (pressed, ()) = tokio::join!(button.check_is_pressed_debounced(), async {
+            tokio::task::yield_now().await;
+            clock.advance(debounce);
+        });
+        assert!(pressed);
+    }
+
+    #[tokio::test]
+    async fn button_bounce_does_not_count() {
+        let debounce = Duration::from_millis(testing::config().sensor.button_debounce_ms);
+        let gpio = MockGpio::new();
+        let clock = VirtualClock::new();
+        let pin = GpioConfig::default().button_pin;
+        let button = Button::from_gpio(&gpio, pin, clock.clone()).unwrap();
+
+        gpio.set_input(pin, false);
+        // let go again while it's still waiting out the debounce
+        let (pressed, ()) = tokio::join!(button.check_is_pressed_debounced(), async {
+            tokio::task::yield_now().await;
+            gpio.set_input(pin, true);
+            clock.advance(debounce);
+        });
+        assert!(!pressed);
     }
-}
 
-impl From<MockPin> for MockInputPin {
-    fn from(value: MockPin) -> Self {
-        MockInputPin::new(value.gpio_id)
+    #[test]
+    fn ultrasonic_sensor_times_out_without_an_echo() {
+        let gpio = MockGpio::new();
+        let pins = GpioConfig::default();
+        let mut sensor = UltrasonicSensor::from_gpio(&gpio, &pins).unwrap();
+        assert!(matches!(
+            sensor.read_distance(),
+            Err(ReadEchoError::Timeout)
+        ));
+        // the trigger pulse is over by the time it gives up
+        assert_eq!(gpio.level(pins.ultrasonic_trigger_pin), Some(false));
     }
 }
@@ -0,0 +1,492 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use axum::{Json, http::StatusCode};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::{GpioConfig, config};
+
+use super::{
+    QUADRATURE_ORDER,
+    hal::{GpioProvider, InputLine, OutputLine, Pull},
+};
+
+const SPEED_OF_SOUND_M_S: f64 = 343.0;
+// an HC-SR04 takes a moment after the trigger to send its burst before the echo line goes up
+const ECHO_DELAY: Duration = Duration::from_micros(200);
+// a bolt turned by hand moves a step of the motor's worth this often
+const HAND_TURN_STEP: Duration = Duration::from_millis(5);
+// enough for any sane motion.steps, a runaway loop shouldn't eat the memory
+const MAX_RECORDED_PULSES: usize = 10_000;
+
+static SIMULATOR: OnceCell<Simulator> = OnceCell::new();
+
+// the world outside the pins plus everything the firmware drove. lines work out their levels from it
+#[derive(Debug)]
+struct Board {
+    pins: GpioConfig,
+    levels: HashMap<u8, bool>,
+    claimed: HashSet<u8>,
+    button_pressed: bool,
+    door_distance_cm: f64,
+    echo_timeout: bool,
+    triggered_at: Option<Instant>,
+    steps: u64,
+    position: i64,        // clockwise (unlocking) steps minus counter-clockwise ones
+    pulses: Vec<Instant>, // every step pulse since the driver was last enabled
+    travel: i64,          // motion.steps, from locked to fully unlocked
+    encoder_counts: i64,  // feedback.encoder_counts over that travel
+    bolt: i64,            // where the bolt really is, 0 is locked. stops dead at either end
+    skip_steps: u32,      // pulses still to come that won't move it
+}
+
+impl Board {
+    fn level(&self, pin: u8) -> bool {
+        self.levels.get(&pin).copied().unwrap_or(false)
+    }
+
+    fn drive(&mut self, pin: u8, high: bool) {
+        let was_high = self.levels.insert(pin, high).unwrap_or(false);
+        match (was_high, high) {
+            (true, false) if pin == self.pins.ultrasonic_trigger_pin => {
+                self.triggered_at = Some(Instant::now());
+            }
+            (false, true) if pin == self.pins.motor_step_pin => {
+                self.steps += 1;
+                let delta = match self.level(self.pins.motor_dir_pin) {
+                    true => 1, // see StepMotor::set_direction
+                    false => -1,
+                };
+                self.position += delta;
+                match self.skip_steps {
+                    0 => self.bolt = (self.bolt + delta).clamp(0, self.travel),
+                    _ => self.skip_steps -= 1,
+                }
+                if self.pulses.len() < MAX_RECORDED_PULSES {
+                    self.pulses.push(Instant::now());
+                }
+            }
+            (true, false) if pin == self.pins.motor_enable_pin => self.pulses.clear(), // enable is active low
+            _ => {}
+        }
+    }
+
+    // high for as long as sound takes to get to the door and back, starting ECHO_DELAY after the trigger
+    fn echo(&self) -> bool {
+        let Some(triggered_at) = self.triggered_at else {
+            return false;
+        };
+        if self.echo_timeout {
+            return false;
+        }
+        let round_trip =
+            Duration::from_secs_f64(2.0 * (self.door_distance_cm / 100.0) / SPEED_OF_SOUND_M_S);
+        let since = triggered_at.elapsed();
+        since >= ECHO_DELAY && since < ECHO_DELAY + round_trip
+    }
+
+    // counts up toward unlocked, one phase at a time as long as encoder_counts <= travel
+    fn encoder_phase(&self) -> u8 {
+        let count = self.bolt * self.encoder_counts / self.travel.max(1);
+        QUADRATURE_ORDER[count.rem_euclid(4) as usize]
+    }
+
+    fn input(&self, pin: u8, idle_high: bool) -> bool {
+        let pins = &self.pins;
+        if pin == pins.button_pin {
+            !self.button_pressed // pulled up, the button shorts it to ground
+        } else if pin == pins.ultrasonic_echo_pin {
+            self.echo()
+        } else if Some(pin) == pins.locked_stop_pin {
+            self.bolt > 0 // end-stops close to ground
+        } else if Some(pin) == pins.unlocked_stop_pin {
+            self.bolt < self.travel
+        } else if Some(pin) == pins.encoder_a_pin {
+            self.encoder_phase() & 0b10 != 0
+        } else if Some(pin) == pins.encoder_b_pin {
+            self.encoder_phase() & 0b01 != 0
+        } else {
+            self.levels.get(&pin).copied().unwrap_or(idle_high)
+        }
+    }
+
+    fn snapshot(&self) -> BoardSnapshot {
+        let pins = &self.pins;
+        let intervals_ms = self
+            .pulses
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs_f64() * 1000.0)
+            .collect();
+        BoardSnapshot {
+            button_pressed: self.button_pressed,
+            door_distance_cm: self.door_distance_cm,
+            echo_timeout: self.echo_timeout,
+            ready_led: self.level(pins.ready_led_pin),
+            in_use_led: self.level(pins.in_use_led_pin),
+            motor_enabled: !self.level(pins.motor_enable_pin) && self.level(pins.motor_sleep_pin),
+            motor_clockwise: self.level(pins.motor_dir_pin),
+            steps: self.steps,
+            position: self.position,
+            last_move_pulses: self.pulses.len(),
+            last_move_intervals_ms: intervals_ms,
+            bolt_position: self.bolt,
+            locked_stop_closed: self.bolt <= 0,
+            unlocked_stop_closed: self.bolt >= self.travel,
+            skip_steps: self.skip_steps,
+        }
+    }
+}
+
+// what the firmware produced, and the knobs as they're currently set
+#[derive(Debug, Clone, Serialize)]
+pub struct BoardSnapshot {
+    pub button_pressed: bool,
+    pub door_distance_cm: f64,
+    pub echo_timeout: bool,
+    pub ready_led: bool,
+    pub in_use_led: bool,
+    pub motor_enabled: bool,
+    pub motor_clockwise: bool,
+    pub steps: u64,
+    pub position: i64,
+    pub last_move_pulses: usize,
+    pub last_move_intervals_ms: Vec<f64>, // gaps between the pulses, i.e. the motion profile as stepped
+    pub bolt_position: i64,               // 0 locked, motion.steps fully unlocked
+    pub locked_stop_closed: bool,         // whether or not gpio.locked_stop_pin is wired
+    pub unlocked_stop_closed: bool,
+    pub skip_steps: u32,
+}
+
+// every field optional, only what's there changes
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardUpdate {
+    pub button_pressed: Option<bool>,
+    pub door_distance_cm: Option<f64>,
+    pub echo_timeout: Option<bool>, // the echo never comes back, like with the sensor unplugged
+    #[serde(default)]
+    pub reset_steps: bool,
+    pub bolt_position: Option<i64>, // turning the thumb-turn by hand, which takes a moment
+    pub skip_steps: Option<u32>, // the next this many pulses don't move the bolt, like a slipping motor
+}
+
+// a virtual board wired like the real one, per the given pin assignments.
+// clones share the board, so a test keeps one and hands the other to the devices
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    board: Arc<Mutex<Board>>,
+}
+
+impl Simulator {
+    pub fn new(pins: GpioConfig, travel: u64, encoder_counts: i64) -> Self {
+        Self {
+            board: Arc::new(Mutex::new(Board {
+                pins,
+                levels: HashMap::new(),
+                claimed: HashSet::new(),
+                button_pressed: false,
+                door_distance_cm: 100.0, // open, so nothing autolocks until someone says otherwise
+                echo_timeout: false,
+                triggered_at: None,
+                steps: 0,
+                position: 0,
+                pulses: Vec::new(),
+                travel: travel as i64,
+                encoder_counts,
+                bolt: 0,
+                skip_steps: 0,
+            })),
+        }
+    }
+
+    // the one serve runs on, wired per config().gpio with the bolt travel from config().motion
+    pub fn shared() -> &'static Simulator {
+        SIMULATOR.get_or_init(|| {
+            let config = config();
+            Simulator::new(
+                config.gpio.clone(),
+                config.motion.steps,
+                config.feedback.encoder_counts,
+            )
+        })
+    }
+
+    // puts the bolt somewhere without any pulses, clamped to its travel
+    pub fn place_bolt(&self, position: i64) {
+        let mut board = self.board.lock().unwrap();
+        board.bolt = position.clamp(0, board.travel);
+    }
+
+    pub fn snapshot(&self) -> BoardSnapshot {
+        self.board.lock().unwrap().snapshot()
+    }
+
+    pub fn update(&self, update: BoardUpdate) -> Result<BoardSnapshot, anyhow::Error> {
+        let mut board = self.board.lock().unwrap();
+        if let Some(position) = update.bolt_position
+            && !(0..=board.travel).contains(&position)
+        {
+            return Err(anyhow!(
+                "bolt_position must be from 0 (locked) to {} (unlocked), got {position}",
+                board.travel
+            ));
+        }
+        if let Some(cm) = update.door_distance_cm {
+            if !(cm.is_finite() && cm >= 0.0) {
+                return Err(anyhow!("door_distance_cm must be a distance, got {cm}"));
+            }
+            board.door_distance_cm = cm;
+        }
+        if let Some(pressed) = update.button_pressed {
+            board.button_pressed = pressed;
+        }
+        if let Some(timeout) = update.echo_timeout {
+            board.echo_timeout = timeout;
+        }
+        if let Some(skip) = update.skip_steps {
+            board.skip_steps = skip;
+        }
+        if update.reset_steps {
+            board.steps = 0;
+            board.position = 0;
+            board.pulses.clear();
+        }
+        let snapshot = board.snapshot();
+        drop(board);
+        if let Some(position) = update.bolt_position {
+            self.turn_bolt(position);
+        }
+        Ok(snapshot)
+    }
+
+    // a hand's pace, a count at a time, so the encoder sees every phase go by.
+    // done in the background, so the snapshot update hands back has it where it started
+    fn turn_bolt(&self, target: i64) {
+        let board = Arc::clone(&self.board);
+        thread::spawn(move || {
+            loop {
+                {
+                    let mut board = board.lock().unwrap();
+                    if board.bolt == target {
+                        return;
+                    }
+                    board.bolt += (target - board.bolt).signum();
+                }
+                thread::sleep(HAND_TURN_STEP);
+            }
+        });
+    }
+
+    fn claim(&self, pin: u8) -> Result<(), anyhow::Error> {
+        if !self.board.lock().unwrap().claimed.insert(pin) {
+            return Err(anyhow!("gpio pin {pin} is already in use"));
+        }
+        Ok(())
+    }
+}
+
+impl GpioProvider for Simulator {
+    fn output(&self, pin: u8) -> Result<Box<dyn OutputLine>, anyhow::Error> {
+        self.claim(pin)?;
+        Ok(Box::new(SimulatedLine {
+            pin,
+            idle_high: false,
+            board: Arc::clone(&self.board),
+        }))
+    }
+
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputLine>, anyhow::Error> {
+        self.claim(pin)?;
+        Ok(Box::new(SimulatedLine {
+            pin,
+            idle_high: pull == Pull::Up,
+            board: Arc::clone(&self.board),
+        }))
+    }
+}
+
+pub struct SimulatedLine {
+    pin: u8,
+    idle_high: bool,
+    board: Arc<Mutex<Board>>,
+}
+
+impl OutputLine for SimulatedLine {
+    fn set_high(&mut self) {
+        self.board.lock().unwrap().drive(self.pin, true);
+    }
+
+    fn set_low(&mut self) {
+        self.board.lock().unwrap().drive(self.pin, false);
+    }
+}
+
+impl InputLine for SimulatedLine {
+    fn is_high(&self) -> bool {
+        self.board.lock().unwrap().input(self.pin, self.idle_high)
+    }
+}
+
+impl Drop for SimulatedLine {
+    fn drop(&mut self) {
+        self.board.lock().unwrap().claimed.remove(&self.pin);
+    }
+}
+
+// GET /debug/sim. only routed with gpio.backend = "simulator", and unauthenticated like it's a bench
+pub async fn board() -> Json<BoardSnapshot> {
+    Json(Simulator::shared().snapshot())
+}
+
+// POST /debug/sim with a BoardUpdate, answers with the board afterwards
+pub async fn update_board(
+    Json(update): Json<BoardUpdate>,
+) -> Result<Json<BoardSnapshot>, (StatusCode, String)> {
+    info!(?update, "Simulator board changed");
+    Simulator::shared()
+        .update(update)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use more_asserts::assert_ge;
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::{
+        clock::{self, VirtualClock},
+        lock::{InstructionSource, LockInstruction, QUEUE_CAPACITY},
+        rpi::{Button, MotorDirection, ReadEchoError, StepMotor, UltrasonicSensor},
+        sensors, testing,
+    };
+
+    const TRAVEL: u64 = 60;
+
+    fn simulator() -> (Simulator, GpioConfig) {
+        let pins = GpioConfig::default();
+        (Simulator::new(pins.clone(), TRAVEL, TRAVEL as i64), pins)
+    }
+
+    fn middle(mut readings: Vec<f64>) -> f64 {
+        readings.sort_by(f64::total_cmp);
+        readings[readings.len() / 2]
+    }
+
+    #[tokio::test]
+    async fn pressing_the_button_sends_a_toggle() {
+        testing::config();
+        let (sim, pins) = simulator();
+        let clock = VirtualClock::new();
+        let button = Button::from_gpio(&sim, pins.button_pin, clock.clone()).unwrap();
+        let (lock_tx, mut lock_rx) = channel(QUEUE_CAPACITY);
+
+        let interface = sensors::expose_button_interface(Arc::new(lock_tx), button, clock.clone());
+        let pressed = async {
+            sim.update(BoardUpdate {
+                button_pressed: Some(true),
+                ..Default::default()
+            })
+            .unwrap();
+            for _ in 0..100 {
+                tokio::task::yield_now().await;
+                if let Ok(queued) = lock_rx.try_recv() {
+                    return queued;
+                }
+                clock.advance(Duration::from_millis(10));
+            }
+            panic!("the button press never reached the lock");
+        };
+        let queued = tokio::select! {
+            _ = interface => unreachable!("the button interface runs forever"),
+            queued = pressed => queued,
+        };
+        assert!(matches!(
+            queued.instruction,
+            LockInstruction::Reverse(InstructionSource::Button)
+        ));
+    }
+
+    #[test]
+    fn the_sensor_reads_the_door_distance() {
+        let (sim, pins) = simulator();
+        let mut sensor = UltrasonicSensor::from_gpio(&sim, &pins).unwrap();
+        for cm in [5.0, 30.0, 80.0] {
+            sim.update(BoardUpdate {
+                door_distance_cm: Some(cm),
+                ..Default::default()
+            })
+            .unwrap();
+            // timed on the wall clock, so take the middle of a few in case one got descheduled.
+            // descheduled past the whole echo, it times out like a real sensor would
+            let readings: Vec<_> = (0..20)
+                .filter_map(|_| sensor.read_distance().ok())
+                .map(|distance| distance.as_cm_f64())
+                .take(5)
+                .collect();
+            assert!(!readings.is_empty(), "no echo at {cm}cm");
+            let read = middle(readings);
+            assert!((read - cm).abs() <= 1.5, "read {read}cm for {cm}cm");
+        }
+    }
+
+    #[test]
+    fn a_missing_echo_times_out() {
+        let (sim, pins) = simulator();
+        let mut sensor = UltrasonicSensor::from_gpio(&sim, &pins).unwrap();
+        sim.update(BoardUpdate {
+            echo_timeout: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(matches!(
+            sensor.read_distance(),
+            Err(ReadEchoError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn a_move_is_recorded_pulse_by_pulse() {
+        let (sim, pins) = simulator();
+        let mut motor = StepMotor::from_gpio(&sim, &pins, clock::system()).unwrap();
+        let step_delay = Duration::from_millis(5);
+        motor.activate();
+        motor.set_direction(MotorDirection::Clockwise);
+        for _ in 0..10 {
+            motor.take_step(step_delay);
+        }
+
+        let board = sim.snapshot();
+        assert!(board.motor_enabled);
+        assert!(board.motor_clockwise);
+        assert_eq!(board.steps, 10);
+        assert_eq!(board.position, 10);
+        assert_eq!(board.bolt_position, 10);
+        assert_eq!(board.last_move_pulses, 10);
+        assert_eq!(board.last_move_intervals_ms.len(), 9);
+        for interval in &board.last_move_intervals_ms {
+            assert_ge!(*interval, step_delay.as_secs_f64() * 1000.0);
+        }
+
+        // the next move starts its own record, the totals keep counting
+        motor.deactivate();
+        motor.activate();
+        motor.set_direction(MotorDirection::CounterClockwise);
+        for _ in 0..4 {
+            motor.take_step(step_delay);
+        }
+        let board = sim.snapshot();
+        assert_eq!(board.steps, 14);
+        assert_eq!(board.position, 6);
+        assert_eq!(board.last_move_pulses, 4);
+        assert_eq!(board.last_move_intervals_ms.len(), 3);
+    }
+}
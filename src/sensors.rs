@@ -3,62 +3,216 @@ use std::{
     time::{Duration, Instant},
 };
 
-use tokio::{sync::mpsc::Sender, time::sleep};
+use serde_json::json;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, info};
 
 use crate::{
-    lock::{InstructionSource, LockInstruction, LockInstructor},
+    clock::SharedClock,
+    config::{WebhookEvent, config},
+    events::{self, LockEventKind},
+    lock::{InstructionSource, LockInstruction, LockInstructor, QueuedInstruction},
+    metrics,
     rpi::{Button, UltrasonicSensor},
+    webhooks,
 };
 
-pub async fn expose_button_interface(lock_tx: Arc<Sender<LockInstruction>>) {
-    let button = Button::new();
+pub async fn expose_button_interface(
+    lock_tx: Arc<Sender<QueuedInstruction>>,
+    button: Button,
+    clock: SharedClock,
+) {
     loop {
-        if button.check_is_pressed_debounced().await {
-            if let Err(e) =
-                lock_tx.send_instruction(LockInstruction::Reverse(InstructionSource::Button))
-            {
-                println!("{}", e)
-            };
+        if button.check_is_pressed_debounced().await
+            && let Err(e) = lock_tx
+                .send_instruction(LockInstruction::Reverse(InstructionSource::Button))
+                .await
+        {
+            info!("Button press dropped. {e}")
         }
-        sleep(Duration::from_millis(100)).await;
+        clock
+            .sleep(Duration::from_millis(config().sensor.button_poll_ms))
+            .await;
     }
 }
 
-pub async fn expose_closed_detection_interface(lock_tx: Arc<Sender<LockInstruction>>) {
-    let mut ultrasonic_sensor = UltrasonicSensor::new();
-    let mut start_timer = Instant::now();
-    let frame_threshold_cm = 6; //distance from doorframe
-    let autolock_interval_sec = 10; // time to autolock
-    let err_tolerance = 3;
+pub async fn expose_closed_detection_interface(
+    lock_tx: Arc<Sender<QueuedInstruction>>,
+    mut ultrasonic_sensor: UltrasonicSensor,
+    clock: SharedClock,
+) {
+    let mut start_timer = clock.now();
+    let sensor = &config().sensor;
+    let frame_threshold_cm = sensor.frame_threshold_cm; //distance from doorframe
+    let autolock_interval_sec = sensor.autolock_interval_secs; // time to autolock
+    let err_tolerance = sensor.err_tolerance;
     let mut errs = 0;
+    let mut door_closed: Option<bool> = None; // unknown until the first reading settles
+    let mut open_since: Option<Instant> = None; // cleared once door_left_open has gone out
+    let door_open_secs = config().webhooks.door_open_secs;
     loop {
-        match ultrasonic_sensor.read_distance() {
+        let reading = ultrasonic_sensor.read_distance();
+        if let Ok(distance) = &reading {
+            metrics::record_distance(distance.as_cm_f64());
+        }
+        match reading {
             Ok(distance) => match distance.as_cm_u64() < frame_threshold_cm {
                 true => {
-                    if start_timer.elapsed().as_secs() >= autolock_interval_sec {
-                        if let Err(e) = lock_tx.send_instruction(LockInstruction::EnsureLocked(
-                            InstructionSource::AutoSensor,
-                        )) {
-                            println!("Autolock instruction dropped. {e}");
+                    if door_closed != Some(true) {
+                        door_closed = Some(true);
+                        open_since = None;
+                        events::publish(LockEventKind::DoorClosed);
+                    }
+                    if (clock.now() - start_timer).as_secs() >= autolock_interval_sec {
+                        metrics::record_autolock("sensor");
+                        if let Err(e) = lock_tx
+                            .send_instruction(LockInstruction::EnsureLocked(
+                                InstructionSource::AutoSensor,
+                            ))
+                            .await
+                        {
+                            info!("Autolock instruction dropped. {e}");
                         }
-                        sleep(Duration::from_millis(5000)).await; // auto lock mech can take a break after triggering
-                        start_timer = Instant::now();
+                        clock.sleep(Duration::from_millis(sensor.cooldown_ms)).await; // auto lock mech can take a break after triggering
+                        start_timer = clock.now();
                         errs = 0;
                     }
                 }
                 false => {
                     errs += 1;
                     if errs > err_tolerance {
-                        start_timer = Instant::now();
+                        start_timer = clock.now();
                         errs = 0;
+                        if door_closed != Some(false) {
+                            door_closed = Some(false);
+                            open_since = Some(clock.now());
+                            events::publish(LockEventKind::DoorOpened);
+                        }
+                    }
+                    if door_open_secs > 0
+                        && let Some(since) = open_since
+                        && (clock.now() - since).as_secs() >= door_open_secs
+                    {
+                        open_since = None;
+                        webhooks::raise(
+                            WebhookEvent::DoorLeftOpen,
+                            json!({ "open_secs": (clock.now() - since).as_secs() }),
+                        );
                     }
                 }
             },
-            Err(_) => {
-                println!("Error reading distance from ultrasonic sensor")
+            // every poll without a sensor attached, so keep it out of the way
+            Err(e) => {
+                metrics::record_sensor_error(&e);
+                debug!("Error reading distance from ultrasonic sensor. {e}")
             }
         }
 
-        sleep(Duration::from_millis(1000)).await;
+        clock
+            .sleep(Duration::from_millis(sensor.poll_interval_ms))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::{
+        clock::VirtualClock,
+        lock::QUEUE_CAPACITY,
+        rpi::{MockGpio, Simulator, simulator::BoardUpdate},
+        testing,
+    };
+
+    #[tokio::test]
+    async fn the_button_has_to_be_held_past_the_debounce() {
+        let config = testing::config();
+        let debounce = Duration::from_millis(config.sensor.button_debounce_ms);
+        let poll = Duration::from_millis(config.sensor.button_poll_ms);
+        let gpio = MockGpio::new();
+        let pin = config.gpio.button_pin;
+        let clock = VirtualClock::new();
+        let button = Button::from_gpio(&gpio, pin, clock.clone()).unwrap();
+        let (lock_tx, mut lock_rx) = channel(QUEUE_CAPACITY);
+
+        let interface = expose_button_interface(Arc::new(lock_tx), button, clock.clone());
+        let presses = async {
+            // a bounce, let go before the debounce is up
+            gpio.set_input(pin, false);
+            clock.advance(poll);
+            testing::settle().await;
+            gpio.set_input(pin, true);
+            clock.advance(debounce);
+            testing::settle().await;
+            assert!(lock_rx.try_recv().is_err(), "a bounce toggled the lock");
+
+            gpio.set_input(pin, false);
+            clock.advance(poll);
+            testing::settle().await;
+            clock.advance(debounce);
+            testing::settle().await;
+            lock_rx
+                .try_recv()
+                .expect("a held press never toggled the lock")
+        };
+        let queued = tokio::select! {
+            biased;
+            _ = interface => unreachable!("the button interface runs forever"),
+            queued = presses => queued,
+        };
+        assert!(matches!(
+            queued.instruction,
+            LockInstruction::Reverse(InstructionSource::Button)
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_closed_door_autolocks_once_the_interval_is_up() {
+        let config = testing::config();
+        let sensor = &config.sensor;
+        let poll = Duration::from_millis(sensor.poll_interval_ms);
+        let polls = sensor.autolock_interval_secs * 1000 / sensor.poll_interval_ms;
+        let sim = Simulator::new(config.gpio.clone(), config.motion.steps, 0);
+        sim.update(BoardUpdate {
+            door_distance_cm: Some(sensor.frame_threshold_cm as f64 / 2.0),
+            ..Default::default()
+        })
+        .unwrap();
+        let ultrasonic_sensor = UltrasonicSensor::from_gpio(&sim, &config.gpio).unwrap();
+        let clock = VirtualClock::new();
+        let (lock_tx, mut lock_rx) = channel(QUEUE_CAPACITY);
+
+        let interface =
+            expose_closed_detection_interface(Arc::new(lock_tx), ultrasonic_sensor, clock.clone());
+        let closed = async {
+            for polled in 0..polls {
+                testing::settle().await;
+                assert!(
+                    lock_rx.try_recv().is_err(),
+                    "autolocked after {polled} of {polls} polls"
+                );
+                clock.advance(poll);
+            }
+            // the echo is timed on the wall clock, so a busy test run can miss one and put it off a poll
+            for _ in 0..5 {
+                testing::settle().await;
+                if let Ok(queued) = lock_rx.try_recv() {
+                    return queued;
+                }
+                clock.advance(poll);
+            }
+            panic!("the door was shut all interval and never autolocked");
+        };
+        let queued = tokio::select! {
+            biased;
+            _ = interface => unreachable!("the sensor interface runs forever"),
+            queued = closed => queued,
+        };
+        assert!(matches!(
+            queued.instruction,
+            LockInstruction::EnsureLocked(InstructionSource::AutoSensor)
+        ));
     }
 }
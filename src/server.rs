@@ -1,23 +1,53 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
     Router,
     routing::{get, post},
 };
 use tokio::sync::mpsc::Sender;
+use tracing::info;
 
 use crate::{
-    lock::LockInstruction,
-    routes::{door_control, home},
+    api,
+    config::{GpioBackend, config},
+    lock::QueuedInstruction,
+    metrics,
+    routes::{
+        clear_lockout, create_guest_code, door_control, guest_codes, history, home,
+        list_guest_codes, list_lockouts, lockouts, revoke_guest_code, search_history,
+    },
+    rpi::simulator,
 };
 
-pub async fn run_app(lock_tx: Arc<Sender<LockInstruction>>) -> Result<(), anyhow::Error> {
-    let app = Router::new()
+pub async fn run_app(lock_tx: Arc<Sender<QueuedInstruction>>) -> Result<(), anyhow::Error> {
+    let mut app = Router::new()
         .route("/home", get(home))
         .route("/door-control", post(door_control))
+        .route("/guest-codes", get(guest_codes).post(create_guest_code))
+        .route("/guest-codes/list", post(list_guest_codes))
+        .route("/guest-codes/revoke", post(revoke_guest_code))
+        .route("/lockouts", get(lockouts))
+        .route("/lockouts/list", post(list_lockouts))
+        .route("/lockouts/clear", post(clear_lockout))
+        .route("/history", get(history).post(search_history))
+        .nest("/api/v1", api::router())
         .with_state(lock_tx);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    println!("Serving routes on port 3000");
-    axum::serve(listener, app).await?;
+    if config().server.metrics {
+        app = app.route("/metrics", get(metrics::metrics));
+    }
+    if config().gpio.backend == GpioBackend::Simulator {
+        app = app.route(
+            "/debug/sim",
+            get(simulator::board).post(simulator::update_board),
+        );
+    }
+    let addr = config().socket_addr();
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Serving routes on {addr}");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
@@ -0,0 +1,88 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::config,
+    lock::{LockAction, LockState},
+};
+
+// what we know about the bolt the last time we touched it.
+// in_motion is set right before the motor starts and cleared once the move completes,
+// so if we ever load a record with it set, we died halfway through a move
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateRecord {
+    pub state: LockState,
+    pub in_motion: Option<LockAction>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+impl StateRecord {
+    pub fn is_trustworthy(&self) -> bool {
+        self.in_motion.is_none()
+    }
+}
+
+pub fn state_file_path() -> PathBuf {
+    config().files.lock_state.clone()
+}
+
+pub fn load() -> Result<Option<StateRecord>, anyhow::Error> {
+    let path = state_file_path();
+    if !path.exists() {
+        return Ok(None);
+    }
+    let raw = fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&raw)?))
+}
+
+pub fn record_settled(state: &LockState) -> Result<(), anyhow::Error> {
+    write_record(&StateRecord {
+        state: state.clone(),
+        in_motion: None,
+        recorded_at: Utc::now(),
+    })
+}
+
+pub fn record_motion(state: &LockState, action: &LockAction) -> Result<(), anyhow::Error> {
+    write_record(&StateRecord {
+        state: state.clone(),
+        in_motion: Some(action.clone()),
+        recorded_at: Utc::now(),
+    })
+}
+
+fn write_record(record: &StateRecord) -> Result<(), anyhow::Error> {
+    write_atomic(
+        &state_file_path(),
+        serde_json::to_string_pretty(record)?.as_bytes(),
+    )
+}
+
+// write to a sibling temp file, fsync it, then rename over the real one.
+// a power cut leaves either the old record or the new one, never half of each
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    fs::rename(&tmp_path, path)?;
+
+    // the rename itself lives in the directory entry, so that needs flushing too
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
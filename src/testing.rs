@@ -0,0 +1,101 @@
+// shared by the unit tests. they run on the same globals as the daemon (config, STATE),
+// so the whole test run gets one config with its files somewhere of its own
+use std::{env, fs, path::PathBuf, process, sync::Arc, thread, time::Duration};
+
+use chrono::Utc;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    clock::VirtualClock,
+    config::{self, Config},
+    lock::{Lock, LockState, STATE},
+    rpi::Simulator,
+    state_file::StateRecord,
+};
+
+// there's only the one STATE, so tests that move it take turns
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+// somewhere of this test run's own to keep a file
+pub fn temp_path(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("doorknob-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+pub fn config() -> &'static Config {
+    config::init_for_tests(|| {
+        let mut config = Config::default();
+        config.gpio.locked_stop_pin = Some(5);
+        config.gpio.unlocked_stop_pin = Some(6);
+        let files = &mut config.files;
+        files.lock_state = temp_path("lock_state.json");
+        files.users = temp_path("users.json");
+        files.legacy_password_hash = temp_path("password_hash.txt");
+        files.guest_codes = temp_path("guest_codes.json");
+        files.tokens = temp_path("tokens.json");
+        files.lockouts = temp_path("lockouts.json");
+        files.audit_log = temp_path("audit.jsonl");
+        files.webhook_queue = temp_path("webhook_queue.json");
+        // so STATE comes up locked instead of asking on stdin
+        let record = StateRecord {
+            state: LockState::Locked,
+            in_motion: None,
+            recorded_at: Utc::now(),
+        };
+        fs::write(
+            &files.lock_state,
+            serde_json::to_string_pretty(&record).unwrap(),
+        )
+        .unwrap();
+        config
+    })
+}
+
+// holds off every other test that touches STATE until the guard goes, starting it at state
+pub async fn serial(state: LockState) -> MutexGuard<'static, ()> {
+    config();
+    let guard = SERIAL.lock().await;
+    STATE.send_replace(state);
+    guard
+}
+
+// a lock on a simulated board wired per the test config, bolt placed at bolt.
+// building the stepper waits on the clock, so time passes here while another thread builds it
+pub fn simulated_lock(clock: &Arc<VirtualClock>, bolt: i64) -> (Simulator, Lock) {
+    let config = config();
+    let sim = Simulator::new(
+        config.gpio.clone(),
+        config.motion.steps,
+        config.feedback.encoder_counts,
+    );
+    sim.place_bolt(bolt);
+    let lock = thread::scope(|scope| {
+        let building = scope.spawn(|| Lock::new(&sim, clock.clone()));
+        while !building.is_finished() {
+            clock.advance(Duration::from_millis(1));
+            thread::sleep(Duration::from_micros(100));
+        }
+        building.join().unwrap().unwrap()
+    });
+    (sim, lock)
+}
+
+// lets every task that's been woken run before the test looks
+pub async fn settle() {
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+// moves the clock along at about 5x real time, slow enough for the motor thread to keep up
+pub async fn pump_until(clock: &VirtualClock, done: impl Fn() -> bool) {
+    for _ in 0..10_000 {
+        if done() {
+            return;
+        }
+        clock.advance(Duration::from_millis(5));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    panic!("gave up waiting, the clock went 50s without it happening");
+}
@@ -0,0 +1,549 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        mpsc::{self, Sender},
+    },
+    thread::{self, JoinHandle},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    audit::{self, AuditEntry},
+    auth::{Principal, verify_admin_password, verify_password},
+    config::config,
+    metrics,
+    state_file::write_atomic,
+    tokens::verify_token,
+    webhooks,
+};
+
+// free attempts before backoff kicks in, per client
+const FREE_ATTEMPTS: u32 = 3;
+const BASE_BACKOFF_SECS: i64 = 1;
+const MAX_BACKOFF_SECS: i64 = 300;
+// hard lockouts, per client and for the whole house
+const CLIENT_LOCKOUT_AFTER: u32 = 10;
+const GLOBAL_LOCKOUT_AFTER: u32 = 50;
+const LOCKOUT_SECS: i64 = 15 * 60;
+// a counter forgets everything once it's been quiet this long
+const FORGET_AFTER_SECS: i64 = 60 * 60;
+
+// the daemon's, wired up from config()
+static THROTTLE: Lazy<Throttle> = Lazy::new(|| Throttle::new(config().files.lockouts.clone()));
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FailureRecord {
+    pub failures: u32,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub blocked_until: Option<DateTime<Utc>>,
+    pub locked_out: bool, // true when blocked_until is a hard lockout rather than backoff
+}
+
+impl FailureRecord {
+    fn forget_if_stale(&mut self, now: DateTime<Utc>) {
+        let stale = self
+            .last_failure
+            .is_some_and(|last| now - last > TimeDelta::seconds(FORGET_AFTER_SECS));
+        let blocked = self.blocked_until.is_some_and(|until| until > now);
+        if stale && !blocked {
+            *self = FailureRecord::default();
+        }
+    }
+
+    fn blocked_at(&self, now: DateTime<Utc>) -> Option<Throttled> {
+        self.blocked_until
+            .filter(|until| *until > now)
+            .map(|until| Throttled {
+                until,
+                locked_out: self.locked_out,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThrottleState {
+    pub clients: HashMap<IpAddr, FailureRecord>,
+    pub global: FailureRecord,
+}
+
+#[derive(Debug, Clone)]
+pub struct Throttled {
+    pub until: DateTime<Utc>,
+    pub locked_out: bool,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.locked_out {
+            "Locked out"
+        } else {
+            "Backing off"
+        };
+        write!(f, "{kind} until {}", self.until.format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+#[derive(Debug)]
+pub enum GuardedVerifyError {
+    Throttled(Throttled),
+    Internal(anyhow::Error),
+}
+
+fn backoff_secs(failures: u32) -> i64 {
+    let doublings = failures.saturating_sub(FREE_ATTEMPTS).min(16);
+    (BASE_BACKOFF_SECS << doublings).min(MAX_BACKOFF_SECS)
+}
+
+// an attempt that's been let through and already counted as a failure, until it turns out fine
+#[derive(Debug)]
+struct Reservation {
+    ip: IpAddr,
+    // the client's block from before this attempt, and the one it set if it set one
+    block_before: (Option<DateTime<Utc>>, bool),
+    blocked_until: Option<DateTime<Utc>>,
+    global_lockout: Option<DateTime<Utc>>, // set when this very attempt locked everyone out
+}
+
+// the counters and the file they're kept in. serve only ever has THROTTLE, the functions below
+// hand everything to it
+pub struct Throttle {
+    path: PathBuf,
+    state: Mutex<ThrottleState>,
+    // every attempt gets counted, so the file is written on its own thread, not under the lock
+    // or on the runtime
+    saves: Option<Sender<ThrottleState>>,
+    saver: Option<JoinHandle<()>>,
+}
+
+impl Throttle {
+    pub fn new(path: PathBuf) -> Self {
+        let (saves, saved) = mpsc::channel::<ThrottleState>();
+        let saved_path = path.clone();
+        let saver = thread::Builder::new()
+            .name("lockouts".to_string())
+            .spawn(move || {
+                // only the newest copy matters, a backlog of saves collapses into one write
+                for mut state in saved.iter() {
+                    while let Ok(newer) = saved.try_recv() {
+                        state = newer;
+                    }
+                    write_state(&saved_path, &state);
+                }
+            })
+            .expect("could not start the lockouts writer");
+        Self {
+            path,
+            state: Mutex::new(ThrottleState::default()),
+            saves: Some(saves),
+            saver: Some(saver),
+        }
+    }
+
+    pub fn load(&self) -> Result<(), anyhow::Error> {
+        if self.path.exists() {
+            let loaded: ThrottleState = serde_json::from_str(&fs::read_to_string(&self.path)?)?;
+            info!(
+                "Loaded failed attempt counters for {} client(s)",
+                loaded.clients.len()
+            );
+            *self.state.lock().unwrap() = loaded;
+        }
+        Ok(())
+    }
+
+    // sent while the lock is held, so the saves go out in the order the changes were made
+    fn persist(&self, state: &ThrottleState) {
+        let saves = self.saves.as_ref().expect("only taken on drop");
+        if saves.send(state.clone()).is_err() {
+            error!("Lockouts writer is gone, lockouts won't survive a restart");
+        }
+    }
+
+    // checking and counting under one lock, so a burst of concurrent attempts can't all get past
+    // the check before any of them has failed. global only counts toward a hard lockout, everyone
+    // shares it so backoff would just be noise. the admin forms skip it, see verify_admin
+    fn reserve(
+        &self,
+        ip: IpAddr,
+        check_global: bool,
+        now: DateTime<Utc>,
+    ) -> Result<Reservation, Throttled> {
+        let mut state = self.state.lock().unwrap();
+        state.global.forget_if_stale(now);
+        if check_global && let Some(throttled) = state.global.blocked_at(now) {
+            return Err(throttled);
+        }
+        if let Some(record) = state.clients.get_mut(&ip) {
+            record.forget_if_stale(now);
+            if let Some(throttled) = record.blocked_at(now) {
+                return Err(throttled);
+            }
+        }
+        let block_before = state.clients.get(&ip).map_or((None, false), |record| {
+            (record.blocked_until, record.locked_out)
+        });
+        let global_lockout = count_failure(&mut state, ip, now);
+        let blocked_until = state.clients[&ip]
+            .blocked_until
+            .filter(|until| Some(*until) != block_before.0);
+        self.persist(&state);
+        Ok(Reservation {
+            ip,
+            block_before,
+            blocked_until,
+            global_lockout,
+        })
+    }
+
+    // the attempt was good after all, so only its own failure is forgiven. the ones before it
+    // stand, or a guest code would reset the count for guessing at everyone else's
+    fn refund(&self, reservation: Reservation) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(client) = state.clients.get_mut(&reservation.ip) {
+            client.failures = client.failures.saturating_sub(1);
+            if reservation.blocked_until.is_some()
+                && client.blocked_until == reservation.blocked_until
+            {
+                (client.blocked_until, client.locked_out) = reservation.block_before;
+            }
+        }
+        state.global.failures = state.global.failures.saturating_sub(1);
+        if reservation.global_lockout.is_some()
+            && state.global.blocked_until == reservation.global_lockout
+        {
+            state.global.blocked_until = None;
+            state.global.locked_out = false;
+        }
+        self.persist(&state);
+    }
+
+    // None clears everything, including the global lockout
+    pub fn clear(&self, ip: Option<IpAddr>) {
+        let mut state = self.state.lock().unwrap();
+        match ip {
+            Some(ip) => {
+                state.clients.remove(&ip);
+            }
+            None => *state = ThrottleState::default(),
+        }
+        self.persist(&state);
+        info!(
+            "Cleared lockouts for {}",
+            ip.map_or("everyone".to_string(), |ip| ip.to_string())
+        );
+    }
+
+    pub fn snapshot(&self) -> ThrottleState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Throttle {
+    // hangs up and waits for the writer, so the file has the last save once this returns
+    fn drop(&mut self) {
+        self.saves.take();
+        if let Some(saver) = self.saver.take()
+            && saver.join().is_err()
+        {
+            error!("Lockouts writer had panicked");
+        }
+    }
+}
+
+fn write_state(path: &Path, state: &ThrottleState) {
+    let written = serde_json::to_string_pretty(state)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| write_atomic(path, json.as_bytes()));
+    if let Err(e) = written {
+        error!("Could not save lockouts, they won't survive a restart. {e}");
+    }
+}
+
+// returns when the global lockout runs until, if this failure is what started it
+fn count_failure(
+    state: &mut ThrottleState,
+    ip: IpAddr,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let client = state.clients.entry(ip).or_default();
+    client.failures += 1;
+    client.last_failure = Some(now);
+    if client.failures >= CLIENT_LOCKOUT_AFTER {
+        client.blocked_until = Some(now + TimeDelta::seconds(LOCKOUT_SECS));
+        client.locked_out = true;
+        warn!("Locking out {ip} after {} failed attempts", client.failures);
+    } else if client.failures > FREE_ATTEMPTS {
+        client.blocked_until = Some(now + TimeDelta::seconds(backoff_secs(client.failures)));
+    }
+
+    state.global.failures += 1;
+    state.global.last_failure = Some(now);
+    if state.global.failures < GLOBAL_LOCKOUT_AFTER {
+        return None;
+    }
+    let until = now + TimeDelta::seconds(LOCKOUT_SECS);
+    state.global.blocked_until = Some(until);
+    state.global.locked_out = true;
+    warn!(
+        "Locking out everyone after {} failed attempts",
+        state.global.failures
+    );
+    Some(until)
+}
+
+pub fn load() -> Result<(), anyhow::Error> {
+    THROTTLE.load()
+}
+
+pub fn clear(ip: Option<IpAddr>) {
+    THROTTLE.clear(ip);
+}
+
+pub fn snapshot() -> ThrottleState {
+    THROTTLE.snapshot()
+}
+
+fn refused(ip: IpAddr, throttled: Throttled) -> GuardedVerifyError {
+    metrics::record_throttled();
+    audit::record(AuditEntry::Throttled {
+        client_ip: ip,
+        until: throttled.until,
+        locked_out: throttled.locked_out,
+    });
+    GuardedVerifyError::Throttled(throttled)
+}
+
+// verify_password with the counters wrapped around it. argon2 never runs for a blocked client
+pub async fn verify(ip: IpAddr, passcode: &str) -> Result<Option<Principal>, GuardedVerifyError> {
+    counted(ip, true, "passcode", verify_password(passcode)).await
+}
+
+// for the admin forms. only admin passcodes are tried, which is what lets them skip the global
+// lockout: an admin can still get in to clear it, and anyone else is only ever told no
+pub async fn verify_admin(
+    ip: IpAddr,
+    passcode: &str,
+) -> Result<Option<Principal>, GuardedVerifyError> {
+    counted(ip, false, "passcode", verify_admin_password(passcode)).await
+}
+
+// bearer tokens get guessed at just like passcodes, so they share the counters
+pub async fn verify_bearer(
+    ip: IpAddr,
+    token: &str,
+) -> Result<Option<Principal>, GuardedVerifyError> {
+    counted(ip, true, "token", async {
+        Ok(verify_token(token)
+            .await?
+            .map(|(name, scope)| Principal::Token { name, scope }))
+    })
+    .await
+}
+
+// a broken hash leaves its failure counted, the counters would rather err that way
+async fn counted<F>(
+    ip: IpAddr,
+    check_global: bool,
+    credential: &str,
+    attempt: F,
+) -> Result<Option<Principal>, GuardedVerifyError>
+where
+    F: Future<Output = Result<Option<Principal>, anyhow::Error>>,
+{
+    let reservation = THROTTLE
+        .reserve(ip, check_global, Utc::now())
+        .map_err(|throttled| refused(ip, throttled))?;
+    let principal = attempt.await.map_err(GuardedVerifyError::Internal)?;
+    match &principal {
+        Some(_) => THROTTLE.refund(reservation),
+        None => {
+            metrics::record_auth_failure(credential);
+            webhooks::auth_failed();
+            audit::record(AuditEntry::AuthFailed {
+                client_ip: ip,
+                credential: credential.to_string(),
+            });
+        }
+    }
+    Ok(principal)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures_util::future::join_all;
+
+    use super::*;
+    use crate::testing;
+
+    fn fresh_throttle(name: &str) -> Throttle {
+        let path = testing::temp_path(name);
+        let _ = fs::remove_file(&path);
+        Throttle::new(path)
+    }
+
+    fn client(n: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, n])
+    }
+
+    // fails `times` attempts for ip, each one the moment the last one's backoff ran out
+    fn fail(throttle: &Throttle, ip: IpAddr, times: u32, now: &mut DateTime<Utc>) {
+        for _ in 0..times {
+            throttle.reserve(ip, true, *now).unwrap();
+            if let Some(until) = throttle.snapshot().clients[&ip].blocked_until {
+                *now = until;
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_free_attempts_and_is_capped() {
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 1), 2);
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 2), 4);
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 5), 32);
+        assert_eq!(backoff_secs(100), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn a_client_backs_off_then_gets_locked_out() {
+        let throttle = fresh_throttle("lockouts-client.json");
+        let ip = client(1);
+        let mut now = Utc::now();
+
+        fail(&throttle, ip, FREE_ATTEMPTS, &mut now);
+        assert!(throttle.snapshot().clients[&ip].blocked_until.is_none());
+
+        throttle.reserve(ip, true, now).unwrap();
+        let throttled = throttle.reserve(ip, true, now).unwrap_err();
+        assert!(!throttled.locked_out);
+        assert_eq!(throttled.until, now + TimeDelta::seconds(2));
+        // someone else isn't held up by it
+        throttle.reserve(client(2), true, now).unwrap();
+
+        now = throttled.until;
+        fail(
+            &throttle,
+            ip,
+            CLIENT_LOCKOUT_AFTER - FREE_ATTEMPTS - 2,
+            &mut now,
+        );
+        throttle.reserve(ip, true, now).unwrap();
+        let throttled = throttle.reserve(ip, true, now).unwrap_err();
+        assert!(throttled.locked_out);
+        assert_eq!(throttled.until, now + TimeDelta::seconds(LOCKOUT_SECS));
+    }
+
+    #[test]
+    fn enough_failures_lock_out_everyone_but_the_admin_forms() {
+        let throttle = fresh_throttle("lockouts-global.json");
+        let now = Utc::now();
+        for n in 0..GLOBAL_LOCKOUT_AFTER {
+            throttle.reserve(client(n as u8), true, now).unwrap();
+        }
+
+        let throttled = throttle.reserve(client(200), true, now).unwrap_err();
+        assert!(throttled.locked_out);
+        throttle.reserve(client(200), false, now).unwrap();
+    }
+
+    #[test]
+    fn a_good_attempt_only_forgives_its_own_failure() {
+        let throttle = fresh_throttle("lockouts-refund.json");
+        let ip = client(1);
+        let mut now = Utc::now();
+        fail(&throttle, ip, FREE_ATTEMPTS, &mut now);
+
+        // the attempt that would have started backing off turns out fine
+        let reservation = throttle.reserve(ip, true, now).unwrap();
+        throttle.refund(reservation);
+        let record = &throttle.snapshot().clients[&ip];
+        assert_eq!(record.failures, FREE_ATTEMPTS);
+        assert!(record.blocked_until.is_none());
+        assert_eq!(throttle.snapshot().global.failures, FREE_ATTEMPTS);
+
+        // so the next bad one is still held against it
+        throttle.reserve(ip, true, now).unwrap();
+        assert!(throttle.reserve(ip, true, now).is_err());
+    }
+
+    #[test]
+    fn a_good_attempt_lifts_the_global_lockout_it_started() {
+        let throttle = fresh_throttle("lockouts-global-refund.json");
+        let now = Utc::now();
+        for n in 1..GLOBAL_LOCKOUT_AFTER {
+            throttle.reserve(client(n as u8), true, now).unwrap();
+        }
+
+        let reservation = throttle.reserve(client(200), true, now).unwrap();
+        assert!(throttle.snapshot().global.locked_out);
+        throttle.refund(reservation);
+        assert!(!throttle.snapshot().global.locked_out);
+        throttle.reserve(client(201), true, now).unwrap();
+    }
+
+    #[test]
+    fn stale_failures_are_forgotten() {
+        let throttle = fresh_throttle("lockouts-stale.json");
+        let ip = client(1);
+        let mut now = Utc::now();
+        fail(&throttle, ip, FREE_ATTEMPTS + 1, &mut now);
+
+        now += TimeDelta::seconds(FORGET_AFTER_SECS + 1);
+        throttle.reserve(ip, true, now).unwrap();
+        assert_eq!(throttle.snapshot().clients[&ip].failures, 1);
+    }
+
+    #[tokio::test]
+    async fn a_burst_is_counted_before_any_attempt_runs() {
+        testing::config();
+        let ip = client(250);
+        let tried = AtomicU32::new(0);
+        let attempts = (0..2 * FREE_ATTEMPTS).map(|_| {
+            counted(ip, true, "passcode", async {
+                tried.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                Ok(None)
+            })
+        });
+
+        let results = join_all(attempts).await;
+        let refused = results
+            .iter()
+            .filter(|result| matches!(result, Err(GuardedVerifyError::Throttled(_))))
+            .count();
+        assert_eq!(tried.load(Ordering::SeqCst), FREE_ATTEMPTS + 1);
+        assert_eq!(refused as u32, FREE_ATTEMPTS - 1);
+        clear(Some(ip));
+    }
+
+    #[test]
+    fn lockouts_survive_a_restart() {
+        let path = testing::temp_path("lockouts-restart.json");
+        let _ = fs::remove_file(&path);
+        let throttle = Throttle::new(path.clone());
+        let ip = client(1);
+        let now = Utc::now();
+        for _ in 0..=FREE_ATTEMPTS {
+            throttle.reserve(ip, true, now).unwrap();
+        }
+        drop(throttle);
+
+        let throttle = Throttle::new(path);
+        throttle.load().unwrap();
+        assert_eq!(throttle.snapshot().clients[&ip].failures, FREE_ATTEMPTS + 1);
+        assert!(throttle.reserve(ip, true, now).is_err());
+
+        throttle.clear(Some(ip));
+        throttle.reserve(ip, true, now).unwrap();
+    }
+}
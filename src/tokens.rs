@@ -0,0 +1,256 @@
+use std::{fmt, fs, path::PathBuf};
+
+use anyhow::anyhow;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::{
+    auth::{hash_password, password_matches_async},
+    config::config,
+    lock::LockInstruction,
+    state_file::write_atomic,
+};
+
+const TOKEN_PREFIX: &str = "dk";
+
+static TOKENS: Lazy<RwLock<Vec<ApiToken>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+// each scope includes everything above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    ReadState,
+    LockOnly,
+    LockUnlock,
+}
+
+impl Scope {
+    pub fn permits(&self, instruction: &LockInstruction) -> bool {
+        match instruction {
+            LockInstruction::EnsureLocked(_) => *self >= Scope::LockOnly,
+            LockInstruction::EnsureUnlocked(_)
+            | LockInstruction::Reverse(_)
+            | LockInstruction::HoldOpen(_) => *self >= Scope::LockUnlock,
+            // saying where the bolt is takes a human admin
+            LockInstruction::MarkLocked(_)
+            | LockInstruction::MarkUnlocked(_)
+            | LockInstruction::Home(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::ReadState => write!(f, "read_state"),
+            Scope::LockOnly => write!(f, "lock_only"),
+            Scope::LockUnlock => write!(f, "lock_unlock"),
+        }
+    }
+}
+
+// the full token is dk_<id>_<secret>. id is stored in the clear so we only run argon2 once per request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub id: String,
+    pub secret_hash: String,
+    pub scope: Scope,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// token info that is safe to hand out
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenSummary {
+    pub name: String,
+    pub scope: Scope,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<&ApiToken> for TokenSummary {
+    fn from(token: &ApiToken) -> Self {
+        TokenSummary {
+            name: token.name.clone(),
+            scope: token.scope,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        }
+    }
+}
+
+pub fn tokens_file_path() -> PathBuf {
+    config().files.tokens.clone()
+}
+
+fn persist(tokens: &[ApiToken]) -> Result<(), anyhow::Error> {
+    write_atomic(
+        &tokens_file_path(),
+        serde_json::to_string_pretty(tokens)?.as_bytes(),
+    )
+}
+
+pub async fn load_tokens() -> Result<(), anyhow::Error> {
+    let path = tokens_file_path();
+    let loaded: Vec<ApiToken> = if path.exists() {
+        serde_json::from_str(&fs::read_to_string(&path)?)?
+    } else {
+        Vec::new()
+    };
+    info!("Loaded {} API token(s)", loaded.len());
+    *TOKENS.write().await = loaded;
+    Ok(())
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// returns the full token. this is the only time it exists outside the client
+pub async fn create_token(
+    name: &str,
+    scope: Scope,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<String, anyhow::Error> {
+    if name.trim().is_empty() {
+        return Err(anyhow!("token name can't be empty"));
+    }
+    if expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(anyhow!("token would already be expired"));
+    }
+    let id = random_hex(4);
+    let secret = random_hex(24);
+
+    let mut tokens = TOKENS.write().await;
+    if tokens.iter().any(|t| t.name == name) {
+        return Err(anyhow!("token '{name}' already exists"));
+    }
+    let mut updated = tokens.clone();
+    updated.push(ApiToken {
+        name: name.to_string(),
+        id: id.clone(),
+        secret_hash: hash_password(&secret),
+        scope,
+        created_at: Utc::now(),
+        expires_at,
+    });
+    persist(&updated)?;
+    *tokens = updated;
+    info!("Created API token '{name}' with scope {scope}");
+    Ok(format!("{TOKEN_PREFIX}_{id}_{secret}"))
+}
+
+pub async fn revoke_token(name: &str) -> Result<(), anyhow::Error> {
+    let mut tokens = TOKENS.write().await;
+    let mut updated = tokens.clone();
+    updated.retain(|t| t.name != name);
+    if updated.len() == tokens.len() {
+        return Err(anyhow!("no token '{name}'"));
+    }
+    persist(&updated)?;
+    *tokens = updated;
+    info!("Revoked API token '{name}'");
+    Ok(())
+}
+
+pub async fn list_tokens() -> Vec<TokenSummary> {
+    TOKENS.read().await.iter().map(TokenSummary::from).collect()
+}
+
+// None for anything malformed, unknown, expired or with the wrong secret
+pub async fn verify_token(token: &str) -> Result<Option<(String, Scope)>, anyhow::Error> {
+    let mut parts = token.splitn(3, '_');
+    let (Some(TOKEN_PREFIX), Some(id), Some(secret)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(None);
+    };
+    let found = TOKENS.read().await.iter().find(|t| t.id == id).cloned();
+    let Some(found) = found else {
+        return Ok(None);
+    };
+    if found.expires_at.is_some_and(|at| at <= Utc::now()) {
+        info!("Expired API token '{}' used", found.name);
+        return Ok(None);
+    }
+    if !password_matches_async(secret, &found.secret_hash).await? {
+        return Ok(None);
+    }
+    Ok(Some((found.name, found.scope)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::{lock::InstructionSource, testing};
+
+    #[test]
+    fn each_scope_includes_the_ones_below_it() {
+        let lock = LockInstruction::EnsureLocked(InstructionSource::Button);
+        let unlock = LockInstruction::EnsureUnlocked(InstructionSource::Button);
+        let mark = LockInstruction::MarkLocked(InstructionSource::Button);
+        assert!(!Scope::ReadState.permits(&lock));
+        assert!(Scope::LockOnly.permits(&lock));
+        assert!(!Scope::LockOnly.permits(&unlock));
+        assert!(Scope::LockUnlock.permits(&unlock));
+        assert!(!Scope::LockUnlock.permits(&mark));
+    }
+
+    #[tokio::test]
+    async fn only_the_whole_live_token_verifies() {
+        testing::config();
+        let token = create_token(
+            "verified",
+            Scope::LockOnly,
+            Some(Utc::now() + TimeDelta::hours(1)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            verify_token(&token).await.unwrap(),
+            Some(("verified".to_string(), Scope::LockOnly))
+        );
+
+        let (id_part, _) = token.rsplit_once('_').unwrap();
+        assert_eq!(
+            verify_token(&format!("{id_part}_wrongsecret"))
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            verify_token(&token.replacen("dk", "xx", 1)).await.unwrap(),
+            None
+        );
+        assert_eq!(verify_token("dk_verified").await.unwrap(), None);
+
+        for stored in TOKENS.write().await.iter_mut() {
+            if stored.name == "verified" {
+                stored.expires_at = Some(Utc::now() - TimeDelta::seconds(1));
+            }
+        }
+        assert_eq!(verify_token(&token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_token_needs_a_fresh_name_and_a_future_expiry() {
+        testing::config();
+        let past = Some(Utc::now() - TimeDelta::minutes(1));
+        assert!(create_token("stale", Scope::ReadState, past).await.is_err());
+        assert!(create_token(" ", Scope::ReadState, None).await.is_err());
+        create_token("taken", Scope::ReadState, None).await.unwrap();
+        assert!(create_token("taken", Scope::ReadState, None).await.is_err());
+
+        revoke_token("taken").await.unwrap();
+        assert!(revoke_token("taken").await.is_err());
+        assert!(list_tokens().await.iter().all(|t| t.name != "taken"));
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        mpsc::{self, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tokio::{select, sync::Notify, time::sleep};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{WebhookEvent, WebhooksConfig, config},
+    state_file::write_atomic,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_BACKOFF_SECS: u64 = 5;
+// even with nothing due, look again now and then in case the clock jumped
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+// past this the oldest deliveries go, so a dead endpoint can't fill the disk
+const MAX_QUEUED: usize = 1000;
+
+// the daemon's, wired up from config()
+static WEBHOOKS: Lazy<Webhooks> = Lazy::new(|| {
+    let config = config();
+    Webhooks::new(config.webhooks.clone(), config.files.webhook_queue.clone())
+});
+
+// webhook_queue.json. a delivery stays in here until its endpoint answers 2xx or we give up on it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct DeliveryQueue {
+    next_id: u64,
+    deliveries: Vec<Delivery>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    id: u64,
+    url: String,
+    event: WebhookEvent,
+    body: String, // signed exactly as sent
+    created_at: DateTime<Utc>,
+    attempts: u32,
+    next_attempt_at: DateTime<Utc>,
+    last_error: Option<String>,
+}
+
+// the queue, the endpoints it goes to and the file it's kept in. serve only ever has WEBHOOKS,
+// the functions below hand everything to it
+pub struct Webhooks {
+    config: WebhooksConfig,
+    queue_path: PathBuf,
+    queue: Mutex<DeliveryQueue>,
+    wake: Notify,
+    auth_failures: Mutex<VecDeque<Instant>>,
+    // raise gets called from the lock actor, so the queue is saved on its own thread
+    saves: Option<Sender<DeliveryQueue>>,
+    saver: Option<JoinHandle<()>>,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhooksConfig, queue_path: PathBuf) -> Self {
+        let (saves, saved) = mpsc::channel::<DeliveryQueue>();
+        let path = queue_path.clone();
+        let saver = thread::Builder::new()
+            .name("webhook-queue".to_string())
+            .spawn(move || {
+                // only the newest copy matters, a backlog of saves collapses into one write
+                for mut queue in saved.iter() {
+                    while let Ok(newer) = saved.try_recv() {
+                        queue = newer;
+                    }
+                    write_queue(&path, &queue);
+                }
+            })
+            .expect("could not start the webhook queue writer");
+        Self {
+            config,
+            queue_path,
+            queue: Mutex::new(DeliveryQueue::default()),
+            wake: Notify::new(),
+            auth_failures: Mutex::new(VecDeque::new()),
+            saves: Some(saves),
+            saver: Some(saver),
+        }
+    }
+
+    pub fn load(&self) -> Result<(), anyhow::Error> {
+        if self.queue_path.exists() {
+            let loaded: DeliveryQueue =
+                serde_json::from_str(&fs::read_to_string(&self.queue_path)?)?;
+            info!(
+                "Loaded {} pending webhook deliveries",
+                loaded.deliveries.len()
+            );
+            *self.queue.lock().unwrap() = loaded;
+        }
+        Ok(())
+    }
+
+    fn persist(&self, queue: &DeliveryQueue) {
+        let saves = self.saves.as_ref().expect("only taken on drop");
+        if saves.send(queue.clone()).is_err() {
+            error!("Webhook queue writer is gone, pending deliveries won't survive a restart");
+        }
+    }
+
+    pub fn raise(&self, event: WebhookEvent, data: Value) -> usize {
+        let endpoints: Vec<_> = self
+            .config
+            .endpoints
+            .iter()
+            .filter(|endpoint| event == WebhookEvent::Test || endpoint.events.contains(&event))
+            .collect();
+        if endpoints.is_empty() {
+            return 0;
+        }
+
+        let now = Utc::now();
+        let mut queue = self.queue.lock().unwrap();
+        for endpoint in &endpoints {
+            queue.next_id += 1;
+            let id = queue.next_id;
+            let body = json!({ "id": id, "event": event, "at": now, "data": data }).to_string();
+            queue.deliveries.push(Delivery {
+                id,
+                url: endpoint.url.clone(),
+                event,
+                body,
+                created_at: now,
+                attempts: 0,
+                next_attempt_at: now,
+                last_error: None,
+            });
+        }
+        let overflow = queue.deliveries.len().saturating_sub(MAX_QUEUED);
+        if overflow > 0 {
+            warn!("Webhook queue is full, dropping the {overflow} oldest deliveries");
+            queue.deliveries.drain(..overflow);
+        }
+        self.persist(&queue);
+        drop(queue);
+        info!(?event, "Queued webhook for {} endpoint(s)", endpoints.len());
+        self.wake.notify_one();
+        endpoints.len()
+    }
+
+    pub fn auth_failed(&self) {
+        let window = Duration::from_secs(self.config.auth_burst_window_secs);
+        let mut failures = self.auth_failures.lock().unwrap();
+        failures.push_back(Instant::now());
+        while failures.front().is_some_and(|at| at.elapsed() > window) {
+            failures.pop_front();
+        }
+        if failures.len() < self.config.auth_burst {
+            return;
+        }
+        // start counting again, one alert per burst is plenty
+        failures.clear();
+        drop(failures);
+        self.raise(
+            WebhookEvent::AuthFailureBurst,
+            json!({
+                "failures": self.config.auth_burst,
+                "window_secs": self.config.auth_burst_window_secs,
+            }),
+        );
+    }
+
+    // looked up at delivery rather than stored, so rotating a secret in the config applies to the backlog too
+    fn secret_for(&self, url: &str) -> Option<&str> {
+        self.config
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.url == url)
+            .map(|endpoint| endpoint.secret.as_str())
+    }
+
+    fn backoff(&self, attempts: u32) -> TimeDelta {
+        let secs = FIRST_BACKOFF_SECS
+            .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+            .min(self.config.max_backoff_secs);
+        TimeDelta::seconds(secs as i64)
+    }
+
+    fn forget(&self, id: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.deliveries.retain(|d| d.id != id);
+        self.persist(&queue);
+    }
+
+    fn settle(&self, id: u64, result: Result<(), anyhow::Error>) {
+        let mut queue = self.queue.lock().unwrap();
+        let Some(index) = queue.deliveries.iter().position(|d| d.id == id) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                let delivery = queue.deliveries.remove(index);
+                info!(id, event = ?delivery.event, "Delivered webhook to {}", delivery.url);
+            }
+            Err(e) => {
+                let now = Utc::now();
+                let retry_for = TimeDelta::seconds(self.config.retry_for_secs as i64);
+                let delivery = &mut queue.deliveries[index];
+                delivery.attempts += 1;
+                delivery.last_error = Some(e.to_string());
+                if now - delivery.created_at >= retry_for {
+                    error!(
+                        id,
+                        "Giving up on webhook to {} after {} attempts. {e}",
+                        delivery.url,
+                        delivery.attempts
+                    );
+                    queue.deliveries.remove(index);
+                } else {
+                    delivery.next_attempt_at = now + self.backoff(delivery.attempts);
+                    warn!(
+                        id,
+                        "Webhook to {} failed, retrying at {}. {e}",
+                        delivery.url,
+                        delivery.next_attempt_at
+                    );
+                }
+            }
+        }
+        self.persist(&queue);
+    }
+
+    // oldest first
+    fn due(&self) -> Vec<Delivery> {
+        let now = Utc::now();
+        let queue = self.queue.lock().unwrap();
+        let mut due: Vec<_> = queue
+            .deliveries
+            .iter()
+            .filter(|d| d.next_attempt_at <= now)
+            .cloned()
+            .collect();
+        due.sort_by_key(|d| d.id);
+        due
+    }
+
+    fn until_next_attempt(&self) -> Duration {
+        let next = self
+            .queue
+            .lock()
+            .unwrap()
+            .deliveries
+            .iter()
+            .map(|d| d.next_attempt_at)
+            .min();
+        match next {
+            Some(at) => (at - Utc::now())
+                .to_std()
+                .unwrap_or_default()
+                .min(IDLE_CHECK),
+            None => IDLE_CHECK,
+        }
+    }
+
+    // one delivery at a time. nothing here is urgent enough to hammer an endpoint that's struggling
+    pub async fn run(&self) -> Result<(), anyhow::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        info!(
+            "Delivering webhooks to {} endpoint(s)",
+            self.config.endpoints.len()
+        );
+        loop {
+            for delivery in self.due() {
+                let Some(secret) = self.secret_for(&delivery.url) else {
+                    warn!(
+                        id = delivery.id,
+                        "{} is no longer a webhook endpoint, dropping its delivery", delivery.url
+                    );
+                    self.forget(delivery.id);
+                    continue;
+                };
+                let result = deliver(&client, &delivery, secret).await;
+                self.settle(delivery.id, result);
+            }
+            select! {
+                _ = self.wake.notified() => {},
+                _ = sleep(self.until_next_attempt()) => {},
+            }
+        }
+    }
+}
+
+impl Drop for Webhooks {
+    // hangs up and waits for the writer, so the file has the last save once this returns
+    fn drop(&mut self) {
+        self.saves.take();
+        if let Some(saver) = self.saver.take()
+            && saver.join().is_err()
+        {
+            error!("Webhook queue writer had panicked");
+        }
+    }
+}
+
+fn write_queue(path: &Path, queue: &DeliveryQueue) {
+    let written = serde_json::to_string_pretty(queue)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| write_atomic(path, json.as_bytes()));
+    if let Err(e) = written {
+        error!("Could not save the webhook queue, pending deliveries won't survive a restart. {e}");
+    }
+}
+
+pub fn load() -> Result<(), anyhow::Error> {
+    WEBHOOKS.load()
+}
+
+// queues the event for every endpoint that wants it. returns how many that was
+pub fn raise(event: WebhookEvent, data: Value) -> usize {
+    WEBHOOKS.raise(event, data)
+}
+
+// every failed passcode or token, from anyone. enough of them close together is a burst
+pub fn auth_failed() {
+    WEBHOOKS.auth_failed()
+}
+
+pub async fn run() -> Result<(), anyhow::Error> {
+    WEBHOOKS.run().await
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    delivery: &Delivery,
+    secret: &str,
+) -> Result<(), anyhow::Error> {
+    let response = client
+        .post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header(
+            "X-Doorknob-Event",
+            serde_json::to_value(delivery.event)?
+                .as_str()
+                .unwrap_or_default(),
+        )
+        .header("X-Doorknob-Delivery", delivery.id.to_string())
+        .header(
+            "X-Doorknob-Signature",
+            sign(secret, delivery.body.as_bytes()),
+        )
+        .body(delivery.body.clone())
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("{} answered {status}", delivery.url));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        Router,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+    };
+    use more_asserts::assert_ge;
+    use tokio::{net::TcpListener, time::timeout};
+
+    use super::*;
+    use crate::{config::WebhookEndpoint, testing};
+
+    const SECRET: &str = "correct horse battery staple";
+
+    // what a local endpoint got, and what it answers the next ones with. 200 once that runs out
+    #[derive(Default)]
+    struct Endpoint {
+        answers: Mutex<VecDeque<StatusCode>>,
+        received: Mutex<Vec<(Instant, HeaderMap, String)>>,
+    }
+
+    async fn receive(
+        State(endpoint): State<Arc<Endpoint>>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        let mut received = endpoint.received.lock().unwrap();
+        received.push((Instant::now(), headers, body));
+        let mut answers = endpoint.answers.lock().unwrap();
+        answers.pop_front().unwrap_or(StatusCode::OK)
+    }
+
+    async fn endpoint(answers: &[StatusCode]) -> (Arc<Endpoint>, String) {
+        let endpoint = Arc::new(Endpoint {
+            answers: Mutex::new(answers.iter().copied().collect()),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(Arc::clone(&endpoint));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (endpoint, url)
+    }
+
+    fn config(url: &str) -> WebhooksConfig {
+        WebhooksConfig {
+            max_backoff_secs: 1,
+            endpoints: vec![WebhookEndpoint {
+                url: url.to_string(),
+                secret: SECRET.to_string(),
+                events: vec![WebhookEvent::Locked],
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn fresh_queue(name: &str) -> PathBuf {
+        let path = testing::temp_path(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn pending(webhooks: &Webhooks) -> Vec<Delivery> {
+        webhooks.queue.lock().unwrap().deliveries.clone()
+    }
+
+    // runs the sender until done says so
+    async fn deliver_until(webhooks: &Webhooks, done: impl Fn() -> bool) {
+        let waiting = async {
+            while !done() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        select! {
+            result = webhooks.run() => panic!("the sender stopped. {result:?}"),
+            waited = timeout(Duration::from_secs(10), waiting) => {
+                waited.expect("the sender never got there");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed_with_the_endpoint_secret() {
+        let (endpoint, url) = endpoint(&[]).await;
+        let webhooks = Webhooks::new(config(&url), fresh_queue("signed.json"));
+        assert_eq!(webhooks.raise(WebhookEvent::Unlocked, json!({})), 0);
+        assert_eq!(
+            webhooks.raise(WebhookEvent::Locked, json!({ "instruction_id": 7 })),
+            1
+        );
+        deliver_until(&webhooks, || pending(&webhooks).is_empty()).await;
+
+        let received = endpoint.received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (_, headers, body) = &received[0];
+        let signature = headers["X-Doorknob-Signature"].to_str().unwrap();
+        let signature = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature)
+            .expect("signature doesn't match the body");
+
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["event"], "locked");
+        assert_eq!(body["data"]["instruction_id"], 7);
+        assert_eq!(headers["X-Doorknob-Event"], "locked");
+        assert_eq!(
+            headers["X-Doorknob-Delivery"],
+            body["id"].to_string().as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_refused_delivery_is_retried_after_backing_off() {
+        let (endpoint, url) = endpoint(&[StatusCode::SERVICE_UNAVAILABLE]).await;
+        let webhooks = Webhooks::new(config(&url), fresh_queue("retried.json"));
+        webhooks.raise(WebhookEvent::Locked, json!({}));
+
+        deliver_until(&webhooks, || {
+            pending(&webhooks).first().is_some_and(|d| d.attempts == 1)
+        })
+        .await;
+        let delivery = &pending(&webhooks)[0];
+        assert!(delivery.last_error.as_ref().unwrap().contains("503"));
+        assert_ge!(
+            delivery.next_attempt_at - Utc::now(),
+            TimeDelta::milliseconds(500)
+        );
+
+        deliver_until(&webhooks, || pending(&webhooks).is_empty()).await;
+        let received = endpoint.received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].2, received[1].2);
+        // max_backoff_secs is 1 here
+        assert_ge!(received[1].0 - received[0].0, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let webhooks = Webhooks::new(
+            WebhooksConfig {
+                max_backoff_secs: 60,
+                ..Default::default()
+            },
+            fresh_queue("backoff.json"),
+        );
+        let backoff: Vec<_> = [1, 2, 3, 4, 5, 100]
+            .into_iter()
+            .map(|attempts| webhooks.backoff(attempts).num_seconds())
+            .collect();
+        assert_eq!(backoff, [5, 10, 20, 40, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn the_queue_survives_a_restart() {
+        let (endpoint, url) = endpoint(&[]).await;
+        let path = fresh_queue("restarted.json");
+        let before = Webhooks::new(config(&url), path.clone());
+        before.raise(WebhookEvent::Locked, json!({ "instruction_id": 1 }));
+        let queued = pending(&before);
+        drop(before); // waits for the save
+
+        let after = Webhooks::new(config(&url), path.clone());
+        after.load().unwrap();
+        let loaded = pending(&after);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].body, queued[0].body);
+
+        deliver_until(&after, || pending(&after).is_empty()).await;
+        assert_eq!(endpoint.received.lock().unwrap()[0].2, queued[0].body);
+        drop(after);
+        let saved: DeliveryQueue =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.next_id, 1);
+        assert!(saved.deliveries.is_empty());
+    }
+}